@@ -0,0 +1,121 @@
+//! Microbenchmarks for the interpreter.
+//!
+//! Run with `cargo run --release --example bench [loop|counter|integers|strings] [iterations]`:
+//!
+//! - `loop` (the default) times a tight arithmetic loop, for measuring the interpreter's dispatch overhead.
+//! - `counter` times a loop that only counts, first with `i = i + one`, which compiles to the general `GetLocal`,
+//!   `GetLocal`, `Add`, `SetLocal`, then with `i = i + 1`, which compiles to `IncLocal`.
+//! - `integers` times a loop of integer arithmetic whose results stay small, then the same loop on fractions. Numbers
+//!   are `f64`s stored inline in `Value::Real`, so neither allocates and there's nothing for a small-integer cache like
+//!   CPython's to save; the two take the same time.
+//! - `strings` times allocating and concatenating many short strings, like identifiers.
+
+use std::time::{Duration, Instant};
+
+use ankoku::{
+    compiler::Compiler,
+    parser::{stmt::Stmt, tokenizer::Tokenizer},
+    vm::{instruction::Instruction, obj::AnkokuString, InterpretResult, VM},
+};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let bench = args.next().unwrap_or_else(|| "loop".to_string());
+    let iterations = args
+        .next()
+        .map(|n| n.parse::<u32>().expect("iterations must be a number"));
+
+    let (iterations, elapsed) = match bench.as_str() {
+        "loop" => {
+            let iterations = iterations.unwrap_or(5_000_000);
+            (iterations, arithmetic_loop(iterations))
+        }
+        "counter" => {
+            let iterations = iterations.unwrap_or(10_000_000);
+            let general = run(&format!(
+                "{{ var i = 0; var one = 1; while (i < {}) i = i + one; }}",
+                iterations
+            ));
+            report("i = i + one", iterations, general);
+            let inc = run(&format!(
+                "{{ var i = 0; while (i < {}) i = i + 1; }}",
+                iterations
+            ));
+            report("i = i + 1", iterations, inc);
+            return;
+        }
+        "integers" => {
+            let iterations = iterations.unwrap_or(5_000_000);
+            report("integers", iterations, small_arithmetic(iterations, 0.0));
+            report("fractions", iterations, small_arithmetic(iterations, 0.25));
+            return;
+        }
+        "strings" => {
+            let iterations = iterations.unwrap_or(10_000_000);
+            (iterations, short_strings(iterations))
+        }
+        other => panic!(
+            "unknown benchmark {:?}, expected loop, counter, integers or strings",
+            other
+        ),
+    };
+    report(&bench, iterations, elapsed);
+}
+
+fn report(name: &str, iterations: u32, elapsed: Duration) {
+    println!(
+        "{}: {} iterations in {:?} ({:.1} ns/iteration)",
+        name,
+        iterations,
+        elapsed,
+        elapsed.as_nanos() as f64 / iterations as f64
+    );
+}
+
+fn arithmetic_loop(iterations: u32) -> Duration {
+    run(&format!(
+        "{{ var i = 0; var sum = 0; while (i < {}) {{ sum = sum + i * 2 - i / 2; i = i + 1; }} print sum; }}",
+        iterations
+    ))
+}
+
+/// A loop of arithmetic on `x`, which comes out the same every iteration so it stays small, starting at `start`.
+fn small_arithmetic(iterations: u32, start: f64) -> Duration {
+    run(&format!(
+        "{{ var i = 0; var x = {}; while (i < {}) {{ x = (x + 3) * 5 - x * 4 - 15; i = i + 1; }} }}",
+        start, iterations
+    ))
+}
+
+/// Compiles `source` and times running it.
+fn run(source: &str) -> Duration {
+    let tokens = Tokenizer::new(source)
+        .collect::<Result<Vec<_>, _>>()
+        .expect("benchmark doesn't tokenize");
+    let source = source.chars().collect::<Vec<_>>();
+    let (stmts, errors) = Stmt::parse(tokens, source.clone());
+    assert!(errors.is_empty(), "benchmark doesn't parse");
+    let mut vm = VM::new();
+    let (mut chunk, errors) = Compiler::compile(&stmts, &source, &vm);
+    assert!(errors.is_empty(), "benchmark doesn't compile");
+    chunk.write(Instruction::Return.into(), 1);
+
+    let start = Instant::now();
+    assert_eq!(vm.interpret(chunk), InterpretResult::Ok);
+    start.elapsed()
+}
+
+fn short_strings(iterations: u32) -> Duration {
+    const NAMES: [&str; 4] = ["x", "count", "to_string", "longer_identifier"];
+
+    let start = Instant::now();
+    let mut total = 0;
+    for i in 0..iterations {
+        let name = AnkokuString::new(NAMES[i as usize % NAMES.len()].to_string());
+        let suffixed = name.concat("_1");
+        total += std::hint::black_box(suffixed).as_str().len();
+    }
+    let elapsed = start.elapsed();
+    assert!(total > 0);
+    elapsed
+}
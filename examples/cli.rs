@@ -1,10 +1,10 @@
-use std::process::exit;
+use std::{fmt::Write, process::exit};
 
 use ankoku::{
-    compiler::Compiler,
+    compiler::{Compiler, CompilerOptions},
     parser::{stmt::Stmt, tokenizer::Tokenizer},
-    util::error::{AnkokuError, ErrorReporter},
-    vm::{instruction::Instruction, VM},
+    util::error::{AnkokuError, ErrorReporter, Severity},
+    vm::{error::RuntimeError, instruction::Instruction, InterpretResult, VM},
 };
 
 fn main() {
@@ -20,7 +20,7 @@ fn main() {
     let tokens = Tokenizer::new(source.as_ref())
         .map(|v| {
             if let Err(v) = v {
-                let reporter = CLIErrorReporter;
+                let reporter = CLIErrorReporter::default();
                 reporter.report(v);
                 panic!("tokenizer error") // TODO: "Don't Panic"
             } else {
@@ -29,71 +29,260 @@ fn main() {
         })
         .collect::<Vec<_>>();
 
-    let (ast, errors) = Stmt::parse(tokens, source.chars().collect());
-    if !errors.is_empty() {
-        let reporter = CLIErrorReporter;
-        for err in errors {
-            reporter.report(err);
-        }
+    let source = source.chars().collect::<Vec<_>>();
+    let (ast, errors) = Stmt::parse(tokens, source.clone());
+    if CLIErrorReporter::default().report_all(errors) {
         return;
     }
     let mut vm = VM::new();
-    let mut compiled = Compiler::compile(&ast, &vm);
+    let options = CompilerOptions {
+        module: Some(input.clone()),
+        ..Default::default()
+    };
+    let (mut compiled, errors) = Compiler::compile_with_options(&ast, &source, &vm, options);
+    if CLIErrorReporter::default().report_all(errors) {
+        return;
+    }
     compiled.disassemble("CLI compiled chunk");
     compiled.write(Instruction::Return.into(), 1);
-    vm.interpret(compiled);
+    if vm.interpret(compiled) == InterpretResult::RuntimeError {
+        if let Some(err) = vm.last_error() {
+            print!("{}", CLIErrorReporter::default().render_runtime_error(err));
+        }
+        exit(1);
+    }
 }
 
 use owo_colors::OwoColorize;
 
-pub struct CLIErrorReporter;
+/// Renders errors for a terminal, with their codes prefixed by a namespace: `AK1001`, or `AKW3003` for a warning.
+pub struct CLIErrorReporter {
+    prefix: String,
+}
+
+impl Default for CLIErrorReporter {
+    fn default() -> Self {
+        Self::with_prefix("AK")
+    }
+}
 
-impl ErrorReporter for CLIErrorReporter {
-    fn report<E: AnkokuError>(&self, err: E) {
+impl CLIErrorReporter {
+    /// A reporter that prefixes codes with `prefix` instead of `AK`, for tools that embed Ankoku and want its
+    /// diagnostics under their own name. Warnings still get a `W` after it.
+    pub fn with_prefix<S: Into<String>>(prefix: S) -> Self {
+        Self {
+            prefix: prefix.into(),
+        }
+    }
+
+    /// Reports every error, returning whether any of them should stop the program from running.
+    pub fn report_all<E: AnkokuError>(&self, errors: Vec<E>) -> bool {
+        let mut fatal = false;
+        for err in errors {
+            fatal |= err.severity() == Severity::Error;
+            self.report(err);
+        }
+        fatal
+    }
+
+    pub fn render_runtime_error(&self, err: &RuntimeError) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{} {:?}",
+            "runtime error:".bright_red().bold(),
+            err.kind
+        )
+        .unwrap();
+        if !err.traceback.is_empty() {
+            writeln!(out, "{}", "traceback (most recent call last):".bold()).unwrap();
+            for frame in &err.traceback {
+                writeln!(out, "  line {}, in {}", frame.line, frame.function).unwrap();
+            }
+        }
+        out
+    }
+
+    pub fn render<E: AnkokuError>(&self, err: &E) -> String {
+        let mut out = String::new();
         if let Some((line, col, content)) = err.line_col() {
-            println!(
-                "{} {:04}: {}",
-                "error".bright_red().bold(),
-                format!("AK{}", err.code()).bold(),
-                err.msg()
-            );
-            // println!("{} todo filename", "-->".bold().bright_cyan());
-
-            let bottom_highlight = || {
-                format!(
-                    "{}{}",
-                    " ".repeat(col - 1),
-                    "^".repeat(err.length().unwrap_or(1)).bold().yellow(),
-                )
+            let (severity, code, highlight) = match err.severity() {
+                Severity::Error => (
+                    "error".bright_red().bold().to_string(),
+                    format!("{}{}", self.prefix, err.code()),
+                    "^".repeat(err.length().unwrap_or(1))
+                        .bold()
+                        .yellow()
+                        .to_string(),
+                ),
+                Severity::Warning => (
+                    "warning".yellow().bold().to_string(),
+                    format!("{}W{}", self.prefix, err.code()),
+                    "-".repeat(err.length().unwrap_or(1))
+                        .bold()
+                        .yellow()
+                        .to_string(),
+                ),
             };
+            writeln!(out, "{} {:04}: {}", severity, code.bold(), err.msg()).unwrap();
+            if let Some(filename) = err.filename() {
+                writeln!(out, "{} {}", "-->".bold().bright_cyan(), filename).unwrap();
+            }
+
+            let bottom_highlight = || format!("{}{}", " ".repeat(col - 1), highlight);
             // 4 digits ought to be enough for anyone
             if line < 100 {
-                println!("{}", "    |".bold().bright_cyan());
-                println!(
+                writeln!(out, "{}", "    |".bold().bright_cyan()).unwrap();
+                writeln!(
+                    out,
                     "{} {}",
                     format!(" {:2} |", line).bold().bright_cyan(),
                     content
-                );
-                println!("{} {}", "    |".bold().bright_cyan(), bottom_highlight());
+                )
+                .unwrap();
+                writeln!(
+                    out,
+                    "{} {}",
+                    "    |".bold().bright_cyan(),
+                    bottom_highlight()
+                )
+                .unwrap();
             } else if line < 1000 {
-                println!("{}", "     |".bold().bright_cyan());
-                println!(
+                writeln!(out, "{}", "     |".bold().bright_cyan()).unwrap();
+                writeln!(
+                    out,
                     "{} {}",
                     format!(" {:3} |", line).bold().bright_cyan(),
                     content
-                );
-                println!("{} {}", "     |".bold().bright_cyan(), bottom_highlight());
+                )
+                .unwrap();
+                writeln!(
+                    out,
+                    "{} {}",
+                    "     |".bold().bright_cyan(),
+                    bottom_highlight()
+                )
+                .unwrap();
             } else if line < 10000 {
-                println!("{}", "      |".bold().bright_cyan());
-                println!(
+                writeln!(out, "{}", "      |".bold().bright_cyan()).unwrap();
+                writeln!(
+                    out,
                     "{} {}",
                     format!(" {:4} |", line).bold().bright_cyan(),
                     content
-                );
-                println!("{} {}", "      |".bold().bright_cyan(), bottom_highlight());
+                )
+                .unwrap();
+                writeln!(
+                    out,
+                    "{} {}",
+                    "      |".bold().bright_cyan(),
+                    bottom_highlight()
+                )
+                .unwrap();
             }
         } else {
-            println!("error has no line");
+            writeln!(out, "error has no line").unwrap();
         }
+        out
+    }
+}
+
+impl ErrorReporter for CLIErrorReporter {
+    fn report<E: AnkokuError>(&self, err: E) {
+        print!("{}", self.render(&err));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, fmt::Display};
+
+    use ankoku::{
+        parser::{stmt::Stmt, tokenizer::Tokenizer},
+        util::error::{AnkokuError, Severity},
+        vm::error::{RuntimeError, RuntimeErrorType, TraceFrame},
+    };
+
+    use super::CLIErrorReporter;
+
+    #[derive(Debug)]
+    struct TestWarning;
+    impl Error for TestWarning {}
+    impl Display for TestWarning {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.msg())
+        }
+    }
+    impl AnkokuError for TestWarning {
+        fn msg(&self) -> &str {
+            "something looks off"
+        }
+        fn code(&self) -> u32 {
+            4001
+        }
+        fn line_col(&self) -> Option<(u32, usize, &str)> {
+            Some((1, 5, "var x = 1;"))
+        }
+        fn length(&self) -> Option<usize> {
+            Some(1)
+        }
+        fn filename(&self) -> Option<&str> {
+            None
+        }
+        fn severity(&self) -> Severity {
+            Severity::Warning
+        }
+    }
+
+    #[test]
+    fn warnings() {
+        let reporter = CLIErrorReporter::default();
+        let rendered = reporter.render(&TestWarning);
+        assert!(rendered.contains("warning"), "{}", rendered);
+        assert!(rendered.contains("AKW4001"), "{}", rendered);
+        assert!(!rendered.contains("error"), "{}", rendered);
+        assert!(!reporter.report_all(vec![TestWarning]));
+    }
+
+    #[test]
+    fn custom_prefix() {
+        let reporter = CLIErrorReporter::with_prefix("MYTOOL");
+        let source = "print 1 +;";
+        let tokens = Tokenizer::new(source).map(|t| t.unwrap()).collect();
+        let (_, errors) = Stmt::parse(tokens, source.chars().collect());
+        let rendered = reporter.render(&errors[0]);
+        assert!(rendered.contains("MYTOOL2001"), "{}", rendered);
+        assert!(!rendered.contains("AK"), "{}", rendered);
+
+        let rendered = reporter.render(&TestWarning);
+        assert!(rendered.contains("MYTOOLW4001"), "{}", rendered);
+    }
+
+    #[test]
+    fn traceback() {
+        let mut err = RuntimeError::new(RuntimeErrorType::UndefinedVariable {
+            name: "x".to_string(),
+        });
+        err.traceback = vec![
+            TraceFrame {
+                function: "<script>".to_string(),
+                line: 4,
+            },
+            TraceFrame {
+                function: "f".to_string(),
+                line: 2,
+            },
+        ];
+        let rendered = CLIErrorReporter::default().render_runtime_error(&err);
+        assert!(
+            rendered.contains("traceback (most recent call last):"),
+            "{}",
+            rendered
+        );
+        assert!(
+            rendered.ends_with("  line 4, in <script>\n  line 2, in f\n"),
+            "{}",
+            rendered
+        );
     }
 }
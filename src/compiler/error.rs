@@ -0,0 +1,155 @@
+use std::{
+    backtrace::Backtrace,
+    error::Error,
+    fmt::{Debug, Display},
+};
+
+use crate::{
+    parser::tokenizer::Token,
+    util::error::{AnkokuError, Severity},
+};
+
+pub struct CompileError {
+    pub kind: CompileErrorType,
+    pub token: Token,
+    pub internal_bt: Backtrace,
+    pub line: String,
+    pub line_num: u32,
+    pub col: usize,
+    /// The imported file the error is in, or `None` for the program being compiled.
+    pub filename: Option<String>,
+}
+impl CompileError {
+    /// Creates an error underlining `token`, which is resolved to a line and column in `source`.
+    pub fn new(kind: CompileErrorType, token: Token, source: &[char]) -> Self {
+        let mut line_num = 1;
+        let mut line_start = 0;
+        for (i, c) in source.iter().enumerate().take(token.start) {
+            if *c == '\n' {
+                line_num += 1;
+                line_start = i + 1;
+            }
+        }
+        let mut line = source[line_start..]
+            .iter()
+            .take_while(|c| **c != '\n')
+            .collect::<String>();
+        // a file with \r\n line endings shouldn't have the \r shown as part of the line
+        if line.ends_with('\r') {
+            line.pop();
+        }
+        CompileError {
+            kind,
+            token,
+            internal_bt: Backtrace::capture(),
+            line,
+            line_num,
+            col: token.start - line_start + 1,
+            filename: None,
+        }
+    }
+}
+impl Error for CompileError {}
+impl Debug for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} on {:?}
+        internal backtrace:
+        {}",
+            self.msg(),
+            self.token,
+            self.internal_bt
+        )
+    }
+}
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg())
+    }
+}
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompileErrorType {
+    ExpectedNumber,
+    ExpectedNumberOrString,
+    /// A local that goes out of scope without ever being read.
+    UnusedVariable,
+    /// A local declared twice in the same scope.
+    AlreadyDeclared,
+    /// A local with the same name as an outer local or a global. Only reported if enabled in
+    /// [`CompilerOptions`](super::CompilerOptions).
+    Shadowed,
+    /// `return` at the top level of an imported module. Only the program itself can return a value to its host, see
+    /// [`VM::eval`](crate::vm::VM::eval).
+    ReturnOutsideFunction,
+    /// The imported file couldn't be read or parsed. Contains the full message.
+    ImportFailed(String),
+    ImportCycle,
+    ImportNotTopLevel,
+    /// A chunk needs more than the 256 constants its instructions can refer to.
+    TooManyConstants,
+    /// A function has more than the 256 locals its instructions can refer to, counting its parameters.
+    TooManyLocals,
+    /// A function captures more than the 255 variables its closures can count.
+    TooManyUpvalues,
+    /// A chunk is longer than jumps can reach, or than [`CompilerOptions::max_chunk_len`](super::CompilerOptions).
+    ChunkTooLarge,
+}
+impl AnkokuError for CompileError {
+    fn msg(&self) -> &str {
+        match &self.kind {
+            CompileErrorType::ExpectedNumber => "expected a number",
+            CompileErrorType::ExpectedNumberOrString => "expected a number or string",
+            CompileErrorType::UnusedVariable => "unused variable",
+            CompileErrorType::AlreadyDeclared => "already a variable with this name in this scope",
+            CompileErrorType::Shadowed => "variable shadows another variable with the same name",
+            CompileErrorType::ReturnOutsideFunction => {
+                "can't return from outside a function in an imported module"
+            }
+            CompileErrorType::ImportFailed(message) => message,
+            CompileErrorType::ImportCycle => "import cycle, this file is already being imported",
+            CompileErrorType::ImportNotTopLevel => "imports must be at the top level",
+            CompileErrorType::TooManyConstants => "too many constants in one chunk",
+            CompileErrorType::TooManyLocals => "too many local variables in one function",
+            CompileErrorType::TooManyUpvalues => "too many captured variables in one function",
+            CompileErrorType::ChunkTooLarge => "too much code in one chunk",
+        }
+    }
+
+    fn code(&self) -> u32 {
+        match self.kind {
+            CompileErrorType::ExpectedNumber => 3001,
+            CompileErrorType::ExpectedNumberOrString => 3002,
+            CompileErrorType::UnusedVariable => 3003,
+            CompileErrorType::AlreadyDeclared => 3004,
+            CompileErrorType::Shadowed => 3005,
+            CompileErrorType::ReturnOutsideFunction => 3006,
+            CompileErrorType::ImportFailed(_) => 3007,
+            CompileErrorType::ImportCycle => 3008,
+            CompileErrorType::ImportNotTopLevel => 3009,
+            CompileErrorType::TooManyConstants => 3010,
+            CompileErrorType::TooManyLocals => 3011,
+            CompileErrorType::ChunkTooLarge => 3012,
+            CompileErrorType::TooManyUpvalues => 3013,
+        }
+    }
+
+    fn line_col(&self) -> Option<(u32, usize, &str)> {
+        Some((self.line_num, self.col, &self.line))
+    }
+
+    fn length(&self) -> Option<usize> {
+        Some(self.token.length)
+    }
+
+    fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    fn severity(&self) -> Severity {
+        match self.kind {
+            CompileErrorType::UnusedVariable | CompileErrorType::Shadowed => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
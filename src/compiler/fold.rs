@@ -0,0 +1,285 @@
+//! Constant folding, run over the AST before it's compiled.
+//!
+//! Folded literals keep a token spanning the whole expression they replaced, so errors involving them still point
+//! at the original source. Constant operands that would be a type error at runtime are reported here instead.
+
+use crate::{
+    parser::{
+        expr::{Expr, ExprType},
+        stmt::{Stmt, StmtType},
+        tokenizer::TokenType,
+    },
+    vm::value::Value,
+};
+
+use super::error::{CompileError, CompileErrorType};
+
+/// Folds `stmt`. With `strict_math`, divisions by zero are left alone, since they're errors the VM has to report (see
+/// [`VM::set_strict_math`](crate::vm::VM::set_strict_math)).
+pub(crate) fn fold_stmt(
+    stmt: &Stmt,
+    source: &[char],
+    strict_math: bool,
+    errors: &mut Vec<CompileError>,
+) -> Stmt {
+    Folder {
+        source,
+        strict_math,
+        errors,
+    }
+    .stmt(stmt)
+}
+
+/// Folds `expr`, like [`fold_stmt`].
+pub(crate) fn fold_expr(
+    expr: &Expr,
+    source: &[char],
+    strict_math: bool,
+    errors: &mut Vec<CompileError>,
+) -> Expr {
+    Folder {
+        source,
+        strict_math,
+        errors,
+    }
+    .expr(expr)
+}
+
+type BinaryOp = fn(Box<Expr>, Box<Expr>) -> ExprType;
+
+struct Folder<'a> {
+    source: &'a [char],
+    strict_math: bool,
+    errors: &'a mut Vec<CompileError>,
+}
+
+impl Folder<'_> {
+    fn stmt(&mut self, stmt: &Stmt) -> Stmt {
+        Stmt::new(match &stmt.kind {
+            StmtType::Print(e) => StmtType::Print(self.expr(e)),
+            StmtType::Expr(e) => StmtType::Expr(self.expr(e)),
+            StmtType::Var(token, name, e) => StmtType::Var(*token, name.clone(), self.expr(e)),
+            StmtType::Block(stmts) => StmtType::Block(stmts.iter().map(|s| self.stmt(s)).collect()),
+            StmtType::If(condition, body, else_body) => StmtType::If(
+                self.expr(condition),
+                Box::new(self.stmt(body)),
+                else_body.as_ref().map(|e| Box::new(self.stmt(e))),
+            ),
+            StmtType::While(condition, body) => {
+                StmtType::While(self.expr(condition), Box::new(self.stmt(body)))
+            }
+            StmtType::Import(token, path) => StmtType::Import(*token, path.clone()),
+            StmtType::Return(keyword, value) => {
+                StmtType::Return(*keyword, value.as_ref().map(|v| self.expr(v)))
+            }
+        })
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Expr {
+        let kind = match &expr.kind {
+            ExprType::Real(_)
+            | ExprType::Bool(_)
+            | ExprType::Null
+            | ExprType::String(_)
+            | ExprType::Var(_) => return expr.clone(),
+            ExprType::Add(l, r) => {
+                let (l, r) = (self.expr(l), self.expr(r));
+                match (&l.kind, &r.kind) {
+                    (ExprType::Real(a), ExprType::Real(b)) => {
+                        return literal(expr, ExprType::Real(a + b))
+                    }
+                    (ExprType::Bool(_) | ExprType::Null, _) => {
+                        self.error(CompileErrorType::ExpectedNumberOrString, &l)
+                    }
+                    (ExprType::Real(_), ExprType::String(_) | ExprType::Null) => {
+                        self.error(CompileErrorType::ExpectedNumber, &r)
+                    }
+                    _ => {}
+                }
+                ExprType::Add(Box::new(l), Box::new(r))
+            }
+            ExprType::Subtract(l, r) => {
+                return self.arithmetic(expr, l, r, ExprType::Subtract, |a, b| a - b)
+            }
+            ExprType::Multiply(l, r) => {
+                return self.arithmetic(expr, l, r, ExprType::Multiply, |a, b| a * b)
+            }
+            ExprType::Divide(l, r) => {
+                // the VM has to report this, so it's left for runtime
+                if self.strict_math && matches!(r.const_value(), Some(Value::Real(b)) if b == 0.0) {
+                    let (l, r) = (self.expr(l), self.expr(r));
+                    return Expr::new(expr.token, ExprType::Divide(Box::new(l), Box::new(r)));
+                }
+                return self.arithmetic(expr, l, r, ExprType::Divide, |a, b| a / b);
+            }
+            ExprType::Greater(l, r) => {
+                return self.comparison(expr, l, r, ExprType::Greater, |a, b| a > b)
+            }
+            ExprType::Less(l, r) => {
+                return self.comparison(expr, l, r, ExprType::Less, |a, b| a < b)
+            }
+            ExprType::GreaterEqual(l, r) => {
+                return self.comparison(expr, l, r, ExprType::GreaterEqual, |a, b| a >= b)
+            }
+            ExprType::LessEqual(l, r) => {
+                return self.comparison(expr, l, r, ExprType::LessEqual, |a, b| a <= b)
+            }
+            ExprType::Equal(l, r) => return self.equality(expr, l, r, ExprType::Equal, false),
+            ExprType::NotEqual(l, r) => return self.equality(expr, l, r, ExprType::NotEqual, true),
+            // strings might or might not be the same object, so this isn't folded even for literals
+            ExprType::Is(l, r) => ExprType::Is(Box::new(self.expr(l)), Box::new(self.expr(r))),
+            ExprType::Or(l, r) => ExprType::Or(Box::new(self.expr(l)), Box::new(self.expr(r))),
+            ExprType::And(l, r) => ExprType::And(Box::new(self.expr(l)), Box::new(self.expr(r))),
+            ExprType::Negate(inner) => {
+                let inner = self.expr(inner);
+                match &inner.kind {
+                    ExprType::Real(n) => return literal(expr, ExprType::Real(-n)),
+                    ExprType::Bool(_) | ExprType::Null | ExprType::String(_) => {
+                        self.error(CompileErrorType::ExpectedNumber, &inner)
+                    }
+                    _ => {}
+                }
+                ExprType::Negate(Box::new(inner))
+            }
+            ExprType::Not(inner) => {
+                let inner = self.expr(inner);
+                if let Some(falsey) = constant_falsey(&inner) {
+                    return literal(expr, ExprType::Bool(falsey));
+                }
+                ExprType::Not(Box::new(inner))
+            }
+            ExprType::Grouping(inner) => {
+                let inner = self.expr(inner);
+                if is_literal(&inner) {
+                    return literal(expr, inner.kind);
+                }
+                ExprType::Grouping(Box::new(inner))
+            }
+            ExprType::Object(pairs) => ExprType::Object(
+                pairs
+                    .iter()
+                    .map(|(key, value)| (key.clone(), Box::new(self.expr(value))))
+                    .collect(),
+            ),
+            ExprType::Function(params, body) => {
+                ExprType::Function(params.clone(), body.iter().map(|s| self.stmt(s)).collect())
+            }
+            ExprType::List(items) => {
+                ExprType::List(items.iter().map(|item| self.expr(item)).collect())
+            }
+            ExprType::Assign(name, value) => {
+                ExprType::Assign(name.clone(), Box::new(self.expr(value)))
+            }
+            ExprType::Get(object, name) => ExprType::Get(Box::new(self.expr(object)), name.clone()),
+            ExprType::Set(object, name, value) => ExprType::Set(
+                Box::new(self.expr(object)),
+                name.clone(),
+                Box::new(self.expr(value)),
+            ),
+            ExprType::Index(indexed, index) => {
+                ExprType::Index(Box::new(self.expr(indexed)), Box::new(self.expr(index)))
+            }
+            ExprType::Call(callee, args) => ExprType::Call(
+                Box::new(self.expr(callee)),
+                args.iter().map(|arg| self.expr(arg)).collect(),
+            ),
+        };
+        Expr::new(expr.token, kind)
+    }
+
+    fn arithmetic(
+        &mut self,
+        expr: &Expr,
+        l: &Expr,
+        r: &Expr,
+        make: BinaryOp,
+        op: fn(f64, f64) -> f64,
+    ) -> Expr {
+        let (l, r) = (self.expr(l), self.expr(r));
+        match (&l.kind, &r.kind) {
+            (ExprType::Real(a), ExprType::Real(b)) => {
+                return literal(expr, ExprType::Real(op(*a, *b)))
+            }
+            (ExprType::Bool(_) | ExprType::Null | ExprType::String(_), _) => {
+                self.error(CompileErrorType::ExpectedNumber, &l)
+            }
+            (_, ExprType::Null | ExprType::String(_)) => {
+                self.error(CompileErrorType::ExpectedNumber, &r)
+            }
+            _ => {}
+        }
+        Expr::new(expr.token, make(Box::new(l), Box::new(r)))
+    }
+
+    fn comparison(
+        &mut self,
+        expr: &Expr,
+        l: &Expr,
+        r: &Expr,
+        make: BinaryOp,
+        op: fn(f64, f64) -> bool,
+    ) -> Expr {
+        let (l, r) = (self.expr(l), self.expr(r));
+        for operand in [&l, &r] {
+            if is_literal(operand) && !matches!(operand.kind, ExprType::Real(_)) {
+                self.error(CompileErrorType::ExpectedNumber, operand);
+            }
+        }
+        if let (ExprType::Real(a), ExprType::Real(b)) = (&l.kind, &r.kind) {
+            return literal(expr, ExprType::Bool(op(*a, *b)));
+        }
+        Expr::new(expr.token, make(Box::new(l), Box::new(r)))
+    }
+
+    /// Folds `==`, or `!=` if `negate` is set, the same way [`Value::deep_eq`](crate::vm::value::Value::deep_eq)
+    /// compares at runtime.
+    fn equality(&mut self, expr: &Expr, l: &Expr, r: &Expr, make: BinaryOp, negate: bool) -> Expr {
+        let (l, r) = (self.expr(l), self.expr(r));
+        if is_literal(&l) && is_literal(&r) {
+            let equal = match (&l.kind, &r.kind) {
+                (ExprType::Real(a), ExprType::Real(b)) => a == b,
+                (ExprType::Bool(a), ExprType::Bool(b)) => a == b,
+                (ExprType::String(a), ExprType::String(b)) => a == b,
+                (ExprType::Null, ExprType::Null) => true,
+                _ => false,
+            };
+            return literal(expr, ExprType::Bool(equal != negate));
+        }
+        Expr::new(expr.token, make(Box::new(l), Box::new(r)))
+    }
+
+    fn error(&mut self, kind: CompileErrorType, at: &Expr) {
+        self.errors
+            .push(CompileError::new(kind, at.token, self.source));
+    }
+}
+
+fn is_literal(expr: &Expr) -> bool {
+    matches!(
+        expr.kind,
+        ExprType::Real(_) | ExprType::Bool(_) | ExprType::Null | ExprType::String(_)
+    )
+}
+
+/// Whether a folded expression is always falsey, or `None` if that isn't known until it runs. The same truthiness as
+/// [`Value::falsey`](crate::vm::value::Value::falsey).
+pub(crate) fn constant_falsey(expr: &Expr) -> Option<bool> {
+    match &expr.kind {
+        ExprType::String(s) => Some(s.is_empty()),
+        _ => expr.const_value().map(|value| value.falsey()),
+    }
+}
+
+/// Creates a literal replacing `original`, covering all of its source.
+fn literal(original: &Expr, kind: ExprType) -> Expr {
+    let mut token = original.span_token();
+    token.kind = match kind {
+        ExprType::Real(_) => TokenType::Number,
+        ExprType::Bool(true) => TokenType::True,
+        ExprType::Bool(false) => TokenType::False,
+        ExprType::Null => TokenType::Null,
+        ExprType::String(_) => TokenType::String,
+        _ => token.kind,
+    };
+    Expr::new(token, kind)
+}
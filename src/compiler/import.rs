@@ -0,0 +1,166 @@
+//! `import "path.ak";`, which compiles another module into the program.
+//!
+//! An imported module is compiled inline, at the point it's imported, as if its statements had been written there. Its
+//! globals go into the same namespace as the importer's, so anything it declares can be used after the import. Each
+//! module is only compiled once however many times it's imported, and importing a module that's still being imported
+//! is an error rather than an infinite loop.
+//!
+//! What an import's path means is up to the [`ModuleResolver`], which maps it to a module id and that module's source.
+//! [`FsResolver`], the default, reads files relative to the importing file.
+
+use std::{
+    error::Error,
+    fmt::{Debug, Display},
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use super::{error::CompileErrorType, Compiler};
+use crate::{
+    parser::{
+        stmt::Stmt,
+        tokenizer::{Token, Tokenizer},
+    },
+    util::error::AnkokuError,
+    vm::VM,
+};
+
+/// Maps the path in an `import` to the module it refers to.
+pub trait ModuleResolver {
+    /// Resolves `spec`, imported by the module with the id `from`, to the imported module's id and source. `from` is
+    /// empty for a program that wasn't given a module id.
+    ///
+    /// Modules are identified by their ids, so two specs resolving to the same id are the same module and it's only
+    /// compiled once.
+    fn resolve(&self, from: &str, spec: &str) -> Result<(String, String), ResolveError>;
+}
+
+#[derive(Debug)]
+pub enum ResolveError {
+    /// There's no module at `spec`.
+    NotFound,
+    Io(io::Error),
+    /// Any other reason a resolver couldn't load a module.
+    Other(String),
+}
+impl Error for ResolveError {}
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveError::NotFound => write!(f, "module not found"),
+            ResolveError::Io(e) => write!(f, "{}", e),
+            ResolveError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+impl From<io::Error> for ResolveError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::NotFound {
+            ResolveError::NotFound
+        } else {
+            ResolveError::Io(e)
+        }
+    }
+}
+
+/// Resolves imports to files, relative to the directory of the importing file. Module ids are the files' paths.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FsResolver;
+impl ModuleResolver for FsResolver {
+    fn resolve(&self, from: &str, spec: &str) -> Result<(String, String), ResolveError> {
+        let dir = Path::new(from).parent().unwrap_or_else(|| Path::new(""));
+        let path = normalize(&dir.join(spec));
+        let source = std::fs::read_to_string(&path)?;
+        Ok((path.to_string_lossy().into_owned(), source))
+    }
+}
+
+/// Removes `.` and `..` components from `path` without touching the filesystem, so the same file imported by two
+/// different paths has the same id.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+impl Compiler {
+    pub(super) fn import(&mut self, token: Token, spec: &str, vm: &VM) {
+        let from = self.importing.last().map(String::as_str).unwrap_or("");
+        let resolved = match &self.options.resolver {
+            Some(resolver) => resolver.resolve(from, spec),
+            None => FsResolver.resolve(from, spec),
+        };
+        let (id, source) = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                let msg = format!("couldn't import {}: {}", spec, e);
+                self.error(CompileErrorType::ImportFailed(msg), token);
+                return;
+            }
+        };
+
+        if self.importing.contains(&id) {
+            self.error(CompileErrorType::ImportCycle, token);
+            return;
+        }
+        if !self.imported.insert(id.clone()) {
+            return;
+        }
+
+        let mut tokens = vec![];
+        for result in Tokenizer::new(&source) {
+            match result {
+                Ok(t) => tokens.push(t),
+                Err(e) => return self.import_failed(token, &id, &e),
+            }
+        }
+        let chars = source.chars().collect::<Vec<_>>();
+        let (stmts, errors) = Stmt::parse(tokens, chars.clone());
+        if let Some(e) = errors.first() {
+            return self.import_failed(token, &id, e);
+        }
+
+        let source = std::mem::replace(&mut self.source, chars);
+        let filename = self.filename.replace(id.clone());
+        self.importing.push(id);
+        self.compile_stmts(&stmts, vm);
+        self.importing.pop();
+        self.filename = filename;
+        self.source = source;
+    }
+
+    /// Reports that an imported module couldn't be parsed, at the import that pulled it in.
+    fn import_failed(&mut self, token: Token, id: &str, e: &impl AnkokuError) {
+        let msg = match e.line_col() {
+            Some((line, col, _)) => format!("{}:{}:{}: {}", id, line, col, e.msg()),
+            None => format!("{}: {}", id, e.msg()),
+        };
+        self.error(CompileErrorType::ImportFailed(msg), token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+    use std::path::Path;
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(normalize(Path::new("./a/../b/./c.ak")), Path::new("b/c.ak"));
+        assert_eq!(normalize(Path::new("../a.ak")), Path::new("../a.ak"));
+        assert_eq!(normalize(Path::new("/x/../a.ak")), Path::new("/a.ak"));
+    }
+}
@@ -1,63 +1,264 @@
-use std::collections::HashMap;
+use std::{collections::HashSet, fmt::Debug, rc::Rc};
 
 use crate::{
     parser::{
         expr::{AstVisitor, Expr, ExprType},
         stmt::{Stmt, StmtType},
+        tokenizer::{Token, TokenType},
     },
-    util::fxhash::FxHashMap,
+    util::fxhash::FxHashSet,
     vm::{
         chunk::Chunk,
         instruction::Instruction,
-        obj::{AnkokuString, Obj, ObjType},
+        obj::{AnkokuString, Function, Obj, ObjType},
         value::Value,
         VM,
     },
 };
 
+use self::error::{CompileError, CompileErrorType};
+
+pub mod error;
+mod fold;
+mod import;
+
+pub use import::{FsResolver, ModuleResolver, ResolveError};
+
+/// The longest a chunk can be, in bytes. Jumps have a signed 32-bit operand, so this is as far as one can reach.
+pub const MAX_CHUNK_LEN: usize = i32::MAX as usize;
+
 struct Local {
     name: String,
+    token: Token,
     depth: usize,
+    /// Whether the local has been read, as opposed to only assigned.
+    used: bool,
+    /// Whether a closure captures the local, so it has to be moved off the stack when it goes out of scope.
+    captured: bool,
+}
+
+/// Where a closure gets one of its upvalues from when it's created.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct UpvalueRef {
+    /// Whether it's a local of the enclosing function, rather than one of the enclosing function's upvalues.
+    is_local: bool,
+    index: u8,
+}
+
+/// The state of a function whose compilation was set aside to compile a function nested in it.
+struct Enclosing {
+    chunk: Chunk,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+    in_function: bool,
 }
 
 pub struct Compiler {
     chunk: Chunk,
-    constant_pool: FxHashMap<Value, usize>,
     scope_depth: usize,
     locals: Vec<Local>,
+    /// The upvalues the function being compiled captures.
+    upvalues: Vec<UpvalueRef>,
+    /// The functions the one being compiled is nested in, outermost (the top level program) first.
+    enclosing: Vec<Enclosing>,
+    /// The source of the file being compiled, which changes while an import is compiled.
+    source: Vec<char>,
+    /// The id of the imported module being compiled, or `None` while compiling the program itself.
+    filename: Option<String>,
+    errors: Vec<CompileError>,
+    options: CompilerOptions,
+    /// Whether a function body is being compiled, rather than the top level program.
+    in_function: bool,
+    /// Globals declared by the program so far.
+    globals: FxHashSet<String>,
+    /// The ids of the modules currently being imported, innermost last. The program itself is first, if it has an id.
+    importing: Vec<String>,
+    /// Every module that's been imported, so each is only compiled once.
+    imported: FxHashSet<String>,
+    /// The scopes that have been closed so far, for [`CompileInfo`].
+    scopes: Vec<ScopeInfo>,
+}
+
+/// What a program defines, for tools like editors and debuggers. See [`Compiler::compile_with_info`].
+#[derive(Clone, Debug, Default)]
+pub struct CompileInfo {
+    /// The globals the program (and the modules it imports) declares, sorted by name.
+    pub globals: Vec<String>,
+    /// Every scope that declared locals, including function bodies, in the order they ended. Nested scopes end before
+    /// the scopes they're in.
+    pub scopes: Vec<ScopeInfo>,
+    /// The top level chunk's constants.
+    pub constants: Vec<Value>,
+}
+
+/// The locals declared in one scope.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScopeInfo {
+    /// How deeply the scope is nested in its function, or in the program if it isn't in one. A function's parameters
+    /// and top level locals are at depth 1.
+    pub depth: usize,
+    /// Whether the scope is in a function, rather than the top level program.
+    pub in_function: bool,
+    /// The names of the scope's locals, in the order they were declared.
+    pub locals: Vec<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct CompilerOptions {
+    /// Warn when a local has the same name as a local in an enclosing scope or a global.
+    pub warn_shadowing: bool,
+    /// The module id of the program being compiled, which its imports are resolved from. For [`FsResolver`] this is
+    /// its path.
+    pub module: Option<String>,
+    /// Resolves imports to modules. Defaults to [`FsResolver`].
+    pub resolver: Option<Rc<dyn ModuleResolver>>,
+    /// The longest a chunk can be before it's a [`CompileErrorType::ChunkTooLarge`] error, for hosts that want to
+    /// limit program size. Defaults to, and can't be more than, [`MAX_CHUNK_LEN`].
+    pub max_chunk_len: Option<usize>,
+}
+impl Debug for CompilerOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompilerOptions")
+            .field("warn_shadowing", &self.warn_shadowing)
+            .field("module", &self.module)
+            .field("resolver", &self.resolver.as_ref().map(|_| "<resolver>"))
+            .field("max_chunk_len", &self.max_chunk_len)
+            .finish()
+    }
 }
 impl Compiler {
-    pub fn compile(stmts: &[Stmt], vm: &VM) -> Chunk {
-        let mut compiler = Compiler {
-            chunk: Chunk::new(),
-            constant_pool: HashMap::default(),
-            scope_depth: 0,
-            locals: Vec::new(),
+    /// Compiles a program. `source` is the code `stmts` were parsed from, used to position errors. The chunk is only
+    /// valid to run if no errors were returned.
+    ///
+    /// Warnings (see [`AnkokuError::severity`](crate::util::error::AnkokuError::severity)) are returned alongside
+    /// errors, but don't stop the chunk from being run.
+    pub fn compile(stmts: &[Stmt], source: &[char], vm: &VM) -> (Chunk, Vec<CompileError>) {
+        Self::compile_with_options(stmts, source, vm, CompilerOptions::default())
+    }
+
+    /// Like [`Compiler::compile`], with control over optional diagnostics.
+    pub fn compile_with_options(
+        stmts: &[Stmt],
+        source: &[char],
+        vm: &VM,
+        options: CompilerOptions,
+    ) -> (Chunk, Vec<CompileError>) {
+        let compiler = Self::compile_program(stmts, source, vm, options);
+        (compiler.chunk, compiler.errors)
+    }
+
+    /// Like [`Compiler::compile_with_options`], also returning what the program defines.
+    pub fn compile_with_info(
+        stmts: &[Stmt],
+        source: &[char],
+        vm: &VM,
+        options: CompilerOptions,
+    ) -> (Chunk, CompileInfo, Vec<CompileError>) {
+        let compiler = Self::compile_program(stmts, source, vm, options);
+        let mut globals = compiler.globals.into_iter().collect::<Vec<_>>();
+        globals.sort();
+        let info = CompileInfo {
+            globals,
+            scopes: compiler.scopes,
+            constants: compiler.chunk.constants.clone(),
         };
-        for stmt in stmts {
-            compiler.visit_stmt(stmt, vm);
+        (compiler.chunk, info, compiler.errors)
+    }
+
+    fn compile_program(
+        stmts: &[Stmt],
+        source: &[char],
+        vm: &VM,
+        options: CompilerOptions,
+    ) -> Compiler {
+        let mut compiler = Compiler::new(source, options);
+        compiler.compile_stmts(stmts, vm);
+
+        compiler
+            .chunk
+            .write(Instruction::Return as u8, compiler.chunk.last_byte_line());
+        compiler.check_chunk(Token::new(TokenType::EOF, 0, 0, 1, 1));
+        compiler
+    }
+
+    /// Compiles a single expression, for evaluating it rather than running a program. The chunk returns with the
+    /// expression's value on top of the stack, see [`VM::eval`].
+    pub fn compile_expr(expr: &Expr, source: &[char], vm: &VM) -> (Chunk, Vec<CompileError>) {
+        let mut compiler = Compiler::new(source, CompilerOptions::default());
+        let expr = fold::fold_expr(expr, source, vm.strict_math(), &mut compiler.errors);
+        if compiler.errors.is_empty() {
+            compiler.visit_node(&expr, vm);
         }
 
         compiler
             .chunk
             .write(Instruction::Return as u8, compiler.chunk.last_byte_line());
+        compiler.check_chunk(Token::new(TokenType::EOF, 0, 0, 1, 1));
 
-        compiler.chunk
+        (compiler.chunk, compiler.errors)
     }
 
-    fn get_constant(&mut self, value: Value) -> usize {
-        self.constant_pool
-            .get(&value)
-            .copied()
-            .unwrap_or_else(|| self.chunk.add_constant(value))
+    fn new(source: &[char], options: CompilerOptions) -> Compiler {
+        let importing = options.module.iter().cloned().collect::<Vec<_>>();
+        Compiler {
+            chunk: Chunk::new(),
+            scope_depth: 0,
+            locals: Vec::new(),
+            upvalues: Vec::new(),
+            enclosing: Vec::new(),
+            source: source.to_vec(),
+            filename: None,
+            errors: vec![],
+            options,
+            in_function: false,
+            globals: HashSet::default(),
+            imported: importing.iter().cloned().collect(),
+            importing,
+            scopes: Vec::new(),
+        }
     }
-    fn write_constant(&mut self, value: Value) {
-        let constant = self.get_constant(value);
+
+    fn compile_stmts(&mut self, stmts: &[Stmt], vm: &VM) {
+        for stmt in stmts {
+            let mut fold_errors = vec![];
+            let stmt = fold::fold_stmt(stmt, &self.source, vm.strict_math(), &mut fold_errors);
+            if fold_errors.is_empty() {
+                self.visit_stmt(&stmt, vm);
+            } else {
+                for mut error in fold_errors {
+                    error.filename = self.filename.clone();
+                    self.errors.push(error);
+                }
+            }
+        }
+    }
+
+    fn write_constant(&mut self, value: Value, token: Token) {
+        let constant = self.make_constant(value, token);
 
         self.chunk
-            .write(Instruction::Constant.into(), self.chunk.last_byte_line());
-        self.chunk
-            .write(constant as u8, self.chunk.last_byte_line());
+            .write(Instruction::Constant.into(), token.line as usize);
+        self.chunk.write(constant, token.line as usize);
+    }
+
+    /// Adds a constant to the chunk for an instruction's operand. Operands are a byte, so a chunk can only have 256
+    /// constants, and `token` gets an error if there are more.
+    fn make_constant(&mut self, value: Value, token: Token) -> u8 {
+        let constant = self.chunk.intern_constant(value);
+        u8::try_from(constant).unwrap_or_else(|_| {
+            self.error(CompileErrorType::TooManyConstants, token);
+            0
+        })
+    }
+
+    /// The string `s` as a value for a constant: the chunk's existing constant with the same contents if it has one, so
+    /// a name used over and over only takes up one slot, or else a new string.
+    fn string_value(&self, s: &str, vm: &VM) -> Value {
+        match self.chunk.find_string(s) {
+            Some(constant) => self.chunk.constants[constant].clone(),
+            None => vm.alloc_constant(Obj::new(ObjType::String(AnkokuString::new(s.to_string())))),
+        }
     }
 
     fn begin_scope(&mut self) {
@@ -66,12 +267,21 @@ impl Compiler {
 
     fn end_scope(&mut self) {
         self.scope_depth -= 1;
+        self.record_scope(self.scope_depth);
 
         while !self.locals.is_empty() && self.locals[self.locals.len() - 1].depth > self.scope_depth
         {
+            let local = self.locals.pop().unwrap();
+            let instruction = if local.captured {
+                Instruction::CloseUpvalue
+            } else {
+                Instruction::Pop
+            };
             self.chunk
-                .write(Instruction::Pop.into(), self.chunk.last_byte_line());
-            self.locals.pop();
+                .write(instruction.into(), self.chunk.last_byte_line());
+            if !local.used {
+                self.error(CompileErrorType::UnusedVariable, local.token);
+            }
         }
         for local in &self.locals {
             debug_assert!(
@@ -83,30 +293,195 @@ impl Compiler {
         }
     }
 
-    fn add_local<S: Into<String>>(&mut self, name: S) {
-        if self.locals.len() > u8::MAX as usize {
-            panic!("too many locals in function") // TODO: compiler errors
+    /// Records the locals deeper than `depth`, which are about to go out of scope, as a scope for [`CompileInfo`].
+    fn record_scope(&mut self, depth: usize) {
+        let locals = self
+            .locals
+            .iter()
+            .filter(|local| local.depth > depth && !local.name.is_empty())
+            .map(|local| local.name.clone())
+            .collect::<Vec<_>>();
+        if !locals.is_empty() {
+            self.scopes.push(ScopeInfo {
+                depth: depth + 1,
+                in_function: self.in_function,
+                locals,
+            });
+        }
+    }
+
+    fn add_local<S: Into<String>>(&mut self, name: S, token: Token) {
+        // only the first local past the limit is reported
+        if self.locals.len() == u8::MAX as usize + 1 {
+            self.error(CompileErrorType::TooManyLocals, token);
         }
         self.locals.push(Local {
             name: name.into(),
+            token,
             depth: self.scope_depth,
+            used: false,
+            captured: false,
         });
     }
     fn resolve_local(&self, name: &str) -> Option<usize> {
-        for (i, local) in self.locals.iter().enumerate().rev() {
-            println!("i = {}, {:?}, {:?}", i, local.name, local.depth);
-            if local.name == name {
-                return Some(i);
-            }
+        self.locals.iter().rposition(|local| local.name == name)
+    }
+
+    /// Whether evaluating `expr` can't have any effect, so an expression statement of it can be left out entirely.
+    /// Locals it reads still count as used. Global reads aren't pure, since reading an undefined global is an error.
+    fn is_pure(&mut self, expr: &Expr) -> bool {
+        match &expr.kind {
+            ExprType::Real(_) | ExprType::Bool(_) | ExprType::Null | ExprType::String(_) => true,
+            ExprType::Grouping(e) => self.is_pure(e),
+            ExprType::Var(name) => match self.resolve_local(name) {
+                Some(local) => {
+                    self.locals[local].used = true;
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Resolves `name` to a local of an enclosing function, capturing it as an upvalue.
+    fn resolve_upvalue(&mut self, name: &str, token: Token) -> Option<u8> {
+        self.resolve_upvalue_at(self.enclosing.len(), name, token)
+    }
+
+    /// Resolves `name` as an upvalue of the function nested `depth` functions deep, where the top level program is 0
+    /// and the function being compiled is `self.enclosing.len()`. Every function between it and the local is given
+    /// an upvalue for it, so the closure can be passed down.
+    fn resolve_upvalue_at(&mut self, depth: usize, name: &str, token: Token) -> Option<u8> {
+        // the top level program isn't a function and doesn't have upvalues
+        let outer = depth.checked_sub(1)?;
+        let local = self.enclosing[outer]
+            .locals
+            .iter()
+            .rposition(|local| local.name == name);
+        if let Some(local) = local {
+            let local_ref = &mut self.enclosing[outer].locals[local];
+            local_ref.captured = true;
+            local_ref.used = true;
+            return Some(self.add_upvalue(depth, true, local as u8, token));
+        }
+        let upvalue = self.resolve_upvalue_at(outer, name, token)?;
+        Some(self.add_upvalue(depth, false, upvalue, token))
+    }
+
+    fn add_upvalue(&mut self, depth: usize, is_local: bool, index: u8, token: Token) -> u8 {
+        let upvalues = if depth == self.enclosing.len() {
+            &mut self.upvalues
+        } else {
+            &mut self.enclosing[depth].upvalues
+        };
+        let upvalue = UpvalueRef { is_local, index };
+        if let Some(i) = upvalues.iter().position(|u| *u == upvalue) {
+            return i as u8;
+        }
+        // the count has to fit in `Function::upvalue_count`, and only the first upvalue past it is reported
+        let past_limit = upvalues.len() == u8::MAX as usize;
+        upvalues.push(upvalue);
+        let index = (upvalues.len() - 1) as u8;
+        if past_limit {
+            self.error(CompileErrorType::TooManyUpvalues, token);
+        }
+        index
+    }
+
+    /// Compiles a function and emits the code to create it: a constant, or a closure if it captures anything.
+    fn emit_function(
+        &mut self,
+        token: Token,
+        name: Option<String>,
+        params: &[(Token, String)],
+        body: &[Stmt],
+        vm: &VM,
+    ) {
+        let (function, upvalues) = self.function(token, name, params, body, vm);
+        let function = vm.alloc_constant(Obj::new(ObjType::Function(function)));
+        if upvalues.is_empty() {
+            self.write_constant(function, token);
+            return;
         }
-        None
+        let constant = self.make_constant(function, token);
+        let line = self.chunk.last_byte_line();
+        self.chunk.write(Instruction::Closure.into(), line);
+        self.chunk.write(constant, line);
+        for upvalue in upvalues {
+            self.chunk.write(upvalue.is_local as u8, line);
+            self.chunk.write(upvalue.index, line);
+        }
+    }
+
+    /// Compiles a function body into a chunk of its own, returning it with the upvalues its closures capture. The
+    /// enclosing function is set aside in `enclosing` until the function is done.
+    fn function(
+        &mut self,
+        token: Token,
+        name: Option<String>,
+        params: &[(Token, String)],
+        body: &[Stmt],
+        vm: &VM,
+    ) -> (Function, Vec<UpvalueRef>) {
+        self.enclosing.push(Enclosing {
+            chunk: std::mem::take(&mut self.chunk),
+            locals: std::mem::take(&mut self.locals),
+            upvalues: std::mem::take(&mut self.upvalues),
+            scope_depth: std::mem::replace(&mut self.scope_depth, 1),
+            in_function: std::mem::replace(&mut self.in_function, true),
+        });
+
+        // slot 0 holds the function being called
+        self.add_local("", token);
+        for (token, name) in params {
+            self.add_local(name.clone(), *token);
+        }
+        for local in &mut self.locals {
+            local.used = true;
+        }
+        for stmt in body {
+            self.visit_stmt(stmt, vm);
+        }
+        // the function's own scope is never ended, its locals are dropped when it returns
+        self.record_scope(0);
+        let unused = self
+            .locals
+            .iter()
+            .filter(|local| !local.used)
+            .map(|local| local.token)
+            .collect::<Vec<_>>();
+        for token in unused {
+            self.error(CompileErrorType::UnusedVariable, token);
+        }
+        self.write_constant(Value::Null, token);
+        self.chunk
+            .write(Instruction::Return.into(), self.chunk.last_byte_line());
+        self.check_chunk(token);
+
+        let enclosing = self.enclosing.pop().unwrap();
+        let function_chunk = std::mem::replace(&mut self.chunk, enclosing.chunk);
+        let upvalues = std::mem::replace(&mut self.upvalues, enclosing.upvalues);
+        self.locals = enclosing.locals;
+        self.scope_depth = enclosing.scope_depth;
+        self.in_function = enclosing.in_function;
+
+        let mut function = Function::new(name, params.len() as u8, function_chunk);
+        function.upvalue_count = upvalues.len() as u8;
+        (function, upvalues)
+    }
+
+    fn error(&mut self, kind: CompileErrorType, token: Token) {
+        let mut error = CompileError::new(kind, token, &self.source);
+        error.filename = self.filename.clone();
+        self.errors.push(error);
     }
 
     fn emit_jump(&mut self, instruction: Instruction) -> usize {
         self.chunk
             .write(instruction.into(), self.chunk.last_byte_line());
 
-        // Jump instructions all take a 32 bit uint
+        // Jump instructions all take a signed 32 bit offset, relative to the end of the instruction
 
         self.chunk.write(0xFF, self.chunk.last_byte_line());
         self.chunk.write(0xFF, self.chunk.last_byte_line());
@@ -119,44 +494,145 @@ impl Compiler {
     fn emit_loop(&mut self, loop_start: usize) {
         self.chunk
             .write(Instruction::Jump.into(), self.chunk.last_byte_line());
-        let offset = loop_start;
-        if offset > u32::MAX as usize {
-            panic!("Too much code to loop on.");
-        }
+        let offset = loop_start as i64 - (self.chunk.code.len() + 4) as i64;
+        // if it doesn't fit, the chunk is too large and `check_chunk` reports it
+        let offset = i32::try_from(offset).unwrap_or(0);
 
-        self.chunk
-            .write(((offset >> 24) & 0xff) as u8, self.chunk.last_byte_line());
-        self.chunk
-            .write(((offset >> 16) & 0xff) as u8, self.chunk.last_byte_line());
-        self.chunk
-            .write(((offset >> 8) & 0xff) as u8, self.chunk.last_byte_line());
-        self.chunk
-            .write((offset & 0xff) as u8, self.chunk.last_byte_line());
+        for byte in offset.to_be_bytes() {
+            self.chunk.write(byte, self.chunk.last_byte_line());
+        }
     }
 
     fn patch_jump(&mut self, jmp_offset: usize) {
-        let jump = self.chunk.code.len(); // TODO: WHY IS THIS BROKEN??!
+        let jump = self.chunk.code.len() - (jmp_offset + 4);
+        // if it doesn't fit, the chunk is too large and `check_chunk` reports it
+        let jump = i32::try_from(jump).unwrap_or(0);
+
+        self.chunk.code[jmp_offset..jmp_offset + 4].copy_from_slice(&jump.to_be_bytes());
+    }
+
+    /// Checks a finished chunk against the limits of its operands. Constants and locals are checked as they're added,
+    /// but jumps can only be checked once all the code is there: they all fit as long as the chunk isn't longer than
+    /// [`MAX_CHUNK_LEN`]. The error points at the code that went over, or at `token` if that isn't known.
+    fn check_chunk(&mut self, token: Token) {
+        let max = self
+            .options
+            .max_chunk_len
+            .map_or(MAX_CHUNK_LEN, |max| max.min(MAX_CHUNK_LEN));
+        if self.chunk.byte_len() <= max {
+            return;
+        }
+        let token = match self.chunk.span_at(max) {
+            Some((start, end)) => {
+                let before = &self.source[..start];
+                let line = before.iter().filter(|c| **c == '\n').count() as u32 + 1;
+                let line_start = before.iter().rposition(|c| *c == '\n').map_or(0, |i| i + 1);
+                Token::new(token.kind, start, end - start, line, start - line_start + 1)
+            }
+            None => token,
+        };
+        self.error(CompileErrorType::ChunkTooLarge, token);
+    }
 
-        if jump > u32::MAX as usize {
-            panic!("Too much code to jump over.");
+    /// Checks that the code compiled for a statement, from `start` to the end of the chunk, leaves the stack as much
+    /// deeper as the compiler thinks: by the number of locals it declared, which was `locals` before it. `GetLocal`
+    /// and `SetLocal` index the stack by local numbers, so a mismatch means they'd read and write the wrong slots.
+    /// Every path through the code is followed, and must agree. Paths that return are done early, and code after an
+    /// error can be wrong, so it isn't checked.
+    #[cfg(debug_assertions)]
+    fn check_stack_depth(&self, start: usize, locals: usize) {
+        use crate::util::error::{AnkokuError, Severity};
+
+        if self.errors.iter().any(|e| e.severity() == Severity::Error) {
+            return;
+        }
+        let end = self.chunk.code.len();
+        let mut depths = vec![None; end - start + 1];
+        let mut pending = vec![(start, 0)];
+        while let Some((offset, depth)) = pending.pop() {
+            assert!(
+                (start..=end).contains(&offset),
+                "{:04}: jump out of the statement at {:04}..{:04}",
+                offset,
+                start,
+                end
+            );
+            match depths[offset - start] {
+                Some(seen) => {
+                    assert_eq!(
+                        seen, depth,
+                        "{:04}: paths reach it at different depths",
+                        offset
+                    );
+                    continue;
+                }
+                None => depths[offset - start] = Some(depth),
+            }
+            if offset == end {
+                continue;
+            }
+            let byte = self.chunk.code[offset];
+            let instruction = Instruction::from_byte(byte)
+                .unwrap_or_else(|| panic!("{:04}: {:#04x} isn't an instruction", offset, byte));
+            let next = self.chunk.next_instruction(offset);
+            assert!(
+                next <= end,
+                "{:04}: {:?}'s operands run past the statement's code",
+                offset,
+                instruction
+            );
+            let depth = depth + instruction.stack_effect(&self.chunk.code[offset + 1..next]);
+            assert!(
+                depth >= 0,
+                "{:04}: {:?} pops below the statement",
+                offset,
+                instruction
+            );
+            match instruction {
+                Instruction::Return => {}
+                Instruction::Jump | Instruction::JumpIfFalse => {
+                    let target = self.chunk.jump_target(offset).unwrap() as usize;
+                    pending.push((target, depth));
+                    if instruction == Instruction::JumpIfFalse {
+                        pending.push((next, depth));
+                    }
+                }
+                // skipping the fallback
+                Instruction::IncLocal | Instruction::DecLocal => pending.push((next + 3, depth)),
+                _ => pending.push((next, depth)),
+            }
         }
+        if let Some(depth) = depths[end - start] {
+            let declared = self.locals.len() as isize - locals as isize;
+            assert_eq!(
+                depth, declared,
+                "{:04}..{:04}: the statement's code changes the stack by {} but it declared {} locals",
+                start, end, depth, declared
+            );
+        }
+    }
 
-        self.chunk.code[jmp_offset] = ((jump >> 24) & 0xff) as u8;
-        self.chunk.code[jmp_offset + 1] = ((jump >> 16) & 0xff) as u8;
-        self.chunk.code[jmp_offset + 2] = ((jump >> 8) & 0xff) as u8;
-        self.chunk.code[jmp_offset + 3] = (jump & 0xff) as u8;
+    /// Compiles a statement that can never run into a chunk of its own and throws it away, so it's still checked for
+    /// errors without any of its code or constants ending up in the program.
+    fn check_dead_stmt(&mut self, stmt: &Stmt, vm: &VM) {
+        let chunk = std::mem::take(&mut self.chunk);
+        self.visit_stmt(stmt, vm);
+        self.chunk = chunk;
     }
-}
-impl AstVisitor<(), ()> for Compiler {
-    fn visit_stmt(&mut self, stmt: &Stmt, vm: &VM) {
+
+    fn emit_stmt(&mut self, stmt: &Stmt, vm: &VM) {
         macro_rules! write_byte {
             ($b:expr) => {
                 self.chunk.write($b, self.chunk.last_byte_line());
             };
         }
+        self.chunk.set_span(None);
 
         match &stmt.kind {
             StmtType::Expr(e) => {
+                if self.is_pure(e) {
+                    return;
+                }
                 self.visit_node(e, vm);
                 write_byte!(Instruction::Pop as u8);
             }
@@ -164,25 +640,36 @@ impl AstVisitor<(), ()> for Compiler {
                 self.visit_node(e, vm);
                 write_byte!(Instruction::Print as u8);
             }
-            StmtType::Var(name, value) => {
-                self.visit_node(value, vm);
+            StmtType::Var(token, name, value) => {
+                if let ExprType::Function(params, body) = &value.kind {
+                    // a function is named after the variable it's declared as
+                    self.emit_function(value.token, Some(name.clone()), params, body, vm);
+                } else {
+                    self.visit_node(value, vm);
+                }
                 if self.scope_depth == 0 {
-                    let constant = self.get_constant(Value::Obj(
-                        vm.alloc(Obj::new(ObjType::String(AnkokuString::new(name.clone())))),
-                    ));
+                    let constant = self.make_constant(self.string_value(name, vm), *token);
                     write_byte!(Instruction::DefineGlobal.into());
-                    write_byte!(constant as u8);
+                    write_byte!(constant);
+                    self.globals.insert(name.clone());
                 } else {
+                    let mut redeclared = false;
+                    let mut shadowed = self.globals.contains(name) || vm.has_global(name);
                     for local in self.locals.iter().rev() {
-                        if local.depth < self.scope_depth {
-                            break;
-                        }
-
                         if *name == local.name {
-                            panic!("already variable named {:?} in this scope", name);
+                            if local.depth < self.scope_depth {
+                                shadowed = true;
+                            } else {
+                                redeclared = true;
+                            }
                         }
                     }
-                    self.add_local(name);
+                    if redeclared {
+                        self.error(CompileErrorType::AlreadyDeclared, *token);
+                    } else if shadowed && self.options.warn_shadowing {
+                        self.error(CompileErrorType::Shadowed, *token);
+                    }
+                    self.add_local(name, *token);
                 }
             }
             StmtType::Block(block) => {
@@ -193,7 +680,27 @@ impl AstVisitor<(), ()> for Compiler {
                 self.end_scope();
             }
             StmtType::If(condition, body, else_body) => {
+                // a constant condition only ever takes one branch, so the other's code is thrown away
+                match fold::constant_falsey(condition) {
+                    Some(false) => {
+                        self.visit_stmt(body, vm);
+                        if let Some(else_body) = else_body {
+                            self.check_dead_stmt(else_body, vm);
+                        }
+                        return;
+                    }
+                    Some(true) => {
+                        self.check_dead_stmt(body, vm);
+                        if let Some(else_body) = else_body {
+                            self.visit_stmt(else_body, vm);
+                        }
+                        return;
+                    }
+                    None => {}
+                }
                 self.visit_node(condition, vm);
+                // code that runs whichever branch is taken is on the condition's line, not the last branch's
+                let line = self.chunk.last_byte_line();
 
                 let jump = self.emit_jump(Instruction::JumpIfFalse);
 
@@ -204,7 +711,7 @@ impl AstVisitor<(), ()> for Compiler {
                 let else_jump = self.emit_jump(Instruction::Jump);
 
                 self.patch_jump(jump);
-                write_byte!(Instruction::Pop.into());
+                self.chunk.write(Instruction::Pop.into(), line);
                 if let Some(else_body) = else_body {
                     self.visit_stmt(else_body, vm);
                 }
@@ -212,8 +719,19 @@ impl AstVisitor<(), ()> for Compiler {
             }
             StmtType::While(cond, body) => {
                 let loop_start = self.chunk.code.len();
+                match fold::constant_falsey(cond) {
+                    Some(true) => return self.check_dead_stmt(body, vm),
+                    // loops forever, so there's nothing to check or jump out to
+                    Some(false) => {
+                        self.visit_stmt(body, vm);
+                        self.emit_loop(loop_start);
+                        return;
+                    }
+                    None => {}
+                }
 
                 self.visit_node(cond, vm);
+                let line = self.chunk.last_byte_line();
 
                 let exit_jump = self.emit_jump(Instruction::JumpIfFalse);
                 write_byte!(Instruction::Pop.into());
@@ -221,10 +739,41 @@ impl AstVisitor<(), ()> for Compiler {
                 self.emit_loop(loop_start);
 
                 self.patch_jump(exit_jump);
-                write_byte!(Instruction::Pop.into());
+                self.chunk.write(Instruction::Pop.into(), line);
+            }
+            StmtType::Import(token, path) => {
+                if self.in_function || self.scope_depth > 0 {
+                    self.error(CompileErrorType::ImportNotTopLevel, *token);
+                    return;
+                }
+                self.import(*token, path, vm);
+            }
+            StmtType::Return(keyword, value) => {
+                // the program can return a value to whoever ran it, but an imported module's code is spliced into the
+                // program and would return from that instead
+                if !self.in_function && self.filename.is_some() {
+                    self.error(CompileErrorType::ReturnOutsideFunction, *keyword);
+                    return;
+                }
+                match value {
+                    Some(value) => self.visit_node(value, vm),
+                    None => self.write_constant(Value::Null, *keyword),
+                }
+                write_byte!(Instruction::Return.into());
             }
         }
     }
+}
+impl AstVisitor<(), ()> for Compiler {
+    fn visit_stmt(&mut self, stmt: &Stmt, vm: &VM) {
+        #[cfg(debug_assertions)]
+        let (start, locals) = (self.chunk.code.len(), self.locals.len());
+        let offset = self.chunk.code.len();
+        self.emit_stmt(stmt, vm);
+        self.chunk.mark_statement(offset);
+        #[cfg(debug_assertions)]
+        self.check_stack_depth(start, locals);
+    }
 
     fn visit_node(&mut self, node: &Expr, vm: &VM) {
         macro_rules! write_byte {
@@ -232,14 +781,26 @@ impl AstVisitor<(), ()> for Compiler {
                 self.chunk.write($b, node.token.line as usize);
             };
         }
+        // code written for this node's children gets their spans, and then this node's span is restored
+        let outer = self.chunk.current_span();
+        self.chunk.set_span(Some(node.span()));
         match &node.kind {
             ExprType::Real(n) => {
-                self.write_constant((*n).into());
+                // comparing bits keeps -0 out, it isn't an integer as far as this is concerned
+                let small = *n as i8;
+                if (small as f64).to_bits() == n.to_bits() {
+                    write_byte!(Instruction::SmallInt.into());
+                    write_byte!(small as u8);
+                } else {
+                    self.write_constant((*n).into(), node.token);
+                }
             }
             ExprType::Bool(n) => {
-                self.write_constant((*n).into());
+                self.write_constant((*n).into(), node.token);
+            }
+            ExprType::Null => {
+                self.write_constant(Value::Null, node.token);
             }
-            ExprType::Null => todo!(),
             ExprType::Add(l, r) => {
                 self.visit_node(l, vm);
                 self.visit_node(r, vm);
@@ -278,48 +839,79 @@ impl AstVisitor<(), ()> for Compiler {
                 self.visit_node(b, vm);
             }
             ExprType::Object(table) => {
-                write_byte!(Instruction::NewObject.into());
+                if table.is_empty() {
+                    write_byte!(Instruction::NewObject.into());
+                } else {
+                    // sized up front so the table doesn't rehash as the fields are set. Duplicate keys make this an
+                    // overestimate, which is harmless
+                    write_byte!(Instruction::NewObjectSized.into());
+                    write_byte!(table.len().min(u8::MAX as usize) as u8);
+                }
 
                 for (key, value) in table {
-                    self.write_constant(Value::Obj(vm.alloc(Obj::new(ObjType::String(
-                        AnkokuString::new(key.to_string()),
-                    )))));
+                    self.write_constant(self.string_value(key, vm), node.token);
                     self.visit_node(value, vm);
                     write_byte!(Instruction::ObjectSet.into());
                 }
             }
+            ExprType::Function(params, body) => {
+                self.emit_function(node.token, None, params, body, vm);
+            }
+            ExprType::List(items) => {
+                write_byte!(Instruction::NewList.into());
+
+                for item in items {
+                    self.visit_node(item, vm);
+                    write_byte!(Instruction::ListPush.into());
+                }
+            }
             ExprType::Var(s) => {
                 if let Some(local) = self.resolve_local(s) {
+                    self.locals[local].used = true;
                     write_byte!(Instruction::GetLocal.into());
                     write_byte!(local as u8);
+                } else if let Some(upvalue) = self.resolve_upvalue(s, node.token) {
+                    write_byte!(Instruction::GetUpvalue.into());
+                    write_byte!(upvalue);
                 } else {
-                    let constant = self.get_constant(Value::Obj(
-                        vm.alloc(Obj::new(ObjType::String(AnkokuString::new(s.to_string())))), // intern this too
-                    ));
+                    let constant = self.make_constant(self.string_value(s, vm), node.token);
 
                     write_byte!(Instruction::GetGlobal.into());
-                    write_byte!(constant as u8);
+                    write_byte!(constant);
                 }
             }
             ExprType::Assign(name, value) => {
-                self.visit_node(value, vm);
-
-                if let Some(local) = self.resolve_local(name) {
+                let local = self.resolve_local(name);
+                if let (Some(local), Some(step)) = (local, counter_step(name, value)) {
+                    self.locals[local].used = true;
+                    let fallback = match step {
+                        Instruction::IncLocal => Instruction::Add,
+                        _ => Instruction::Sub,
+                    };
+                    write_byte!(step.into());
+                    write_byte!(local as u8);
+                    write_byte!(fallback.into());
                     write_byte!(Instruction::SetLocal.into());
                     write_byte!(local as u8);
                 } else {
-                    let constant = self.get_constant(Value::Obj(vm.alloc(Obj::new(
-                        ObjType::String(AnkokuString::new(name.to_string())),
-                    ))));
+                    self.visit_node(value, vm);
+
+                    if let Some(local) = local {
+                        write_byte!(Instruction::SetLocal.into());
+                        write_byte!(local as u8);
+                    } else if let Some(upvalue) = self.resolve_upvalue(name, node.token) {
+                        write_byte!(Instruction::SetUpvalue.into());
+                        write_byte!(upvalue);
+                    } else {
+                        let constant = self.make_constant(self.string_value(name, vm), node.token);
 
-                    write_byte!(Instruction::SetGlobal.into());
-                    write_byte!(constant as u8);
+                        write_byte!(Instruction::SetGlobal.into());
+                        write_byte!(constant);
+                    }
                 }
             }
             ExprType::String(s) => {
-                self.write_constant(Value::Obj(
-                    vm.alloc(Obj::new(ObjType::String(AnkokuString::new(s.to_string())))), // intern this too
-                ));
+                self.write_constant(self.string_value(s, vm), node.token);
             }
             ExprType::And(l, r) => {
                 self.visit_node(l, vm);
@@ -341,6 +933,36 @@ impl AstVisitor<(), ()> for Compiler {
                 self.visit_node(r, vm);
                 self.patch_jump(end_jump);
             }
+            ExprType::Call(callee, args) => {
+                self.visit_node(callee, vm);
+                for arg in args {
+                    self.visit_node(arg, vm);
+                }
+
+                write_byte!(Instruction::Call.into());
+                write_byte!(args.len() as u8);
+            }
+            ExprType::Get(object, name) => {
+                self.visit_node(object, vm);
+                let constant = self.make_constant(self.string_value(name, vm), node.token);
+
+                write_byte!(Instruction::GetField.into());
+                write_byte!(constant);
+            }
+            ExprType::Set(object, name, value) => {
+                self.visit_node(object, vm);
+                self.visit_node(value, vm);
+                let constant = self.make_constant(self.string_value(name, vm), node.token);
+
+                write_byte!(Instruction::SetField.into());
+                write_byte!(constant);
+            }
+            ExprType::Index(indexed, index) => {
+                self.visit_node(indexed, vm);
+                self.visit_node(index, vm);
+
+                write_byte!(Instruction::Index.into());
+            }
             ExprType::Greater(l, r) => {
                 self.visit_node(l, vm);
                 self.visit_node(r, vm);
@@ -353,16 +975,72 @@ impl AstVisitor<(), ()> for Compiler {
 
                 write_byte!(Instruction::Less.into());
             }
+            ExprType::GreaterEqual(l, r) => {
+                self.visit_node(l, vm);
+                self.visit_node(r, vm);
+
+                write_byte!(Instruction::GreaterEqual.into());
+            }
+            ExprType::LessEqual(l, r) => {
+                self.visit_node(l, vm);
+                self.visit_node(r, vm);
+
+                write_byte!(Instruction::LessEqual.into());
+            }
+            ExprType::Equal(l, r) => {
+                self.visit_node(l, vm);
+                self.visit_node(r, vm);
+
+                write_byte!(Instruction::Equal.into());
+            }
+            ExprType::NotEqual(l, r) => {
+                self.visit_node(l, vm);
+                self.visit_node(r, vm);
+
+                write_byte!(Instruction::Equal.into());
+                write_byte!(Instruction::Not.into());
+            }
+            ExprType::Is(l, r) => {
+                self.visit_node(l, vm);
+                self.visit_node(r, vm);
+
+                write_byte!(Instruction::Identical.into());
+            }
         };
+        self.chunk.set_span(outer);
+    }
+}
+
+/// `IncLocal` or `DecLocal` if assigning `value` to `name` counts it up or down by one: `x = x + 1` or `x = x - 1`.
+fn counter_step(name: &str, value: &Expr) -> Option<Instruction> {
+    let (ExprType::Add(l, r) | ExprType::Subtract(l, r)) = &value.kind else {
+        return None;
+    };
+    match (&l.kind, &r.kind) {
+        (ExprType::Var(var), ExprType::Real(step)) if var.as_str() == name && *step == 1.0 => {
+            Some(match value.kind {
+                ExprType::Add(..) => Instruction::IncLocal,
+                _ => Instruction::DecLocal,
+            })
+        }
+        _ => None,
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::rc::Rc;
+
     use crate::{
-        compiler::Compiler,
-        parser::{stmt::Stmt, tokenizer::Tokenizer, ParserError},
-        vm::{InterpretResult, VM},
+        compiler::{
+            error::{CompileError, CompileErrorType},
+            Compiler, CompilerOptions, ModuleResolver, ResolveError, ScopeInfo,
+        },
+        parser::{expr::Expr, stmt::Stmt, tokenizer::Tokenizer, ParserError},
+        util::error::{AnkokuError, Severity},
+        vm::{
+            chunk::Chunk, instruction::Instruction, obj::ObjType, value::Value, InterpretResult, VM,
+        },
     };
 
     fn parse_stmts<S: AsRef<str>>(source: S) -> (Vec<Stmt>, Vec<ParserError>) {
@@ -388,6 +1066,92 @@ mod tests {
         }
     }
 
+    #[test]
+    fn compile_info() {
+        let vm = VM::new();
+        let source = "var a = 1; var b = 2;";
+        let stmts = parse_stmts_unwrap(source);
+        let chars = source.chars().collect::<Vec<_>>();
+        let (_, info, errors) =
+            Compiler::compile_with_info(&stmts, &chars, &vm, CompilerOptions::default());
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(info.globals, ["a", "b"]);
+        assert!(info.scopes.is_empty());
+        // compared by contents, so this doesn't depend on which objects the strings were allocated as
+        assert_eq!(
+            info.constants
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+
+        let source = "var f = fn(x) { var y = x; { var z = y; print z; } return y; };";
+        let stmts = parse_stmts_unwrap(source);
+        let chars = source.chars().collect::<Vec<_>>();
+        let (_, info, errors) =
+            Compiler::compile_with_info(&stmts, &chars, &vm, CompilerOptions::default());
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(info.globals, ["f"]);
+        assert_eq!(
+            info.scopes,
+            [
+                ScopeInfo {
+                    depth: 2,
+                    in_function: true,
+                    locals: vec!["z".to_string()],
+                },
+                ScopeInfo {
+                    depth: 1,
+                    in_function: true,
+                    locals: vec!["x".to_string(), "y".to_string()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_expr() {
+        let source = "1 + 2 * a";
+        let tokens = Tokenizer::new(source).map(|v| v.unwrap()).collect();
+        let chars = source.chars().collect::<Vec<_>>();
+        let expr = Expr::parse(tokens, chars.clone()).unwrap();
+
+        let mut vm = VM::new();
+        let (chunk, errors) = compile("var a = 3;", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(vm.interpret(chunk), InterpretResult::Ok);
+        let (chunk, errors) = Compiler::compile_expr(&expr, &chars, &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        verify(&chunk);
+        assert_eq!(vm.eval(chunk), Some(Value::Real(7.0)));
+    }
+
+    fn compile<S: AsRef<str>>(source: S, vm: &VM) -> (Chunk, Vec<CompileError>) {
+        let stmts = parse_stmts_unwrap(source.as_ref());
+        let (chunk, errors) =
+            Compiler::compile(&stmts, &source.as_ref().chars().collect::<Vec<_>>(), vm);
+        if errors.is_empty() {
+            verify(&chunk);
+        }
+        (chunk, errors)
+    }
+
+    /// Verifies a chunk and the chunks of all the functions in it.
+    fn verify(chunk: &Chunk) {
+        if let Err(e) = chunk.verify() {
+            chunk.disassemble("unverifiable chunk");
+            panic!("{}", e);
+        }
+        for constant in &chunk.constants {
+            if let Value::Obj(o) = constant {
+                if let ObjType::Function(f) = &o.kind {
+                    verify(&f.chunk);
+                }
+            }
+        }
+    }
+
     // #[test]
     // fn basic() {
     //     let expr = parse_expr("1 + 2 * 3 - 4 / -5").unwrap();
@@ -401,29 +1165,615 @@ mod tests {
 
     #[test]
     fn statements() {
-        let stmt = parse_stmts_unwrap("print 1 + 2 * 3 - 4 / -5; print 15;");
         let mut vm = VM::new();
-        let compiled = Compiler::compile(&stmt, &vm);
+        let (compiled, errors) = compile("print 1 + 2 * 3 - 4 / -5; print 15;", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
         compiled.disassemble("compiled");
         let result = vm.interpret(compiled);
         assert_eq!(result, InterpretResult::Ok);
     }
     #[test]
     fn objects() {
-        let stmt = parse_stmts_unwrap("print { a = 1, b = 2 }; print 1;");
         let mut vm = VM::new();
-        let compiled = Compiler::compile(&stmt, &vm);
+        let (compiled, errors) = compile("print { a = 1, b = 2 }; print 1;", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
         compiled.disassemble("compiled");
         let result = vm.interpret(compiled);
         assert_eq!(result, InterpretResult::Ok);
     }
     #[test]
     fn variables() {
-        let stmt = parse_stmts_unwrap("var a = 12; print a; a = 13; print a;");
         let mut vm = VM::new();
-        let compiled = Compiler::compile(&stmt, &vm);
+        let (compiled, errors) = compile("var a = 12; print a; a = 13; print a;", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
         compiled.disassemble("compiled");
         let result = vm.interpret(compiled);
         assert_eq!(result, InterpretResult::Ok);
     }
+
+    #[test]
+    fn folding() {
+        let vm = VM::new();
+        let (compiled, errors) = compile("print 1 + 2 * 3 - 4 / -5;", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        compiled.disassemble("folded");
+        assert_eq!(compiled.constants, vec![7.8.into()]);
+    }
+
+    #[test]
+    fn folded_type_error_keeps_span() {
+        let vm = VM::new();
+        let (_, errors) = compile("var a = 1;\nprint 1 < 2 < 3;", &vm);
+        assert_eq!(errors.len(), 1);
+        let err = &errors[0];
+        assert_eq!(err.kind, CompileErrorType::ExpectedNumber);
+        // `1 < 2` was folded to `true`, but the error still underlines the original comparison
+        assert_eq!(err.line_col(), Some((2, 7, "print 1 < 2 < 3;")));
+        assert_eq!(err.length(), Some("1 < 2".len()));
+    }
+
+    #[test]
+    fn grouping_error_spans_parens() {
+        let vm = VM::new();
+        let (_, errors) = compile("print (1 < 2) * 3;", &vm);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, CompileErrorType::ExpectedNumber);
+        assert_eq!(errors[0].line_col(), Some((1, 7, "print (1 < 2) * 3;")));
+        assert_eq!(errors[0].length(), Some("(1 < 2)".len()));
+    }
+
+    #[test]
+    fn constant_if() {
+        let vm = VM::new();
+        let (compiled, errors) = compile("if (false) print 1;", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(compiled.code, vec![Instruction::Return.into()]);
+
+        let (compiled, errors) = compile("if (1 < 2) print 1; else print 2;", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        compiled.disassemble("constant if");
+        assert_eq!(
+            compiled.code,
+            vec![
+                Instruction::SmallInt.into(),
+                1,
+                Instruction::Print.into(),
+                Instruction::Return.into()
+            ]
+        );
+    }
+
+    #[test]
+    fn dead_branches_are_checked() {
+        let vm = VM::new();
+        let body = "{ var a = 1; var a = 2; print a; }";
+        let kinds =
+            |errors: &[CompileError]| errors.iter().map(|e| e.kind.clone()).collect::<Vec<_>>();
+        let (_, errors) = compile(body, &vm);
+        let expected = kinds(&errors);
+        assert!(expected.contains(&CompileErrorType::AlreadyDeclared));
+        for source in [
+            format!("if (false) {}", body),
+            format!("if (true) print 1; else {}", body),
+            format!("while (false) {}", body),
+        ] {
+            let (compiled, errors) = compile(&source, &vm);
+            assert_eq!(kinds(&errors), expected, "{}", source);
+            assert!(compiled.constants.is_empty(), "{}", source);
+        }
+
+        // but none of their code is kept
+        let (compiled, errors) = compile("if (false) print \"never\";", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(compiled.code, vec![Instruction::Return.into()]);
+        assert!(compiled.constants.is_empty());
+    }
+
+    #[test]
+    fn counters() {
+        let vm = VM::new();
+        let (compiled, errors) = compile(
+            "{ var i = 0; i = i + 1; i = i - 1; i = i + 2; i = 1 + i; }",
+            &vm,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        let instructions = compiled
+            .instructions()
+            .map(|(_, instruction, _)| instruction)
+            .collect::<Vec<_>>();
+        let steps = [Instruction::IncLocal, Instruction::DecLocal];
+        assert_eq!(
+            instructions
+                .iter()
+                .filter(|i| steps.contains(i))
+                .collect::<Vec<_>>(),
+            [&Instruction::IncLocal, &Instruction::DecLocal]
+        );
+        let inc = instructions
+            .iter()
+            .position(|i| *i == Instruction::IncLocal)
+            .unwrap();
+        assert_eq!(
+            instructions[inc..inc + 3],
+            [
+                Instruction::IncLocal,
+                Instruction::Add,
+                Instruction::SetLocal
+            ]
+        );
+
+        // only locals
+        let (compiled, _) = compile("var g = 0; g = g + 1;", &vm);
+        assert!(!compiled.code.contains(&Instruction::IncLocal.into()));
+    }
+
+    #[test]
+    fn stack_depth() {
+        // every statement here is checked against the compiler's locals as it's compiled
+        let mut vm = VM::new();
+        let (compiled, errors) = compile(
+            "var total = 0;
+            {
+                var a = 1;
+                {
+                    var b = [a, { c = a + 1 }];
+                    var get = fn() { return b; };
+                    if (a > 0 && b) { var c = 2; total = total + c; } else { var d = 3; total = d; }
+                    var i = 0;
+                    while (i < 3) { var step = i * 2; total = total + step || 0; i = i + 1; }
+                    total = total + get()[1].c;
+                }
+                var e = a;
+                total = total + e;
+            }
+            return total;",
+            &vm,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(vm.eval(compiled), Some(Value::Real(11.0)));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "changes the stack by 1 but it declared 0 locals")]
+    fn stack_depth_mismatch() {
+        let mut compiler = Compiler::new(&[], CompilerOptions::default());
+        // a statement that leaves a temporary behind
+        compiler.chunk.write(Instruction::SmallInt.into(), 1);
+        compiler.chunk.write(1, 1);
+        compiler.check_stack_depth(0, 0);
+    }
+
+    #[test]
+    fn constant_while() {
+        let vm = VM::new();
+        let (compiled, errors) = compile("while (null) print 1;", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(compiled.code, vec![Instruction::Return.into()]);
+
+        let (compiled, errors) = compile("while (true) print 1;", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        compiled.disassemble("constant while");
+        // the body, then straight back to it
+        assert_eq!(
+            compiled.code[..3],
+            [Instruction::SmallInt.into(), 1, Instruction::Print.into()]
+        );
+        assert_eq!(compiled.code[3], Instruction::Jump.into());
+        assert!(!compiled.code.contains(&Instruction::JumpIfFalse.into()));
+    }
+
+    #[test]
+    fn null_literals() {
+        let mut vm = VM::new();
+        let (compiled, errors) = compile(
+            "var a = null; var b; var o = { a = null, b = 1 }; null; \
+            return a == null && b == null && o.a == null && o.b == 1;",
+            &vm,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        // every null shares one constant slot
+        let nulls = compiled
+            .constants
+            .iter()
+            .filter(|c| matches!(c, Value::Null))
+            .count();
+        assert_eq!(nulls, 1);
+        assert_eq!(vm.eval(compiled), Some(Value::Bool(true)));
+    }
+
+    #[test]
+    fn constant_dedup() {
+        let vm = VM::new();
+        let (compiled, errors) = compile(
+            "var a = 1000; var b = 1000; var c = -0; var d = 0 / 0; var e = 0 / 0;",
+            &vm,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        let reals = compiled
+            .constants
+            .iter()
+            .filter_map(|v| {
+                if let Value::Real(n) = v {
+                    Some(*n)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(reals.len(), 4);
+        assert_eq!(reals[0], 1000.0);
+        assert!(reals[1] == 0.0 && reals[1].is_sign_negative());
+        assert!(reals[2].is_nan() && reals[3].is_nan());
+    }
+
+    #[test]
+    fn string_constant_dedup() {
+        let vm = VM::new();
+        let (compiled, errors) = compile(
+            "var a = 0; a = a + 1; print a; var o = { a = \"a\" }; print o.a;",
+            &vm,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        // the global, the field and the string literal all share one slot, and `o` has the other
+        assert_eq!(compiled.constants.len(), 2, "{:?}", compiled.constants);
+        assert_eq!(compiled.find_string("a"), Some(0));
+
+        // more references than a chunk has constants
+        let (_, errors) = compile("a = a + 1;\n".repeat(300), &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn unused_variables() {
+        let vm = VM::new();
+        let (_, errors) = compile("{ var unused = 1; }", &vm);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, CompileErrorType::UnusedVariable);
+        assert_eq!(errors[0].severity(), Severity::Warning);
+        assert_eq!(errors[0].line_col(), Some((1, 7, "{ var unused = 1; }")));
+
+        let (_, errors) = compile("{ var used = 1; print used; }", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let (_, errors) = compile("{\r\n  var unused = 1;\r\n}", &vm);
+        assert_eq!(errors[0].line_col(), Some((2, 7, "  var unused = 1;")));
+    }
+
+    #[test]
+    fn redeclaration() {
+        let vm = VM::new();
+        let (_, errors) = compile("{ var a = 1; var a = 2; print a; }", &vm);
+        let errors = errors
+            .into_iter()
+            .filter(|e| e.severity() == Severity::Error)
+            .collect::<Vec<_>>();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].kind, CompileErrorType::AlreadyDeclared);
+        assert_eq!(errors[0].severity(), Severity::Error);
+        assert_eq!(errors[0].line_col().map(|(_, col, _)| col), Some(18));
+    }
+
+    #[test]
+    fn shadowing() {
+        let source = "var g = 0; { var a = 1; { var a = 2; var g = 3; print a + g; } print a; }"
+            .chars()
+            .collect::<Vec<_>>();
+        let vm = VM::new();
+        let stmts = parse_stmts_unwrap(source.iter().collect::<String>());
+
+        let (_, errors) = Compiler::compile(&stmts, &source, &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let options = CompilerOptions {
+            warn_shadowing: true,
+            ..Default::default()
+        };
+        let (_, errors) = Compiler::compile_with_options(&stmts, &source, &vm, options);
+        assert_eq!(
+            errors.iter().map(|e| e.kind.clone()).collect::<Vec<_>>(),
+            vec![CompileErrorType::Shadowed, CompileErrorType::Shadowed]
+        );
+        assert!(errors.iter().all(|e| e.severity() == Severity::Warning));
+    }
+
+    #[test]
+    fn spans() {
+        let vm = VM::new();
+        let (chunk, errors) = compile("print 1 + a * 2;", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        let span_of = |instruction: Instruction| {
+            let offset = chunk
+                .code
+                .iter()
+                .position(|b| *b == instruction as u8)
+                .unwrap();
+            chunk.span_at(offset)
+        };
+        // "1 + a * 2"
+        assert_eq!(span_of(Instruction::Add), Some((6, 15)));
+        // "a * 2"
+        assert_eq!(span_of(Instruction::Mul), Some((10, 15)));
+        assert_eq!(span_of(Instruction::GetGlobal), Some((10, 11)));
+        assert_eq!(span_of(Instruction::Print), None);
+        assert_eq!(chunk.span_at(chunk.code.len()), None);
+    }
+
+    #[test]
+    fn statement_starts() {
+        let vm = VM::new();
+        let source =
+            "var a = 1;\n\nwhile (a < 3) {\n    a = a + 1;\n    print a;\n}\nprint \"done\";";
+        let (chunk, errors) = compile(source, &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        let lines = chunk
+            .statement_starts
+            .iter()
+            .map(|(_, line)| *line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [1, 3, 4, 5, 7]);
+        assert!(chunk.statement_starts.windows(2).all(|w| w[0].0 < w[1].0));
+
+        let print = chunk.offset_for_line(5).unwrap();
+        assert_eq!(chunk.code[print], Instruction::GetGlobal as u8);
+        assert_eq!(chunk.lines[print], 5);
+        // nothing starts on line 2, so it breaks at the loop on line 3
+        assert_eq!(chunk.offset_for_line(2), chunk.offset_for_line(3));
+        assert_eq!(chunk.offset_for_line(1), Some(0));
+        assert_eq!(chunk.offset_for_line(8), None);
+    }
+
+    #[test]
+    fn too_many_constants() {
+        let vm = VM::new();
+        let source = (0..256)
+            .map(|i| format!("var g{} = 1;", i))
+            .collect::<String>();
+        let (_, errors) = compile(&source, &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let source = (0..260)
+            .map(|i| format!("var g{} = 1;\n", i))
+            .collect::<String>();
+        let (_, errors) = compile(&source, &vm);
+        assert_eq!(errors.len(), 4);
+        for (error, line) in errors.iter().zip(257..) {
+            assert_eq!(error.kind, CompileErrorType::TooManyConstants);
+            assert_eq!(error.line_num, line);
+        }
+
+        // each function has its own constants
+        let body = (0..200)
+            .map(|i| format!("print g{};", i))
+            .collect::<String>();
+        let source = format!("var f = fn() {{ {} }}; var h = fn() {{ {} }};", body, body);
+        let (_, errors) = compile(&source, &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn too_many_locals() {
+        let vm = VM::new();
+        let locals = |n: usize| {
+            (0..n)
+                .map(|i| format!("var v{} = 0; print v{};\n", i, i))
+                .collect::<String>()
+        };
+        let (_, errors) = compile(format!("{{\n{}}}", locals(256)), &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let (_, errors) = compile(format!("{{\n{}}}", locals(300)), &vm);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, CompileErrorType::TooManyLocals);
+        assert_eq!(errors[0].line_num, 258);
+
+        // the function itself takes up a slot
+        let (_, errors) = compile(format!("var f = fn() {{\n{}}};", locals(256)), &vm);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, CompileErrorType::TooManyLocals);
+        assert_eq!(errors[0].line_num, 257);
+    }
+
+    #[test]
+    fn too_many_upvalues() {
+        let vm = VM::new();
+        let captures = |n: usize| {
+            let locals = (0..n)
+                .map(|i| format!("var v{} = 0;\n", i))
+                .collect::<String>();
+            let prints = (0..n)
+                .map(|i| format!("print v{};\n", i))
+                .collect::<String>();
+            format!("{{\n{}print fn() {{\n{}}};\n}}", locals, prints)
+        };
+        let (_, errors) = compile(captures(255), &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let (_, errors) = compile(captures(256), &vm);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, CompileErrorType::TooManyUpvalues);
+        // the 256th print, after the block's opening line, 256 locals and the function's first line
+        assert_eq!(errors[0].line_num, 514);
+    }
+
+    #[test]
+    fn chunk_too_large() {
+        let vm = VM::new();
+        // 3 bytes a statement, so byte 19 is in the 7th
+        let prints = (1..=10)
+            .map(|i| format!("print {};\n", i))
+            .collect::<String>();
+        let options = || CompilerOptions {
+            max_chunk_len: Some(19),
+            ..Default::default()
+        };
+        let compile_with = |source: &str| {
+            let stmts = parse_stmts_unwrap(source);
+            let chars = source.chars().collect::<Vec<_>>();
+            Compiler::compile_with_options(&stmts, &chars, &vm, options()).1
+        };
+
+        let errors = compile_with(&prints);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, CompileErrorType::ChunkTooLarge);
+        assert_eq!(errors[0].line_num, 7);
+        assert_eq!(errors[0].line, "print 7;");
+
+        // functions' chunks are checked on their own
+        let errors = compile_with(&format!("var f = fn() {{\n{}}};", prints));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, CompileErrorType::ChunkTooLarge);
+        assert_eq!(errors[0].line_num, 8);
+
+        let (chunk, errors) = compile(&prints, &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(chunk.byte_len(), 31);
+    }
+
+    #[test]
+    fn return_outside_function() {
+        let vm = VM::new();
+        let (_, errors) = compile("return 1;", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let (_, errors) = compile_with_modules("import \"m\";", &[("m", "return 1;")], &vm);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, CompileErrorType::ReturnOutsideFunction);
+
+        let (_, errors) = compile_with_modules(
+            "import \"m\";",
+            &[("m", "var f = fn(x) { return x; };")],
+            &vm,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    /// Resolves imports to modules named by their spec.
+    struct MemoryResolver(&'static [(&'static str, &'static str)]);
+    impl ModuleResolver for MemoryResolver {
+        fn resolve(&self, _from: &str, spec: &str) -> Result<(String, String), ResolveError> {
+            self.0
+                .iter()
+                .find(|(name, _)| *name == spec)
+                .map(|(name, source)| (name.to_string(), source.to_string()))
+                .ok_or(ResolveError::NotFound)
+        }
+    }
+
+    fn compile_with_modules(
+        source: &str,
+        modules: &'static [(&'static str, &'static str)],
+        vm: &VM,
+    ) -> (Chunk, Vec<CompileError>) {
+        let stmts = parse_stmts_unwrap(source);
+        let options = CompilerOptions {
+            module: Some("main".into()),
+            resolver: Some(Rc::new(MemoryResolver(modules))),
+            ..Default::default()
+        };
+        let source = source.chars().collect::<Vec<_>>();
+        Compiler::compile_with_options(&stmts, &source, vm, options)
+    }
+
+    #[test]
+    fn imports() {
+        let mut vm = VM::new();
+        let (chunk, errors) = compile_with_modules(
+            "import \"math\"; import \"math\"; var answer = double(half);",
+            &[
+                (
+                    "math",
+                    "import \"half\"; var double = fn(x) { return x * 2; };",
+                ),
+                ("half", "var half = 21;"),
+            ],
+            &vm,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(vm.interpret(chunk), InterpretResult::Ok);
+        assert!(vm.has_global("double") && vm.has_global("half"));
+
+        let (_, errors) = compile_with_modules("import \"missing\";", &[], &vm);
+        assert_eq!(errors.len(), 1);
+        assert!(
+            matches!(&errors[0].kind, CompileErrorType::ImportFailed(msg) if msg == "couldn't import missing: module not found")
+        );
+
+        let (_, errors) = compile_with_modules("import \"bad\";", &[("bad", "\n  var = 1;")], &vm);
+        assert_eq!(errors.len(), 1);
+        assert!(
+            matches!(&errors[0].kind, CompileErrorType::ImportFailed(msg) if msg.starts_with("bad:2:"))
+        );
+
+        let (_, errors) =
+            compile_with_modules("import \"warn\";", &[("warn", "{ var unused = 1; }")], &vm);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].filename(), Some("warn"));
+        assert_eq!(errors[0].line_col(), Some((1, 7, "{ var unused = 1; }")));
+    }
+
+    #[test]
+    fn import_errors() {
+        let vm = VM::new();
+        let (_, errors) = compile_with_modules(
+            "import \"a\";",
+            &[("a", "import \"b\";"), ("b", "import \"a\";")],
+            &vm,
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, CompileErrorType::ImportCycle);
+        assert_eq!(errors[0].filename(), Some("b"));
+
+        let (_, errors) = compile_with_modules("import \"main\";", &[("main", "")], &vm);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, CompileErrorType::ImportCycle);
+
+        let (_, errors) = compile_with_modules("{ import \"a\"; }", &[], &vm);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, CompileErrorType::ImportNotTopLevel);
+    }
+
+    #[test]
+    fn pure_expression_statements() {
+        let vm = VM::new();
+        let (compiled, errors) = compile("1; 2; foo();", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        let (expected, _) = compile("foo();", &vm);
+        assert!(compiled.code_eq(&expected));
+
+        // reading a local is pure, and still uses it
+        let (compiled, errors) = compile("{ var a = 1; a; (\"s\"); }", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        // just declaring `a` and popping it at the end of the block
+        assert_eq!(
+            compiled.code,
+            [
+                Instruction::SmallInt.into(),
+                1,
+                Instruction::Pop.into(),
+                Instruction::Return.into()
+            ]
+        );
+
+        // globals might not be defined, so reading one isn't
+        let (compiled, _) = compile("g;", &vm);
+        assert!(!compiled.code.is_empty());
+    }
+
+    #[test]
+    fn small_ints() {
+        let vm = VM::new();
+        let (compiled, errors) = compile("print 5; print -128; print 127;", &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(compiled.constants.is_empty(), "{:?}", compiled.constants);
+        assert_eq!(
+            &compiled.code[..3],
+            &[Instruction::SmallInt.into(), 5, Instruction::Print.into()]
+        );
+        assert_eq!(compiled.code[4] as i8, -128);
+
+        // anything else still goes in the constant pool
+        let (compiled, _) = compile("print 128; print 1.5; print -0;", &vm);
+        assert_eq!(compiled.constants.len(), 3);
+    }
 }
@@ -3,3 +3,69 @@ pub mod parser;
 pub mod vm;
 
 pub mod util;
+
+use compiler::Compiler;
+use parser::{stmt::Stmt, tokenizer::Tokenizer};
+use util::error::ReportedError;
+use vm::{chunk::Chunk, VM};
+
+/// Everything [`analyze`] found wrong with a program, errors and warnings, from each stage in the order they ran.
+pub type Diagnostics = Vec<ReportedError>;
+
+/// Tokenizes, parses and compiles `source`, collecting the errors and warnings from every stage instead of stopping at
+/// the first stage that fails, for editors and other tools that want to know everything wrong with a program at once.
+///
+/// Each stage skips what it can't make sense of and carries on, so the chunk is compiled from the statements that
+/// could be parsed. It's `None` if it doesn't pass [`Chunk::verify_all`], and otherwise safe to run, though whatever
+/// had errors is missing from it. Its strings are allocated in `vm`, so that's where it has to run.
+pub fn analyze(source: &str, vm: &VM) -> (Option<Chunk>, Diagnostics) {
+    let mut diagnostics = Diagnostics::new();
+    let (tokens, errors) = Tokenizer::tokenize(source);
+    diagnostics.extend(errors.iter().map(ReportedError::new));
+    let chars = source.chars().collect::<Vec<_>>();
+    let (stmts, errors) = Stmt::parse(tokens, chars.clone());
+    diagnostics.extend(errors.iter().map(ReportedError::new));
+    let (chunk, errors) = Compiler::compile(&stmts, &chars, vm);
+    diagnostics.extend(errors.iter().map(ReportedError::new));
+    (chunk.verify_all().ok().map(|_| chunk), diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        analyze,
+        util::error::Severity,
+        vm::{value::Value, VM},
+    };
+
+    #[test]
+    fn analyze_partial_programs() {
+        let mut vm = VM::new();
+        let (chunk, diagnostics) = analyze("var a = 1;\nvar b = a +;\nreturn a + 2;", &vm);
+        let found = diagnostics
+            .iter()
+            .map(|d| (d.severity, d.code, d.line))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [(Severity::Error, 2001, Some(2))]);
+        assert_eq!(vm.eval(chunk.unwrap()), Some(Value::Real(3.0)));
+
+        // tokenizer, parser and compiler diagnostics together
+        let (chunk, diagnostics) = analyze(
+            "var a = $1;\nprint (2;\n{ var unused = 1; }\nreturn a;",
+            &vm,
+        );
+        let found = diagnostics
+            .iter()
+            .map(|d| (d.severity, d.code))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [
+                (Severity::Error, 1001),
+                (Severity::Error, 2003),
+                (Severity::Warning, 3003)
+            ]
+        );
+        assert_eq!(vm.eval(chunk.unwrap()), Some(Value::Real(1.0)));
+    }
+}
@@ -3,7 +3,7 @@ use std::{fmt::Display, rc::Rc};
 use crate::{
     parser::tokenizer::Token,
     parser::{Parser, ParserResult},
-    vm::VM,
+    vm::{value::Value, VM},
 };
 
 use super::stmt::Stmt;
@@ -24,14 +24,29 @@ pub enum ExprType {
     And(Box<Expr>, Box<Expr>),
     Greater(Box<Expr>, Box<Expr>),
     Less(Box<Expr>, Box<Expr>),
+    GreaterEqual(Box<Expr>, Box<Expr>),
+    LessEqual(Box<Expr>, Box<Expr>),
+    Equal(Box<Expr>, Box<Expr>),
+    NotEqual(Box<Expr>, Box<Expr>),
+    /// `is`, whether two values are the same object.
+    Is(Box<Expr>, Box<Expr>),
     // Unary operations
     Negate(Box<Expr>),
     Not(Box<Expr>),
     // Other
     Grouping(Box<Expr>),
     Object(Vec<(String, Box<Expr>)>),
+    List(Vec<Expr>),
     Var(Rc<String>),
     Assign(Rc<String>, Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    /// Reading a field: the object, and the field's name.
+    Get(Box<Expr>, Rc<String>),
+    /// Writing a field: the object, the field's name, and the value.
+    Set(Box<Expr>, Rc<String>, Box<Expr>),
+    /// An anonymous function: its parameters, with their tokens, and its body.
+    Function(Vec<(Token, String)>, Vec<Stmt>),
 }
 #[derive(Clone, Debug, PartialEq)]
 pub struct Expr {
@@ -48,6 +63,132 @@ impl Expr {
         let mut parser = Parser::new(tokens, source);
         parser.expression()
     }
+
+    /// The range of source characters this expression and all of its children cover, as `(start, end)` with `end`
+    /// exclusive.
+    pub fn span(&self) -> (usize, usize) {
+        let (first, end) = self.bounds();
+        (first.start, end)
+    }
+
+    /// The expression's first token in the source, and where its last one ends.
+    fn bounds(&self) -> (Token, usize) {
+        let mut first = self.token;
+        let mut end = self.token.start + self.token.length;
+        let mut include = |e: &Expr| {
+            let (t, e) = e.bounds();
+            if t.start < first.start {
+                first = t;
+            }
+            end = end.max(e);
+        };
+        match &self.kind {
+            ExprType::Real(_)
+            | ExprType::Bool(_)
+            | ExprType::Null
+            | ExprType::String(_)
+            | ExprType::Var(_)
+            | ExprType::Function(_, _) => {}
+            ExprType::Add(l, r)
+            | ExprType::Subtract(l, r)
+            | ExprType::Multiply(l, r)
+            | ExprType::Divide(l, r)
+            | ExprType::Or(l, r)
+            | ExprType::And(l, r)
+            | ExprType::Greater(l, r)
+            | ExprType::Less(l, r)
+            | ExprType::GreaterEqual(l, r)
+            | ExprType::LessEqual(l, r)
+            | ExprType::Equal(l, r)
+            | ExprType::NotEqual(l, r)
+            | ExprType::Is(l, r)
+            | ExprType::Index(l, r)
+            | ExprType::Set(l, _, r) => {
+                include(l);
+                include(r);
+            }
+            ExprType::Negate(inner)
+            | ExprType::Not(inner)
+            | ExprType::Grouping(inner)
+            | ExprType::Get(inner, _) => include(inner),
+            ExprType::Object(pairs) => {
+                for (_, value) in pairs {
+                    include(value);
+                }
+            }
+            ExprType::List(items) => {
+                for item in items {
+                    include(item);
+                }
+            }
+            ExprType::Assign(_, value) => include(value),
+            ExprType::Call(callee, args) => {
+                include(callee);
+                for arg in args {
+                    include(arg);
+                }
+            }
+        }
+        (first, end)
+    }
+
+    /// The expression's value, if it's known without running anything: number, boolean and `null` literals, and
+    /// arithmetic, comparisons, `!`, `&&` and `||` on those. Strings aren't, since making one needs a VM to allocate
+    /// it. `None` for anything else, including operations that would be type errors at runtime, and division by zero,
+    /// which can be an error depending on how the VM's set up.
+    pub fn const_value(&self) -> Option<Value> {
+        let reals = |l: &Expr, r: &Expr| match (l.const_value()?, r.const_value()?) {
+            (Value::Real(l), Value::Real(r)) => Some((l, r)),
+            _ => None,
+        };
+        Some(match &self.kind {
+            ExprType::Real(n) => Value::Real(*n),
+            ExprType::Bool(b) => Value::Bool(*b),
+            ExprType::Null => Value::Null,
+            ExprType::Grouping(inner) => inner.const_value()?,
+            ExprType::Negate(inner) => match inner.const_value()? {
+                Value::Real(n) => Value::Real(-n),
+                _ => return None,
+            },
+            ExprType::Not(inner) => Value::Bool(inner.const_value()?.falsey()),
+            ExprType::Add(l, r) => reals(l, r).map(|(l, r)| Value::Real(l + r))?,
+            ExprType::Subtract(l, r) => reals(l, r).map(|(l, r)| Value::Real(l - r))?,
+            ExprType::Multiply(l, r) => reals(l, r).map(|(l, r)| Value::Real(l * r))?,
+            ExprType::Divide(l, r) => match reals(l, r)? {
+                (_, 0.0) => return None,
+                (l, r) => Value::Real(l / r),
+            },
+            ExprType::Greater(l, r) => reals(l, r).map(|(l, r)| Value::Bool(l > r))?,
+            ExprType::Less(l, r) => reals(l, r).map(|(l, r)| Value::Bool(l < r))?,
+            ExprType::GreaterEqual(l, r) => reals(l, r).map(|(l, r)| Value::Bool(l >= r))?,
+            ExprType::LessEqual(l, r) => reals(l, r).map(|(l, r)| Value::Bool(l <= r))?,
+            ExprType::Equal(l, r) => Value::Bool(l.const_value()?.deep_eq(&r.const_value()?)),
+            ExprType::NotEqual(l, r) => Value::Bool(!l.const_value()?.deep_eq(&r.const_value()?)),
+            ExprType::Is(l, r) => Value::Bool(l.const_value()?.ref_eq(&r.const_value()?)),
+            // these give whichever operand decided the result, like at runtime
+            ExprType::And(l, r) => match l.const_value()? {
+                l if l.falsey() => l,
+                _ => r.const_value()?,
+            },
+            ExprType::Or(l, r) => match l.const_value()? {
+                l if l.falsey() => r.const_value()?,
+                l => l,
+            },
+            _ => return None,
+        })
+    }
+
+    /// A copy of this expression's token stretched to cover its whole [span](Expr::span).
+    pub fn span_token(&self) -> Token {
+        let (first, end) = self.bounds();
+        Token::new(
+            self.token.kind,
+            first.start,
+            end - first.start,
+            first.line,
+            first.col,
+        )
+    }
 }
 
 impl Display for Expr {
@@ -62,15 +203,52 @@ impl Display for Expr {
             ExprType::Divide(l, r) => write!(f, "(/ {} {})", l, r),
             ExprType::Greater(l, r) => write!(f, "(> {} {})", l, r),
             ExprType::Less(l, r) => write!(f, "(< {} {})", l, r),
+            ExprType::GreaterEqual(l, r) => write!(f, "(>= {} {})", l, r),
+            ExprType::LessEqual(l, r) => write!(f, "(<= {} {})", l, r),
+            ExprType::Equal(l, r) => write!(f, "(== {} {})", l, r),
+            ExprType::NotEqual(l, r) => write!(f, "(!= {} {})", l, r),
+            ExprType::Is(l, r) => write!(f, "(is {} {})", l, r),
             ExprType::Negate(inner) => write!(f, "(- {})", inner),
             ExprType::Not(inner) => write!(f, "(! {})", inner),
             ExprType::Grouping(inner) => write!(f, "{}", inner),
-            ExprType::Object(table) => write!(f, "{:?}", table),
+            ExprType::Object(pairs) => {
+                write!(f, "(object")?;
+                for (key, value) in pairs {
+                    write!(f, " ({} {})", key, value)?;
+                }
+                write!(f, ")")
+            }
+            ExprType::List(items) => {
+                write!(f, "(list")?;
+                for item in items {
+                    write!(f, " {}", item)?;
+                }
+                write!(f, ")")
+            }
             ExprType::Var(v) => write!(f, "(get {})", v),
-            ExprType::Assign(name, value) => write!(f, "(set {} to {:?})", name, value),
+            ExprType::Assign(name, value) => write!(f, "(set {} {})", name, value),
             ExprType::String(a) => write!(f, "({:?})", a),
             ExprType::Or(l, r) => write!(f, "(|| {} {})", l, r),
             ExprType::And(l, r) => write!(f, "(&& {} {})", l, r),
+            ExprType::Function(params, _) => {
+                write!(f, "(fn")?;
+                for (_, param) in params {
+                    write!(f, " {}", param)?;
+                }
+                write!(f, ")")
+            }
+            ExprType::Index(indexed, index) => write!(f, "(index {} {})", indexed, index),
+            ExprType::Get(object, name) => write!(f, "(field {} {})", object, name),
+            ExprType::Set(object, name, value) => {
+                write!(f, "(set-field {} {} {})", object, name, value)
+            }
+            ExprType::Call(callee, args) => {
+                write!(f, "(call {}", callee)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                write!(f, ")")
+            }
         }
     }
 }
@@ -84,9 +262,10 @@ pub trait AstVisitor<T, A> {
 #[cfg(test)]
 mod tests {
     use crate::{
-        parser::expr::Expr,
+        parser::expr::{Expr, ExprType},
         parser::tokenizer::Tokenizer,
         parser::{ParserErrorType, ParserResult},
+        vm::value::Value,
     };
     fn parse_expr<S: AsRef<str>>(source: S) -> ParserResult<Expr> {
         let tokens = Tokenizer::new(source.as_ref())
@@ -113,6 +292,48 @@ mod tests {
         assert_eq!(ast, "(- (+ 1 1) (/ 2 (* 3 2)))");
     }
 
+    #[test]
+    fn calls() {
+        assert_eq!(parse_expr_lisp("f()"), "(call (get f))");
+        assert_eq!(parse_expr_lisp("f(1, 2 + 3)"), "(call (get f) 1 (+ 2 3))");
+        assert_eq!(parse_expr_lisp("f(1)(2)"), "(call (call (get f) 1) 2)");
+        assert_eq!(
+            parse_expr("f(1, 2").unwrap_err().kind,
+            ParserErrorType::UnclosedArguments
+        );
+    }
+
+    #[test]
+    fn spans() {
+        let source = "-(1 + 2) * foo(3)";
+        let expr = parse_expr(source).unwrap();
+        assert_eq!(expr.span(), (0, source.len()));
+        if let ExprType::Multiply(l, r) = &expr.kind {
+            assert_eq!(l.span(), (0, 8));
+            assert_eq!(r.span(), (11, 17));
+        } else {
+            panic!("expected multiply, got {:?}", expr)
+        }
+    }
+
+    #[test]
+    fn const_value() {
+        let value = |source: &str| parse_expr(source).unwrap().const_value();
+        assert_eq!(value("1.5"), Some(Value::Real(1.5)));
+        assert_eq!(value("false"), Some(Value::Bool(false)));
+        assert_eq!(value("null"), Some(Value::Null));
+        assert_eq!(value("-(1 + 2) * 3"), Some(Value::Real(-9.0)));
+        assert_eq!(value("1 < 2 && !null"), Some(Value::Bool(true)));
+        assert_eq!(value("0 || 2"), Some(Value::Real(2.0)));
+        assert_eq!(value("1 == true"), Some(Value::Bool(false)));
+
+        assert_eq!(value("a"), None);
+        assert_eq!(value("1 + a"), None);
+        assert_eq!(value("true + 1"), None);
+        assert_eq!(value("\"s\""), None);
+        assert_eq!(value("1 / 0"), None);
+    }
+
     #[test]
     fn parse() {
         let source = "(";
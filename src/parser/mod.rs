@@ -1,3 +1,32 @@
+/// Parses `source` as statements and asserts that it parses without errors, to the statements' s-expression forms
+/// separated by spaces.
+///
+/// ```ignore
+/// assert_parses!("print 1 + 2 * 3;", "(print (+ 1 (* 2 3)))");
+/// ```
+#[cfg(test)]
+macro_rules! assert_parses {
+    ($source:expr, $expected:expr $(,)?) => {{
+        let source: &str = $source;
+        let tokens = $crate::parser::tokenizer::Tokenizer::new(source)
+            .collect::<Result<Vec<_>, _>>()
+            .expect("source doesn't tokenize");
+        let (stmts, errors) = $crate::parser::stmt::Stmt::parse(tokens, source.chars().collect());
+        assert!(
+            errors.is_empty(),
+            "{:?} doesn't parse: {:?}",
+            source,
+            errors
+        );
+        let actual = stmts
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(actual, $expected, "parsing {:?}", source);
+    }};
+}
+
 pub mod expr;
 pub mod stmt;
 pub mod tokenizer;
@@ -6,6 +35,7 @@ use std::{
     backtrace::Backtrace,
     error::Error,
     fmt::{Debug, Display},
+    ops::Range,
     rc::Rc,
 };
 
@@ -13,8 +43,8 @@ use once_cell::unsync::OnceCell;
 
 use crate::{
     parser::expr::{Expr, ExprType},
-    parser::tokenizer::{Token, TokenType},
-    util::error::AnkokuError,
+    parser::tokenizer::{parse_number, unescape, Token, TokenType},
+    util::{error::AnkokuError, line_map::LineMap},
 };
 
 use self::stmt::{Stmt, StmtType};
@@ -22,20 +52,24 @@ pub type ParserResult<T> = Result<T, ParserError>;
 pub struct ParserError {
     pub kind: ParserErrorType,
     pub token: Token,
-    pub internal_bt: Backtrace,
+    pub internal_bt: Box<Backtrace>,
     pub line: String,
     pub line_num: u32,
     pub col: usize,
+    /// The characters of the source the parser skipped to recover from this error, ending where it resumed parsing.
+    /// Empty (but still at the resume point) if it didn't need to skip anything. `None` until the parser recovers.
+    pub recovery: Option<Range<usize>>,
 }
 impl ParserError {
     pub fn new(kind: ParserErrorType, token: Token, line: String, line_col: (u32, usize)) -> Self {
         ParserError {
             kind,
             token,
-            internal_bt: Backtrace::capture(),
+            internal_bt: Box::new(Backtrace::capture()),
             line,
             line_num: line_col.0,
             col: line_col.1,
+            recovery: None,
         }
     }
 }
@@ -71,6 +105,18 @@ pub enum ParserErrorType {
     InvalidAssignmentTarget,
     UnclosedBlock,
     ExpectedParen { before: bool },
+    UnclosedArguments,
+    TooManyArguments,
+    UnclosedList,
+    ExpectedParameterName,
+    UnclosedParameters,
+    ExpectedFunctionBody,
+    TooManyParameters,
+    UnclosedIndex,
+    ExpectedImportPath,
+    ExpectedFieldName,
+    ExpectedPrintValue,
+    MisplacedDigitSeparator,
 }
 impl AnkokuError for ParserError {
     fn msg(&self) -> &str {
@@ -97,6 +143,22 @@ impl AnkokuError for ParserError {
             ParserErrorType::ExpectedParen { before: false } => {
                 "expected right paren after condition"
             }
+            ParserErrorType::UnclosedArguments => "unclosed argument list, expected )",
+            ParserErrorType::TooManyArguments => "can't have more than 255 arguments",
+            ParserErrorType::UnclosedList => "unclosed list, expected ]",
+            ParserErrorType::ExpectedParameterName => "expected parameter name",
+            ParserErrorType::UnclosedParameters => "unclosed parameter list, expected )",
+            ParserErrorType::ExpectedFunctionBody => "expected { before function body",
+            ParserErrorType::TooManyParameters => "can't have more than 255 parameters",
+            ParserErrorType::UnclosedIndex => "unclosed index, expected ]",
+            ParserErrorType::ExpectedImportPath => "expected a path string after \"import\"",
+            ParserErrorType::ExpectedFieldName => "expected field name after .",
+            ParserErrorType::ExpectedPrintValue => {
+                "expected a value to print, for a blank line print an empty string: print \"\";"
+            }
+            ParserErrorType::MisplacedDigitSeparator => {
+                "_ can only separate digits in a number, like: 1_000"
+            }
         }
     }
     fn code(&self) -> u32 {
@@ -112,6 +174,18 @@ impl AnkokuError for ParserError {
             ParserErrorType::InvalidAssignmentTarget => 2009,
             ParserErrorType::UnclosedBlock => 2010,
             ParserErrorType::ExpectedParen { .. } => 2011,
+            ParserErrorType::UnclosedArguments => 2012,
+            ParserErrorType::TooManyArguments => 2013,
+            ParserErrorType::UnclosedList => 2014,
+            ParserErrorType::ExpectedParameterName => 2015,
+            ParserErrorType::UnclosedParameters => 2016,
+            ParserErrorType::ExpectedFunctionBody => 2017,
+            ParserErrorType::TooManyParameters => 2018,
+            ParserErrorType::UnclosedIndex => 2019,
+            ParserErrorType::ExpectedImportPath => 2020,
+            ParserErrorType::ExpectedFieldName => 2021,
+            ParserErrorType::ExpectedPrintValue => 2022,
+            ParserErrorType::MisplacedDigitSeparator => 2023,
         }
     }
 
@@ -132,7 +206,7 @@ pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
     panic_mode: bool,
-    source_string: OnceCell<String>,
+    line_map: OnceCell<LineMap>,
 }
 
 impl Parser {
@@ -142,46 +216,33 @@ impl Parser {
             source,
             current: 0,
             panic_mode: false,
-            source_string: OnceCell::new(),
+            line_map: OnceCell::new(),
         }
     }
 
-    fn idx_to_pos(&self, idx: usize) -> (u32, usize) {
-        let mut col = 0;
-        let mut lines = 0;
-        for i in 0..idx {
-            if self.source[i] == '\n' {
-                lines += 1;
-                col = 0;
-                continue;
-            }
-            col += 1;
-        }
-        (lines + 1, col + 1)
+    fn line_map(&self) -> &LineMap {
+        self.line_map.get_or_init(|| LineMap::new(&self.source))
     }
 
     fn get_line(&self, line_num: u32) -> String {
-        assert!(line_num >= 1);
-        let mut lines = self
-            .source_string
-            .get_or_init(|| String::from_iter(&self.source))
-            .lines();
-
-        lines
-            .nth((line_num - 1) as usize)
-            .expect("failed to get line")
-            .to_string()
+        self.line_map().line(&self.source, line_num)
     }
 
     fn new_err(&self, kind: ParserErrorType, token: Token) -> ParserError {
         if token.kind == TokenType::EOF {
-            ParserError::new(kind, token, "EOF".into(), (1, 1))
+            // point just past the end of the last line that isn't blank, rather than at the start of an empty one
+            let mut end = self.source.len();
+            while end > 0 && self.source[end - 1].is_whitespace() {
+                end -= 1;
+            }
+            let pos = self.line_map().pos(end);
+            ParserError::new(kind, token, self.get_line(pos.0), pos)
         } else {
             ParserError::new(
                 kind,
                 token,
-                self.get_line(self.idx_to_pos(token.start).0),
-                self.idx_to_pos(token.start),
+                self.get_line(token.line),
+                (token.line, token.col),
             )
         }
     }
@@ -201,6 +262,7 @@ impl Parser {
             Expr::new(self.peek(), ExprType::Null)
         };
         self.expect_semi(Stmt::new(StmtType::Var(
+            global,
             self.source[global.start..=global.start + global.length - 1]
                 .iter()
                 .collect::<String>(),
@@ -223,18 +285,56 @@ impl Parser {
             self.if_statement()
         } else if self.mtch(&[TokenType::While]) {
             self.while_statement()
-        } else if self.mtch(&[TokenType::LBrace]) {
-            let mut stmts = vec![];
-            while !self.at_end() && !self.check(TokenType::RBrace) {
-                let stmt = self.declaration()?; // TODO: better error handling here, this fails at the first error but it should collect all errors somehow
-                stmts.push(stmt);
-            }
-            self.consume(TokenType::RBrace, ParserErrorType::UnclosedBlock)?;
-            Ok(Stmt::new(StmtType::Block(stmts)))
+        } else if self.mtch(&[TokenType::Return]) {
+            self.return_statement()
+        } else if self.mtch(&[TokenType::Import]) {
+            self.import_statement()
+        } else if self.check(TokenType::LBrace) {
+            self.brace_statement()
         } else {
             self.expression_statement()
         }
     }
+    /// Parses a statement starting with `{`, which is either a block or an expression statement starting with an object
+    /// literal. `{ name =` starts an object literal, anything else starts a block. Blocks can start with an assignment
+    /// too, so if `{ name =` doesn't parse as an object literal statement, it's parsed again as a block.
+    fn brace_statement(&mut self) -> ParserResult<Stmt> {
+        let start = self.current;
+        if self.peek_nth(1).kind == TokenType::Identifier
+            && self.peek_nth(2).kind == TokenType::Equal
+        {
+            if let Ok(stmt) = self.expression_statement() {
+                return Ok(stmt);
+            }
+            self.current = start;
+        }
+        self.advance();
+        Ok(Stmt::new(StmtType::Block(self.block()?)))
+    }
+    /// Parses the rest of a block, after its `{`.
+    fn block(&mut self) -> ParserResult<Vec<Stmt>> {
+        let mut stmts = vec![];
+        while !self.at_end() && !self.check(TokenType::RBrace) {
+            let stmt = self.declaration()?; // TODO: better error handling here, this fails at the first error but it should collect all errors somehow
+            stmts.push(stmt);
+        }
+        self.consume(TokenType::RBrace, ParserErrorType::UnclosedBlock)?;
+        Ok(stmts)
+    }
+    fn import_statement(&mut self) -> ParserResult<Stmt> {
+        let path = self.consume(TokenType::String, ParserErrorType::ExpectedImportPath)?;
+        let path_string = unescape(&self.source[path.start + 1..path.start + path.length - 1]);
+        self.expect_semi(Stmt::new(StmtType::Import(path, path_string)))
+    }
+    fn return_statement(&mut self) -> ParserResult<Stmt> {
+        let keyword = self.prev();
+        let value = if self.check(TokenType::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.expect_semi(Stmt::new(StmtType::Return(keyword, value)))
+    }
     fn while_statement(&mut self) -> ParserResult<Stmt> {
         self.consume(
             TokenType::LParen,
@@ -293,6 +393,11 @@ impl Parser {
     }
 
     fn print_statement(&mut self) -> ParserResult<Stmt> {
+        // `print;` is an error rather than a blank line, so a forgotten value doesn't go unnoticed
+        if self.check(TokenType::Semicolon) {
+            self.panic_mode = true;
+            return Err(self.new_err(ParserErrorType::ExpectedPrintValue, self.peek()));
+        }
         let stmt = Stmt::new(StmtType::Print(self.expression()?));
 
         self.expect_semi(stmt)
@@ -315,8 +420,17 @@ impl Parser {
             let equals = self.prev();
             let value = self.assignment()?;
 
-            if let ExprType::Var(name) = expr.kind {
-                return Ok(Expr::new(equals, ExprType::Assign(name, Box::new(value))));
+            match expr.kind {
+                ExprType::Var(name) => {
+                    return Ok(Expr::new(equals, ExprType::Assign(name, Box::new(value))))
+                }
+                ExprType::Get(object, name) => {
+                    return Ok(Expr::new(
+                        equals,
+                        ExprType::Set(object, name, Box::new(value)),
+                    ))
+                }
+                _ => {}
             }
 
             return Err(self.new_err(ParserErrorType::InvalidAssignmentTarget, self.peek()));
@@ -379,7 +493,7 @@ impl Parser {
     pub fn equality(&mut self) -> ParserResult<Expr> {
         let mut e = self.comparison()?;
 
-        while self.mtch(&[TokenType::BangEqual, TokenType::EqualEqual]) {
+        while self.mtch(&[TokenType::BangEqual, TokenType::EqualEqual, TokenType::Is]) {
             let op = self.prev();
             let right = self.comparison()?;
             e = self.binop(op, e, right);
@@ -425,7 +539,45 @@ impl Parser {
             let inner = self.unary()?;
             return Ok(self.unop(op, inner));
         }
-        self.primary()
+        self.call()
+    }
+    pub fn call(&mut self) -> ParserResult<Expr> {
+        let mut e = self.primary()?;
+        loop {
+            if self.mtch(&[TokenType::LParen]) {
+                e = self.finish_call(e)?;
+            } else if self.mtch(&[TokenType::LBracket]) {
+                let index = self.expression()?;
+                let bracket = self.consume(TokenType::RBracket, ParserErrorType::UnclosedIndex)?;
+                e = Expr::new(bracket, ExprType::Index(Box::new(e), Box::new(index)));
+            } else if self.mtch(&[TokenType::Dot]) {
+                let name =
+                    self.consume(TokenType::Identifier, ParserErrorType::ExpectedFieldName)?;
+                let field = self.source[name.start..name.start + name.length]
+                    .iter()
+                    .collect::<String>();
+                e = Expr::new(name, ExprType::Get(Box::new(e), Rc::new(field)));
+            } else {
+                break;
+            }
+        }
+        Ok(e)
+    }
+    fn finish_call(&mut self, callee: Expr) -> ParserResult<Expr> {
+        let mut args = Vec::new();
+        if !self.check(TokenType::RParen) {
+            loop {
+                if args.len() >= u8::MAX as usize {
+                    return Err(self.new_err(ParserErrorType::TooManyArguments, self.peek()));
+                }
+                args.push(self.expression()?);
+                if !self.mtch(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        let paren = self.consume(TokenType::RParen, ParserErrorType::UnclosedArguments)?;
+        Ok(Expr::new(paren, ExprType::Call(Box::new(callee), args)))
     }
     pub fn primary(&mut self) -> ParserResult<Expr> {
         if self.mtch(&[TokenType::Identifier]) {
@@ -452,19 +604,21 @@ impl Parser {
                 return Err(self.new_err(ParserErrorType::RealParseFailed, self.prev()));
             }
 
-            return Ok(Expr::new(
-                self.prev(),
-                ExprType::Real(
-                    a.parse::<f64>()
-                        .map_err(|_| self.new_err(ParserErrorType::RealParseFailed, self.prev()))?,
-                ),
-            ));
+            let Some(n) = parse_number(&a) else {
+                // a number that'd be fine without its separators has them in the wrong place
+                let kind = match parse_number(&a.replace('_', "")) {
+                    Some(_) => ParserErrorType::MisplacedDigitSeparator,
+                    None => ParserErrorType::RealParseFailed,
+                };
+                return Err(self.new_err(kind, self.prev()));
+            };
+            return Ok(Expr::new(self.prev(), ExprType::Real(n)));
         }
 
         if self.mtch(&[TokenType::String]) {
-            let a = self.source[self.prev().start + 1..=self.prev().start + self.prev().length - 2]
-                .iter()
-                .collect::<String>();
+            let a = unescape(
+                &self.source[self.prev().start + 1..=self.prev().start + self.prev().length - 2],
+            );
 
             if self.mtch(&[TokenType::Dot]) {
                 return Err(self.new_err(ParserErrorType::RealParseFailed, self.prev()));
@@ -473,11 +627,29 @@ impl Parser {
             return Ok(Expr::new(self.prev(), ExprType::String(Rc::new(a)))); // maybe intern these i don't know
         }
 
+        if self.mtch(&[TokenType::Char]) {
+            // a character is its code point, so it can be compared with numbers: 'A' == 65
+            let c = unescape(
+                &self.source[self.prev().start + 1..=self.prev().start + self.prev().length - 2],
+            );
+            let c = c.chars().next().expect("char literal wasn't checked");
+            return Ok(Expr::new(self.prev(), ExprType::Real(c as u32 as f64)));
+        }
+
         if self.mtch(&[TokenType::LParen]) {
+            let open = self.prev();
             let expr = self.expression()?;
             if self.peek().kind == TokenType::RParen {
-                self.advance();
-                return Ok(Expr::new(self.prev(), ExprType::Grouping(Box::new(expr))));
+                let close = self.advance();
+                // covers both parens, so errors about the grouping underline them too
+                let token = Token::new(
+                    TokenType::LParen,
+                    open.start,
+                    close.start + close.length - open.start,
+                    open.line,
+                    open.col,
+                );
+                return Ok(Expr::new(token, ExprType::Grouping(Box::new(expr))));
             } else {
                 return Err(self.new_err(ParserErrorType::UnclosedParentheses, self.peek()));
             }
@@ -487,6 +659,14 @@ impl Parser {
             return self.object();
         }
 
+        if self.mtch(&[TokenType::LBracket]) {
+            return self.list();
+        }
+
+        if self.mtch(&[TokenType::Fn]) {
+            return self.function();
+        }
+
         Err(self.new_err(ParserErrorType::ExpectedExpression, self.peek()))
     }
     fn consume(&mut self, expect: TokenType, error: ParserErrorType) -> ParserResult<Token> {
@@ -497,11 +677,18 @@ impl Parser {
         }
     }
 
-    fn synchronize(&mut self) {
+    /// Skips to the start of the next statement after an error in the one that started at token `start`, returning
+    /// the characters it skipped. It always moves past at least one token if the failed statement didn't, so the same
+    /// error can't repeat forever.
+    fn synchronize(&mut self, start: usize) -> Range<usize> {
         self.panic_mode = false;
+        let from = self.current;
+        if self.current == start && !self.at_end() {
+            self.advance();
+        }
         while self.peek().kind != TokenType::EOF {
-            if self.current > 0 && self.prev().kind == TokenType::Semicolon {
-                return;
+            if self.current > start && self.prev().kind == TokenType::Semicolon {
+                break;
             }
 
             match self.peek().kind {
@@ -513,17 +700,21 @@ impl Parser {
                 | TokenType::While
                 | TokenType::Print
                 | TokenType::Return => {
-                    return;
+                    break;
                 }
                 _ => {}
             }
 
             self.advance();
         }
+        self.tokens[from].start..self.peek().start
     }
     fn object(&mut self) -> ParserResult<Expr> {
         let mut pairs = Vec::new();
         let start = self.prev();
+        if self.mtch(&[TokenType::RBrace]) {
+            return Ok(Expr::new(start, ExprType::Object(pairs)));
+        }
         loop {
             self.consume(
                 TokenType::Identifier,
@@ -532,12 +723,6 @@ impl Parser {
             let key = self.source[self.prev().start..=self.prev().start + self.prev().length - 1]
                 .iter()
                 .collect::<String>();
-            println!(
-                "{}",
-                self.source[self.prev().start..=self.prev().start + self.prev().length - 1]
-                    .iter()
-                    .collect::<String>()
-            );
             self.consume(
                 TokenType::Equal,
                 ParserErrorType::ExpectEqualAfterIdentifierInObject,
@@ -557,6 +742,50 @@ impl Parser {
             }
         }
     }
+    fn function(&mut self) -> ParserResult<Expr> {
+        let keyword = self.prev();
+        self.consume(
+            TokenType::LParen,
+            ParserErrorType::ExpectedParen { before: true },
+        )?;
+        let mut params = Vec::new();
+        if !self.check(TokenType::RParen) {
+            loop {
+                if params.len() >= u8::MAX as usize {
+                    return Err(self.new_err(ParserErrorType::TooManyParameters, self.peek()));
+                }
+                let param = self.consume(
+                    TokenType::Identifier,
+                    ParserErrorType::ExpectedParameterName,
+                )?;
+                let name = self.source[param.start..param.start + param.length]
+                    .iter()
+                    .collect::<String>();
+                params.push((param, name));
+                if !self.mtch(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RParen, ParserErrorType::UnclosedParameters)?;
+        self.consume(TokenType::LBrace, ParserErrorType::ExpectedFunctionBody)?;
+        let body = self.block()?;
+        Ok(Expr::new(keyword, ExprType::Function(params, body)))
+    }
+    fn list(&mut self) -> ParserResult<Expr> {
+        let start = self.prev();
+        let mut items = Vec::new();
+        if !self.check(TokenType::RBracket) {
+            loop {
+                items.push(self.expression()?);
+                if !self.mtch(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RBracket, ParserErrorType::UnclosedList)?;
+        Ok(Expr::new(start, ExprType::List(items)))
+    }
     fn binop(&self, op: Token, left: Expr, right: Expr) -> Expr {
         match op.kind {
             TokenType::Plus => Expr::new(op, ExprType::Add(Box::new(left), Box::new(right))),
@@ -567,6 +796,19 @@ impl Parser {
             TokenType::Or => Expr::new(op, ExprType::Or(Box::new(left), Box::new(right))),
             TokenType::Greater => Expr::new(op, ExprType::Greater(Box::new(left), Box::new(right))),
             TokenType::Less => Expr::new(op, ExprType::Less(Box::new(left), Box::new(right))),
+            TokenType::GreaterEqual => {
+                Expr::new(op, ExprType::GreaterEqual(Box::new(left), Box::new(right)))
+            }
+            TokenType::LessEqual => {
+                Expr::new(op, ExprType::LessEqual(Box::new(left), Box::new(right)))
+            }
+            TokenType::EqualEqual => {
+                Expr::new(op, ExprType::Equal(Box::new(left), Box::new(right)))
+            }
+            TokenType::BangEqual => {
+                Expr::new(op, ExprType::NotEqual(Box::new(left), Box::new(right)))
+            }
+            TokenType::Is => Expr::new(op, ExprType::Is(Box::new(left), Box::new(right))),
             _ => unimplemented!(),
         }
     }
@@ -600,6 +842,10 @@ impl Parser {
     fn peek(&self) -> Token {
         self.tokens[self.current]
     }
+    /// The token `n` tokens after the next one, or the EOF token if there aren't that many.
+    fn peek_nth(&self, n: usize) -> Token {
+        self.tokens[(self.current + n).min(self.tokens.len() - 1)]
+    }
     fn prev(&self) -> Token {
         self.tokens[self.current - 1]
     }
@@ -607,3 +853,196 @@ impl Parser {
         self.peek().kind == TokenType::EOF
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{stmt::Stmt, tokenizer::Tokenizer, ParserError, ParserErrorType};
+
+    fn parse_errors(source: &str) -> Vec<ParserError> {
+        let tokens = Tokenizer::new(source)
+            .map(|v| v.unwrap())
+            .collect::<Vec<_>>();
+        Stmt::parse(tokens, source.chars().collect()).1
+    }
+
+    #[test]
+    fn precedence() {
+        assert_parses!("1 + 2 * 3;", "(+ 1 (* 2 3))");
+        assert_parses!("(1 + 2) * 3;", "(* (+ 1 2) 3)");
+        assert_parses!("-a * b;", "(* (- (get a)) (get b))");
+        assert_parses!("a < b + 1;", "(< (get a) (+ (get b) 1))");
+        assert_parses!(
+            "a == b >= c != d <= e;",
+            "(!= (== (get a) (>= (get b) (get c))) (<= (get d) (get e)))"
+        );
+        assert_parses!(
+            "!a && b || c && d;",
+            "(|| (&& (! (get a)) (get b)) (&& (get c) (get d)))"
+        );
+        assert_parses!("a = b || c;", "(set a (|| (get b) (get c)))");
+        assert_parses!("-f(1)[2];", "(- (index (call (get f) 1) 2))");
+    }
+
+    #[test]
+    fn access_chains() {
+        assert_parses!("a.b.c;", "(field (field (get a) b) c)");
+        assert_parses!("a[0][1];", "(index (index (get a) 0) 1)");
+        assert_parses!("a.b[0];", "(index (field (get a) b) 0)");
+        assert_parses!(
+            "a.b[0].c(d);",
+            "(call (field (index (field (get a) b) 0) c) (get d))"
+        );
+        assert_parses!("-a.b;", "(- (field (get a) b))");
+        assert_parses!("a.b.c = d;", "(set-field (field (get a) b) c (get d))");
+
+        let errors = parse_errors("a.1;");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParserErrorType::ExpectedFieldName);
+    }
+
+    #[test]
+    fn associativity() {
+        assert_parses!("1 - 2 - 3;", "(- (- 1 2) 3)");
+        assert_parses!("8 / 4 / 2;", "(/ (/ 8 4) 2)");
+        assert_parses!("a || b || c;", "(|| (|| (get a) (get b)) (get c))");
+        assert_parses!("a = b = c;", "(set a (set b (get c)))");
+        assert_parses!("!!a;", "(! (! (get a)))");
+        assert_parses!("a is b == c;", "(== (is (get a) (get b)) (get c))");
+    }
+
+    #[test]
+    fn statements() {
+        assert_parses!(
+            "var a = 1; { print a; } if (a) a = 2; else return;",
+            "(var a 1) (block (print (get a))) (if (get a) (set a 2) (return))"
+        );
+        assert_parses!(
+            "while (a < 3) a = a + 1;",
+            "(while (< (get a) 3) (set a (+ (get a) 1)))"
+        );
+    }
+
+    #[test]
+    fn blocks_and_objects() {
+        assert_parses!("{ a = 1 };", "(object (a 1))");
+        assert_parses!("{ a = 1, b = 2 }.b;", "(field (object (a 1) (b 2)) b)");
+        assert_parses!("{ print 1; }", "(block (print 1))");
+        assert_parses!("{ a; }", "(block (get a))");
+        assert_parses!("{ a = 1; }", "(block (set a 1))");
+        assert_parses!(
+            "while (a) { a = a - 1; }",
+            "(while (get a) (block (set a (- (get a) 1))))"
+        );
+        assert_parses!("{}", "(block)");
+    }
+
+    #[test]
+    fn radix_literals() {
+        assert_parses!("0xFF + 0xff;", "(+ 255 255)");
+        assert_parses!("0b1010;", "10");
+        assert_parses!("0o17;", "15");
+        assert_parses!("0xAbC;", "2748");
+
+        for source in ["0x;", "0b2;", "0o;", "0xG;"] {
+            let errors = parse_errors(source);
+            assert_eq!(errors.len(), 1, "{}", source);
+            assert_eq!(
+                errors[0].kind,
+                ParserErrorType::RealParseFailed,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn exponents() {
+        assert_parses!("6.022e23;", "602200000000000000000000");
+        assert_parses!("1E-9;", "0.000000001");
+        assert_parses!("2E+4 - 1e1;", "(- 20000 10)");
+
+        for source in ["3e+;", "1e;", "1e-;"] {
+            let errors = parse_errors(source);
+            assert_eq!(errors.len(), 1, "{}", source);
+            assert_eq!(
+                errors[0].kind,
+                ParserErrorType::RealParseFailed,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn digit_separators() {
+        assert_parses!("1_000_000;", "1000000");
+        assert_parses!("3.141_592;", "3.141592");
+        assert_parses!("0b1111_0000;", "240");
+        // a leading `_` makes it a name instead
+        assert_parses!("_1;", "(get _1)");
+
+        for source in ["1__0;", "1_.0;", "1_;", "1._5;", "1.5_;", "1e_3;"] {
+            let errors = parse_errors(source);
+            assert_eq!(errors.len(), 1, "{}", source);
+            let expected = match source {
+                // the `.` isn't followed by a digit, so it isn't part of the number
+                "1._5;" => ParserErrorType::RealParseFailed,
+                _ => ParserErrorType::MisplacedDigitSeparator,
+            };
+            assert_eq!(errors[0].kind, expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn char_literals() {
+        assert_parses!("'A' == 65;", "(== 65 65)");
+        assert_parses!("'\\n';", "10");
+        assert_parses!("'\\'';", "39");
+        assert_parses!("'\\u{1F600}';", "128512");
+        assert_parses!("'\"';", "34");
+    }
+
+    #[test]
+    fn print_without_value() {
+        let errors = parse_errors("print;\nprint 1;");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParserErrorType::ExpectedPrintValue);
+        assert_eq!((errors[0].line_num, errors[0].col), (1, 6));
+        assert_parses!("print \"\";", "(print (\"\"))");
+    }
+
+    #[test]
+    fn eof_errors_point_at_end() {
+        let errors = parse_errors("var a = 1;\n{\n  print a;\n\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].line_num, errors[0].col), (3, 11));
+        assert_eq!(errors[0].line, "  print a;");
+
+        let errors = parse_errors("");
+        assert!(errors.is_empty());
+        let errors = parse_errors("print");
+        assert_eq!((errors[0].line_num, errors[0].col), (1, 6));
+    }
+
+    #[test]
+    fn recovery_spans() {
+        let source = "print 1 + ;\nprint 2;\nvar = 3 4;\nprint 4;";
+        let tokens = Tokenizer::new(source)
+            .map(|v| v.unwrap())
+            .collect::<Vec<_>>();
+        let (stmts, errors) = Stmt::parse(tokens, source.chars().collect());
+        let stmts = stmts.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(stmts, ["(print 2)", "(print 4)"]);
+        let skipped = errors
+            .iter()
+            .map(|e| &source[e.recovery.clone().unwrap()])
+            .collect::<Vec<_>>();
+        assert_eq!(skipped, [";\n", "= 3 4;\n"]);
+
+        // errors on a statement's first token still skip it, rather than failing on it forever
+        let errors = parse_errors("print 1;; for");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].recovery, Some(8..10));
+        assert_eq!(errors[1].recovery, Some(10..13));
+    }
+}
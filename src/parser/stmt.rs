@@ -1,3 +1,5 @@
+use std::fmt::Display;
+
 use super::{expr::Expr, tokenizer::Token, Parser, ParserError};
 
 #[derive(Clone, Debug, PartialEq)]
@@ -15,13 +17,14 @@ impl Stmt {
         let mut stmts = vec![];
         let mut errors = vec![];
         while !parser.at_end() {
-            let stmt = parser.declaration();
-            if let Ok(stmt) = stmt {
-                stmts.push(stmt);
-            } else if let Err(e) = stmt {
-                errors.push(e);
-                parser.synchronize();
-            };
+            let start = parser.current;
+            match parser.declaration() {
+                Ok(stmt) => stmts.push(stmt),
+                Err(mut e) => {
+                    e.recovery = Some(parser.synchronize(start));
+                    errors.push(e);
+                }
+            }
         }
         (stmts, errors)
     }
@@ -30,8 +33,37 @@ impl Stmt {
 pub enum StmtType {
     Print(Expr),
     Expr(Expr),
-    Var(String, Expr),
+    /// The token is the variable's name, used to point diagnostics at the declaration.
+    Var(Token, String, Expr),
     Block(Vec<Stmt>),
     If(Expr, Box<Stmt>, Option<Box<Stmt>>),
     While(Expr, Box<Stmt>),
+    /// The token is the `return` keyword.
+    Return(Token, Option<Expr>),
+    /// `import "path";`. The token is the path string.
+    Import(Token, String),
+}
+
+/// The same s-expression format as [`Expr`]'s `Display`, for tests and debugging.
+impl Display for Stmt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            StmtType::Print(e) => write!(f, "(print {})", e),
+            StmtType::Expr(e) => write!(f, "{}", e),
+            StmtType::Var(_, name, e) => write!(f, "(var {} {})", name, e),
+            StmtType::Block(stmts) => {
+                write!(f, "(block")?;
+                for stmt in stmts {
+                    write!(f, " {}", stmt)?;
+                }
+                write!(f, ")")
+            }
+            StmtType::If(cond, then, Some(els)) => write!(f, "(if {} {} {})", cond, then, els),
+            StmtType::If(cond, then, None) => write!(f, "(if {} {})", cond, then),
+            StmtType::While(cond, body) => write!(f, "(while {} {})", cond, body),
+            StmtType::Return(_, Some(e)) => write!(f, "(return {})", e),
+            StmtType::Return(_, None) => write!(f, "(return)"),
+            StmtType::Import(_, path) => write!(f, "(import {:?})", path),
+        }
+    }
 }
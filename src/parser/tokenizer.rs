@@ -1,11 +1,12 @@
 use std::{
+    collections::VecDeque,
     error::Error,
-    fmt::{Debug, Display},
+    fmt::{Debug, Display, Write},
 };
 
 use once_cell::unsync::OnceCell;
 
-use crate::util::error::AnkokuError;
+use crate::util::{error::AnkokuError, line_map::LineMap};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TokenType {
@@ -13,6 +14,8 @@ pub enum TokenType {
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
     Comma,
     Dot,
     Minus,
@@ -52,6 +55,10 @@ pub enum TokenType {
     EOF,
     PlusEqual,
     MinusEqual,
+    Import,
+    Is,
+    /// A single character between `'` quotes, like `'a'` or `'\n'`.
+    Char,
 }
 pub type TokenizerResult<T> = Result<T, TokenizerError>;
 #[derive(Clone)]
@@ -95,12 +102,30 @@ impl Display for TokenizerError {
 pub enum TokenizerErrorType {
     UnexpectedCharacter,
     UnterminatedString,
+    /// A backslash in a string that isn't followed by a known escape, or a malformed `\u{...}`.
+    InvalidEscape,
+    /// A `\u{...}` escape that isn't a Unicode scalar value, i.e. is a surrogate or above U+10FFFF.
+    InvalidUnicodeScalar,
+    /// A `'` that isn't followed by exactly one character (or escape) and a closing `'`.
+    UnterminatedChar,
+    /// A `/*` without a matching `*/`.
+    UnterminatedComment,
 }
 impl AnkokuError for TokenizerError {
     fn msg(&self) -> &str {
         match self.kind {
             TokenizerErrorType::UnexpectedCharacter => "unexpected character",
             TokenizerErrorType::UnterminatedString => "unterminated string (missing closing \")",
+            TokenizerErrorType::InvalidEscape => "invalid escape sequence",
+            TokenizerErrorType::InvalidUnicodeScalar => {
+                "unicode escape must be a scalar value (not a surrogate, and at most 10FFFF)"
+            }
+            TokenizerErrorType::UnterminatedChar => {
+                "character literal must be one character between ' quotes, like 'a'"
+            }
+            TokenizerErrorType::UnterminatedComment => {
+                "unterminated block comment (missing closing */)"
+            }
         }
     }
 
@@ -109,13 +134,17 @@ impl AnkokuError for TokenizerError {
     }
 
     fn filename(&self) -> Option<&str> {
-        todo!()
+        None
     }
 
     fn code(&self) -> u32 {
         match self.kind {
             TokenizerErrorType::UnexpectedCharacter => 1001,
             TokenizerErrorType::UnterminatedString => 1002,
+            TokenizerErrorType::InvalidEscape => 1003,
+            TokenizerErrorType::InvalidUnicodeScalar => 1004,
+            TokenizerErrorType::UnterminatedChar => 1005,
+            TokenizerErrorType::UnterminatedComment => 1006,
         }
     }
 
@@ -128,16 +157,20 @@ pub struct Token {
     pub kind: TokenType,
     pub start: usize, // start character
     pub length: usize,
+    /// The line the token starts on, from 1.
     pub line: u32,
+    /// The column the token starts at in characters, from 1.
+    pub col: usize,
 }
 
 impl Token {
-    pub fn new(kind: TokenType, start: usize, length: usize, line: u32) -> Self {
+    pub fn new(kind: TokenType, start: usize, length: usize, line: u32, col: usize) -> Self {
         Self {
             kind,
             start,
             length,
             line,
+            col,
         }
     }
 }
@@ -147,8 +180,12 @@ pub struct Tokenizer {
     start: usize,
     current: usize,
     line: u32,
+    /// Where the line `current` is on starts.
+    line_start: usize,
+    /// The line and column of `start`.
+    start_pos: (u32, usize),
     done: bool,
-    source_string: OnceCell<String>,
+    line_map: OnceCell<LineMap>,
 }
 impl Tokenizer {
     pub fn new(source: &str) -> Self {
@@ -157,21 +194,66 @@ impl Tokenizer {
             current: 0,
             line: 1,
             start: 0,
+            line_start: 0,
+            start_pos: (1, 1),
             done: false,
-            source_string: OnceCell::new(),
+            line_map: OnceCell::new(),
+        }
+    }
+    /// Tokenizes all of `source`, carrying on after errors instead of stopping at the first one like iterating does.
+    /// The characters an error was about are left out of the tokens, which always end with an EOF token.
+    pub fn tokenize(source: &str) -> (Vec<Token>, Vec<TokenizerError>) {
+        let mut tokenizer = Tokenizer::new(source);
+        let (mut tokens, mut errors) = (vec![], vec![]);
+        loop {
+            match tokenizer.next_token() {
+                Ok(token) => {
+                    tokens.push(token);
+                    if token.kind == TokenType::EOF {
+                        return (tokens, errors);
+                    }
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+    /// Tokenizes `source` into one line per token, `KIND "lexeme" @line:col`, for debugging the tokenizer. Errors are
+    /// rendered in place as `error "message" @line:col`, and tokenizing carries on after them.
+    pub fn dump(source: &str) -> String {
+        let mut tokenizer = Tokenizer::new(source);
+        let mut out = String::new();
+        loop {
+            match tokenizer.next_token() {
+                Ok(token) => {
+                    let lexeme = tokenizer.source[token.start..token.start + token.length]
+                        .iter()
+                        .collect::<String>();
+                    writeln!(
+                        out,
+                        "{:?} {:?} @{}:{}",
+                        token.kind, lexeme, token.line, token.col
+                    )
+                    .unwrap();
+                    if token.kind == TokenType::EOF {
+                        return out;
+                    }
+                }
+                Err(e) => writeln!(out, "error {:?} @{}:{}", e.msg(), e.line_num, e.col).unwrap(),
+            }
         }
     }
     pub fn at_end(&self) -> bool {
         self.current >= self.source.len()
     }
     pub fn next_token(&mut self) -> TokenizerResult<Token> {
-        self.skip_whitespace();
+        self.skip_whitespace()?;
         self.start = self.current;
+        self.start_pos = (self.line, self.current - self.line_start + 1);
         if self.at_end() {
             return Ok(self.new_token(TokenType::EOF));
         }
         let c = self.advance();
-        if c.is_alphabetic() {
+        if c.is_alphabetic() || c == '_' {
             let kind = self.identifier();
             return Ok(self.new_token(kind));
         }
@@ -181,6 +263,8 @@ impl Tokenizer {
             ')' => return Ok(self.new_token(TokenType::RParen)),
             '{' => return Ok(self.new_token(TokenType::LBrace)),
             '}' => return Ok(self.new_token(TokenType::RBrace)),
+            '[' => return Ok(self.new_token(TokenType::LBracket)),
+            ']' => return Ok(self.new_token(TokenType::RBracket)),
             ';' => return Ok(self.new_token(TokenType::Semicolon)),
             ',' => return Ok(self.new_token(TokenType::Comma)),
             '.' => return Ok(self.new_token(TokenType::Dot)),
@@ -249,6 +333,9 @@ impl Tokenizer {
             '"' => {
                 return self.string();
             }
+            '\'' => {
+                return self.char();
+            }
 
             '0'..='9' => {
                 return Ok(self.number());
@@ -259,72 +346,150 @@ impl Tokenizer {
         Err(self.new_err(TokenizerErrorType::UnexpectedCharacter))
     }
 
-    fn get_line(&self, line_num: u32) -> String {
-        assert!(line_num >= 1);
-        let mut lines = self
-            .source_string
-            .get_or_init(|| String::from_iter(&self.source))
-            .lines();
-
-        lines
-            .nth((line_num - 1) as usize)
-            .expect("failed to get line")
-            .to_string()
+    /// Only built once something goes wrong, since sources that tokenize cleanly never need it.
+    fn line_map(&self) -> &LineMap {
+        self.line_map.get_or_init(|| LineMap::new(&self.source))
     }
-    fn idx_to_pos(&self, idx: usize) -> (u32, usize) {
-        let mut col = 0;
-        let mut lines = 0;
-        for i in 0..idx {
-            if self.source[i] == '\n' {
-                lines += 1;
-                col = 0;
-                continue;
-            }
-            col += 1;
-        }
-        (lines + 1, col + 1)
+
+    fn get_line(&self, line_num: u32) -> String {
+        self.line_map().line(&self.source, line_num)
     }
 
     fn new_err(&self, kind: TokenizerErrorType) -> TokenizerError {
-        TokenizerError::new(
-            kind,
-            self.get_line(self.idx_to_pos(self.start).0),
-            self.idx_to_pos(self.start),
-            self.current - self.start,
-        )
+        self.new_err_at(kind, self.start)
+    }
+
+    /// An error spanning from `start` to the current character.
+    fn new_err_at(&self, kind: TokenizerErrorType, start: usize) -> TokenizerError {
+        let pos = self.line_map().pos(start);
+        TokenizerError::new(kind, self.get_line(pos.0), pos, self.current - start)
     }
 
     fn number(&mut self) -> Token {
-        while self.peek().map_or(false, |v| v.is_ascii_digit()) {
+        // `_` separates digits, and where it's allowed is checked when the literal's parsed
+        let digit = |v: char| v.is_ascii_digit() || v == '_';
+        if self.source[self.start] == '0' && matches!(self.peek(), Some('x' | 'b' | 'o')) {
+            // the digits are checked when the literal's parsed, so `0b2` is one bad number rather than `0b` and `2`
+            self.advance();
+            while matches!(self.peek(), Some(v) if v.is_ascii_alphanumeric() || v == '_') {
+                self.advance();
+            }
+            return self.new_token(TokenType::Number);
+        }
+        while matches!(self.peek(), Some(v) if digit(v)) {
             self.advance();
         }
-        if self.peek() == Some('.') && self.peek_next().map_or(false, |v| v.is_ascii_digit()) {
+        if self.peek() == Some('.') && matches!(self.peek_next(), Some(v) if v.is_ascii_digit()) {
             self.advance();
 
-            while self.peek().map_or(false, |v| v.is_ascii_digit()) {
+            while matches!(self.peek(), Some(v) if digit(v)) {
+                self.advance();
+            }
+        }
+        if matches!(self.peek(), Some('e' | 'E')) {
+            // the exponent is taken even without digits, so `1e` is a bad number rather than `1` followed by `e`
+            self.advance();
+            if matches!(self.peek(), Some('+' | '-')) {
+                self.advance();
+            }
+            while matches!(self.peek(), Some(v) if digit(v)) {
                 self.advance();
             }
         }
         self.new_token(TokenType::Number)
     }
     fn string(&mut self) -> TokenizerResult<Token> {
+        // a bad escape is reported once the whole string's been consumed, so tokenizing can carry on after it
+        let mut escape_error = None;
         while self.peek() != Some('"') && !self.at_end() {
-            if self.peek() == Some('\n') {
-                self.line += 1;
+            match self.advance() {
+                '\n' => self.newline(),
+                '\\' => {
+                    let escape_start = self.current - 1;
+                    if let Err(kind) = self.escape() {
+                        escape_error.get_or_insert_with(|| self.new_err_at(kind, escape_start));
+                    }
+                }
+                _ => {}
             }
-            self.advance();
         }
         if self.at_end() {
             return Err(self.new_err(TokenizerErrorType::UnterminatedString));
         }
         self.advance();
-        Ok(self.new_token(TokenType::String))
+        match escape_error {
+            Some(e) => Err(e),
+            None => Ok(self.new_token(TokenType::String)),
+        }
+    }
+
+    fn char(&mut self) -> TokenizerResult<Token> {
+        let mut escape_error = None;
+        match self.peek() {
+            Some('\\') => {
+                self.advance();
+                let escape_start = self.current - 1;
+                if let Err(kind) = self.escape() {
+                    escape_error = Some(self.new_err_at(kind, escape_start));
+                }
+            }
+            Some(c) if c != '\'' && c != '\n' => {
+                self.advance();
+            }
+            _ => {}
+        }
+        if self.current - self.start == 1 || !self.mtch('\'') {
+            // skip to the closing quote if it's on this line, so `'ab'` is one error rather than a cascade of them
+            let rest = &self.source[self.current..];
+            if let Some(close) = rest
+                .iter()
+                .take_while(|c| **c != '\n')
+                .position(|c| *c == '\'')
+            {
+                self.current += close + 1;
+            }
+            // a bad escape leaves the rest of it unconsumed, so that's the error rather than the missing quote
+            return Err(
+                escape_error.unwrap_or_else(|| self.new_err(TokenizerErrorType::UnterminatedChar))
+            );
+        }
+        match escape_error {
+            Some(e) => Err(e),
+            None => Ok(self.new_token(TokenType::Char)),
+        }
+    }
+
+    /// Consumes the escape sequence after a backslash in a string, checking it's valid. [unescape] is what turns it
+    /// into a character.
+    fn escape(&mut self) -> Result<(), TokenizerErrorType> {
+        match self.peek() {
+            Some('n' | 't' | 'r' | '0' | '\\' | '"' | '\'') => {
+                self.advance();
+                Ok(())
+            }
+            Some('u') => {
+                self.advance();
+                if !self.mtch('{') {
+                    return Err(TokenizerErrorType::InvalidEscape);
+                }
+                let mut digits = String::new();
+                while matches!(self.peek(), Some(c) if c.is_ascii_hexdigit()) {
+                    digits.push(self.advance());
+                }
+                if !self.mtch('}') || digits.is_empty() || digits.len() > 6 {
+                    return Err(TokenizerErrorType::InvalidEscape);
+                }
+                let value = u32::from_str_radix(&digits, 16).unwrap();
+                match char::from_u32(value) {
+                    Some(_) => Ok(()),
+                    None => Err(TokenizerErrorType::InvalidUnicodeScalar),
+                }
+            }
+            _ => Err(TokenizerErrorType::InvalidEscape),
+        }
     }
     fn identifier(&mut self) -> TokenType {
-        while self
-            .peek()
-            .map_or(false, |v| v.is_alphanumeric() || v == '_')
-        {
+        while matches!(self.peek(), Some(v) if v.is_alphanumeric() || v == '_') {
             self.advance();
         }
 
@@ -344,42 +509,74 @@ impl Tokenizer {
             "false" => TokenType::False,
             "for" => TokenType::For,
             "fn" => TokenType::Fn,
+            "import" => TokenType::Import,
+            "is" => TokenType::Is,
             "this" => TokenType::This,
             "true" => TokenType::True,
             _ => TokenType::Identifier,
         }
     }
 
-    fn skip_whitespace(&mut self) {
-        while self.peek().map_or(false, |v| v.is_whitespace() || v == '/') {
-            if self.peek().unwrap() == '\n' {
-                self.line += 1;
+    fn skip_whitespace(&mut self) -> TokenizerResult<()> {
+        // a `#!` line at the very start is a shebang, so scripts can be run directly
+        if self.current == 0 && self.source.starts_with(&['#', '!']) {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.advance();
             }
-            // line comments (// comment)
-            if self.peek().unwrap() == '/' && self.peek_next() == Some('/') {
-                while !self.peek().map_or(true, |v| v == '\n') {
+        }
+        loop {
+            match self.peek() {
+                Some('\n') => {
                     self.advance();
+                    self.newline();
                 }
-            }
-            // block comments (/* comment */)
-            else if self.peek().unwrap() == '/' && self.peek_next() == Some('*') {
-                self.advance();
-                self.advance();
-                while !self.peek().map_or(true, |v| v == '*')
-                    && !self.peek_next().map_or(true, |v| v == '/')
-                {
+                Some(v) if v.is_whitespace() => {
                     self.advance();
                 }
-                self.advance();
-            }
-            // regular old slash
-            else if self.peek().unwrap() == '/' {
-                return;
+                // line comments (// comment)
+                Some('/') if self.peek_next() == Some('/') => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.advance();
+                    }
+                }
+                Some('/') if self.peek_next() == Some('*') => self.block_comment()?,
+                _ => return Ok(()),
             }
-            if !self.at_end() {
-                self.advance();
+        }
+    }
+    /// Consumes a block comment (/* comment */). They nest, so code that has comments in it can be commented out.
+    fn block_comment(&mut self) -> TokenizerResult<()> {
+        let start = self.current;
+        self.advance();
+        self.advance();
+        let mut depth = 1;
+        while depth > 0 {
+            match (self.peek(), self.peek_next()) {
+                (None, _) => {
+                    // pointing at the comment's opening `/*`, rather than everything after it
+                    let mut err = self.new_err_at(TokenizerErrorType::UnterminatedComment, start);
+                    err.length = 2;
+                    return Err(err);
+                }
+                (Some('/'), Some('*')) => {
+                    self.advance();
+                    self.advance();
+                    depth += 1;
+                }
+                (Some('*'), Some('/')) => {
+                    self.advance();
+                    self.advance();
+                    depth -= 1;
+                }
+                (Some(c), _) => {
+                    self.advance();
+                    if c == '\n' {
+                        self.newline();
+                    }
+                }
             }
         }
+        Ok(())
     }
     fn mtch(&mut self, expected: char) -> bool {
         if self.at_end() {
@@ -411,15 +608,114 @@ impl Tokenizer {
         }
     }
 
+    /// Moves on to the next line, after a newline's been consumed.
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn new_token(&self, kind: TokenType) -> Token {
         Token {
             kind,
             length: self.current - self.start,
-            line: self.line,
+            line: self.start_pos.0,
+            col: self.start_pos.1,
             start: self.start,
         }
     }
 }
+/// Parses a number written the way number literals are, digits with an optional fractional part and exponent, like
+/// `12`, `1.5` or `1.5e3`, or an integer in hexadecimal, binary or octal, like `0xFF`, `0b1010` or `0o17`. Digits can
+/// be separated by `_`, like `1_000`. A leading `-` is allowed too, since scripts parsing user input expect one to
+/// work.
+pub(crate) fn parse_number(s: &str) -> Option<f64> {
+    let unsigned = s.strip_prefix('-').unwrap_or(s);
+    let sign = if unsigned.len() < s.len() { -1.0 } else { 1.0 };
+    let radix = match unsigned.get(..2) {
+        Some("0x") => Some(16),
+        Some("0b") => Some(2),
+        Some("0o") => Some(8),
+        _ => None,
+    };
+    if let Some(radix) = radix {
+        let digits = remove_separators(&unsigned[2..], radix)?;
+        return Some(sign * parse_radix(&digits, radix)?);
+    }
+    let digits = remove_separators(unsigned, 10)?;
+    let (mantissa, exponent) = match digits.find(['e', 'E']) {
+        Some(i) => (&digits[..i], Some(&digits[i + 1..])),
+        None => (&*digits, None),
+    };
+    let (whole, fraction) = match mantissa.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (mantissa, None),
+    };
+    let all_digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    let exponent = exponent.map(|e| e.strip_prefix(['+', '-']).unwrap_or(e));
+    if !all_digits(whole)
+        || !fraction.into_iter().all(all_digits)
+        || !exponent.into_iter().all(all_digits)
+    {
+        return None;
+    }
+    Some(sign * digits.parse::<f64>().ok()?)
+}
+
+/// `digits` with the `_`s separating them taken out, or `None` if there's one that isn't between two digits in `radix`.
+fn remove_separators(digits: &str, radix: u32) -> Option<String> {
+    let chars = digits.chars().collect::<Vec<_>>();
+    let is_digit =
+        |i: Option<usize>| matches!(i.and_then(|i| chars.get(i)), Some(c) if c.is_digit(radix));
+    for (i, c) in chars.iter().enumerate() {
+        if *c == '_' && !(is_digit(i.checked_sub(1)) && is_digit(Some(i + 1))) {
+            return None;
+        }
+    }
+    Some(digits.replace('_', ""))
+}
+
+/// Parses digits in `radix` without a prefix. They're added up as a float, so big literals lose precision like big
+/// decimal ones do rather than overflowing.
+fn parse_radix(digits: &str, radix: u32) -> Option<f64> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.chars().try_fold(0.0, |n, c| {
+        Some(n * radix as f64 + c.to_digit(radix)? as f64)
+    })
+}
+
+/// The value of a string literal, given the characters between its quotes. The escapes must have already been checked
+/// by the tokenizer.
+pub(crate) fn unescape(chars: &[char]) -> String {
+    let mut out = String::with_capacity(chars.len());
+    let mut chars = chars.iter().copied();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some('u') => {
+                let digits = chars
+                    .by_ref()
+                    .skip(1)
+                    .take_while(|c| *c != '}')
+                    .collect::<String>();
+                let value = u32::from_str_radix(&digits, 16).expect("escape wasn't checked");
+                out.push(char::from_u32(value).expect("escape wasn't checked"));
+            }
+            Some(c) => out.push(c),
+            None => unreachable!("escape wasn't checked"),
+        }
+    }
+    out
+}
+
 impl Iterator for Tokenizer {
     type Item = TokenizerResult<Token>;
 
@@ -439,11 +735,56 @@ impl Iterator for Tokenizer {
         }
     }
 }
+
+/// A [`Tokenizer`] that can look any number of tokens ahead without consuming them, for parsers that need more than
+/// one token of lookahead.
+pub struct PeekableTokenizer {
+    tokenizer: Tokenizer,
+    /// Tokens that have been peeked at but not consumed, in order.
+    peeked: VecDeque<TokenizerResult<Token>>,
+}
+impl PeekableTokenizer {
+    pub fn new(source: &str) -> Self {
+        Tokenizer::new(source).into()
+    }
+
+    /// The next token, without consuming it. `None` once tokenizing has finished, like [`Iterator::next`].
+    pub fn peek(&mut self) -> Option<&TokenizerResult<Token>> {
+        self.peek_nth(0)
+    }
+
+    /// The token `n` tokens after the next one, without consuming any. `peek_nth(0)` is the same as `peek()`.
+    pub fn peek_nth(&mut self, n: usize) -> Option<&TokenizerResult<Token>> {
+        while self.peeked.len() <= n {
+            self.peeked.push_back(self.tokenizer.next()?);
+        }
+        self.peeked.get(n)
+    }
+}
+impl From<Tokenizer> for PeekableTokenizer {
+    fn from(tokenizer: Tokenizer) -> Self {
+        Self {
+            tokenizer,
+            peeked: VecDeque::new(),
+        }
+    }
+}
+impl Iterator for PeekableTokenizer {
+    type Item = TokenizerResult<Token>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.peeked.pop_front().or_else(|| self.tokenizer.next())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::parser::tokenizer::TokenType;
+    use crate::{
+        parser::tokenizer::{TokenType, TokenizerErrorType},
+        util::error::AnkokuError,
+    };
 
-    use super::Tokenizer;
+    use super::{parse_number, unescape, PeekableTokenizer, Tokenizer};
 
     fn tokenize_types<S: AsRef<str>>(s: S) -> Vec<TokenType> {
         let tokenizer = Tokenizer::new(s.as_ref());
@@ -451,6 +792,36 @@ mod tests {
         tokenizer.map(|v| v.unwrap().kind).collect::<Vec<_>>()
     }
 
+    #[test]
+    fn peeking() {
+        let mut tokens = PeekableTokenizer::new("{ a = 1 }");
+        let kind = |token: Option<&super::TokenizerResult<super::Token>>| {
+            token.map(|t| t.as_ref().unwrap().kind)
+        };
+        assert_eq!(kind(tokens.peek_nth(1)), Some(TokenType::Identifier));
+        assert_eq!(kind(tokens.peek()), Some(TokenType::LBrace));
+        assert_eq!(kind(tokens.peek_nth(2)), Some(TokenType::Equal));
+
+        assert_eq!(tokens.next().unwrap().unwrap().kind, TokenType::LBrace);
+        assert_eq!(tokens.next().unwrap().unwrap().kind, TokenType::Identifier);
+        assert_eq!(kind(tokens.peek()), Some(TokenType::Equal));
+        let rest = tokens.map(|t| t.unwrap().kind).collect::<Vec<_>>();
+        assert_eq!(
+            rest,
+            vec![
+                TokenType::Equal,
+                TokenType::Number,
+                TokenType::RBrace,
+                TokenType::EOF
+            ]
+        );
+
+        let mut tokens = PeekableTokenizer::new("");
+        assert_eq!(kind(tokens.peek_nth(1)), None);
+        assert_eq!(tokens.next().unwrap().unwrap().kind, TokenType::EOF);
+        assert!(tokens.next().is_none());
+    }
+
     #[test]
     fn empty() {
         let tokens = tokenize_types("");
@@ -482,11 +853,50 @@ mod tests {
     fn numbers() {
         let tokens = tokenize_types("100.3");
         assert_eq!(tokens, vec![TokenType::Number, TokenType::EOF]);
+
+        for source in [
+            "6.022e23",
+            "1E-9",
+            "2e+4",
+            "3e+",
+            "1e",
+            "0xFF",
+            "0xdeadBEEF",
+            "0b1010",
+            "0o17",
+            "0x",
+            "0b2",
+            "1_000_000",
+            "3.141_592",
+            "1__0",
+            "1_.0",
+            "0xFF_FF",
+        ] {
+            let tokens = Tokenizer::new(source)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(tokens[0].kind, TokenType::Number, "{}", source);
+            assert_eq!(tokens[0].length, source.len(), "{}", source);
+            assert_eq!(tokens[1].kind, TokenType::EOF, "{}", source);
+        }
+        assert_eq!(parse_number("0xff"), Some(255.0));
+        assert_eq!(parse_number("-0b11"), Some(-3.0));
+        assert_eq!(parse_number("0x+1"), None);
+        assert_eq!(parse_number("0o8"), None);
+        assert_eq!(parse_number("-1_000.5"), Some(-1000.5));
+        assert_eq!(parse_number("0b1_0"), Some(2.0));
+        assert_eq!(parse_number("0x_1"), None);
+        assert_eq!(parse_number("1e_1"), None);
     }
     #[test]
     fn identifiers() {
         let tokens = tokenize_types("hello_world");
         assert_eq!(tokens, vec![TokenType::Identifier, TokenType::EOF]);
+        let tokens = tokenize_types("_private __add__");
+        assert_eq!(
+            tokens,
+            vec![TokenType::Identifier, TokenType::Identifier, TokenType::EOF]
+        );
     }
     #[test]
     fn keywords() {
@@ -502,4 +912,203 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn columns() {
+        let (tokens, errors) = Tokenizer::tokenize("var a\n  = \"x\ny\" /* z\n */ + 1;");
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            tokens.iter().map(|t| (t.line, t.col)).collect::<Vec<_>>(),
+            vec![
+                (1, 1),
+                (1, 5),
+                (2, 3),
+                (2, 5),
+                (4, 5),
+                (4, 7),
+                (4, 8),
+                (4, 9)
+            ]
+        );
+
+        // errors inside a token are positioned from the token's start
+        let (_, errors) = Tokenizer::tokenize("x\n\"a\nb\\q\"");
+        assert_eq!((errors[0].line_num, errors[0].col), (3, 2));
+    }
+
+    #[test]
+    fn crlf_positions() {
+        let (tokens, errors) = Tokenizer::tokenize("a\r\nb@");
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].line_num, errors[0].col), (2, 2));
+        assert_eq!(errors[0].line, "b@");
+        assert_eq!((tokens[1].line, tokens[1].col), (2, 1));
+    }
+
+    #[test]
+    fn block_comments() {
+        let (tokens, errors) = Tokenizer::tokenize(
+            "/* simple */ a /* outer /* inner */ still\n comment */ b /* 2 * 3 / 4 */ c /**/ d",
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            tokens.iter().map(|t| (t.kind, t.line)).collect::<Vec<_>>(),
+            vec![
+                (TokenType::Identifier, 1),
+                (TokenType::Identifier, 2),
+                (TokenType::Identifier, 2),
+                (TokenType::Identifier, 2),
+                (TokenType::EOF, 2)
+            ]
+        );
+
+        let (tokens, errors) = Tokenizer::tokenize("a\n/* never /* */ closed\n");
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0].kind,
+            TokenizerErrorType::UnterminatedComment
+        ));
+        assert_eq!(
+            (errors[0].line_num, errors[0].col, errors[0].length),
+            (2, 1, 2)
+        );
+        assert_eq!(tokens.last().unwrap().kind, TokenType::EOF);
+    }
+
+    #[test]
+    fn shebang() {
+        let (tokens, errors) = Tokenizer::tokenize("#!/usr/bin/ankoku\nprint 1;");
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            tokens.iter().map(|t| t.kind).collect::<Vec<_>>(),
+            vec![
+                TokenType::Print,
+                TokenType::Number,
+                TokenType::Semicolon,
+                TokenType::EOF
+            ]
+        );
+        assert_eq!(tokens[0].line, 2);
+
+        let (_, errors) = Tokenizer::tokenize("print 1;\n#!/usr/bin/ankoku");
+        assert!(matches!(
+            errors[0].kind,
+            TokenizerErrorType::UnexpectedCharacter
+        ));
+        let (_, errors) = Tokenizer::tokenize(" #!/usr/bin/ankoku");
+        assert!(matches!(
+            errors[0].kind,
+            TokenizerErrorType::UnexpectedCharacter
+        ));
+    }
+
+    #[test]
+    fn tokenize_recovers() {
+        let (tokens, errors) = Tokenizer::tokenize("var a = $ 1;\nprint \"\\q\";");
+        assert_eq!(
+            tokens.iter().map(|t| t.kind).collect::<Vec<_>>(),
+            vec![
+                TokenType::Var,
+                TokenType::Identifier,
+                TokenType::Equal,
+                TokenType::Number,
+                TokenType::Semicolon,
+                TokenType::Print,
+                TokenType::Semicolon,
+                TokenType::EOF
+            ]
+        );
+        let errors = errors
+            .iter()
+            .map(|e| (e.code(), e.line_num))
+            .collect::<Vec<_>>();
+        assert_eq!(errors, [(1001, 1), (1003, 2)]);
+    }
+
+    #[test]
+    fn dump() {
+        assert_eq!(
+            Tokenizer::dump("var x = 1 + 2;"),
+            "Var \"var\" @1:1
+Identifier \"x\" @1:5
+Equal \"=\" @1:7
+Number \"1\" @1:9
+Plus \"+\" @1:11
+Number \"2\" @1:13
+Semicolon \";\" @1:14
+EOF \"\" @1:15
+"
+        );
+        assert_eq!(
+            Tokenizer::dump("a\n$ \"b"),
+            "Identifier \"a\" @1:1
+error \"unexpected character\" @2:1
+error \"unterminated string (missing closing \\\")\" @2:3
+EOF \"\" @2:5
+"
+        );
+    }
+
+    #[test]
+    fn chars() {
+        let (tokens, errors) = Tokenizer::tokenize("'a' '\\n' '\\'' '\u{1F600}'");
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|t| (t.kind, t.length))
+                .collect::<Vec<_>>(),
+            vec![
+                (TokenType::Char, 3),
+                (TokenType::Char, 4),
+                (TokenType::Char, 4),
+                (TokenType::Char, 3),
+                (TokenType::EOF, 0)
+            ]
+        );
+
+        for source in ["''", "'ab'", "'a", "'"] {
+            let (tokens, errors) = Tokenizer::tokenize(source);
+            assert_eq!(errors.len(), 1, "{:?}", source);
+            assert!(
+                matches!(errors[0].kind, TokenizerErrorType::UnterminatedChar),
+                "{:?}",
+                source
+            );
+            // the whole of `'ab'` is skipped
+            if source == "'ab'" {
+                assert_eq!(tokens.len(), 1);
+            }
+        }
+        let (_, errors) = Tokenizer::tokenize("'\\q'");
+        assert!(matches!(errors[0].kind, TokenizerErrorType::InvalidEscape));
+    }
+
+    #[test]
+    fn escapes() {
+        let source = "\"a\\n\\\"\\u{41}\\u{1F600}\"".chars().collect::<Vec<_>>();
+        assert_eq!(
+            tokenize_types(source.iter().collect::<String>()),
+            vec![TokenType::String, TokenType::EOF]
+        );
+        let value = unescape(&source[1..source.len() - 1]);
+        assert_eq!(value, "a\n\"A\u{1F600}");
+        // one char, even though it's outside the BMP
+        let emoji = unescape(&"\\u{1F600}".chars().collect::<Vec<_>>());
+        assert_eq!(emoji.chars().collect::<Vec<_>>(), vec!['\u{1F600}']);
+
+        let err = Tokenizer::new("\"x\\u{D800}\"")
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(err.kind, TokenizerErrorType::InvalidUnicodeScalar));
+        assert_eq!((err.col, err.length), (3, 8));
+        let err = Tokenizer::new("\"\\u{110000}\"")
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(err.kind, TokenizerErrorType::InvalidUnicodeScalar));
+        let err = Tokenizer::new("\"\\q\"").next().unwrap().unwrap_err();
+        assert!(matches!(err.kind, TokenizerErrorType::InvalidEscape));
+    }
 }
@@ -1,16 +1,222 @@
 use std::{
+    cell::RefCell,
     error::Error,
     fmt::{Debug, Display},
+    io::Write,
 };
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    /// Reported, but doesn't stop the program from compiling or running.
+    Warning,
+}
+
 pub trait AnkokuError: Error + Debug + Display {
     fn msg(&self) -> &str;
     fn code(&self) -> u32;
     fn line_col(&self) -> Option<(u32, usize, &str)>;
     fn length(&self) -> Option<usize>;
     fn filename(&self) -> Option<&str>;
+    fn severity(&self) -> Severity {
+        Severity::Error
+    }
 }
 
 pub trait ErrorReporter {
     fn report<E: AnkokuError>(&self, err: E);
 }
+
+/// A plain-data copy of everything an [AnkokuError] reports about itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReportedError {
+    pub severity: Severity,
+    pub code: u32,
+    pub message: String,
+    pub line: Option<u32>,
+    pub col: Option<usize>,
+    pub length: Option<usize>,
+    pub filename: Option<String>,
+}
+
+impl ReportedError {
+    pub fn new<E: AnkokuError>(err: &E) -> Self {
+        let line_col = err.line_col();
+        Self {
+            severity: err.severity(),
+            code: err.code(),
+            message: err.msg().to_string(),
+            line: line_col.map(|(line, _, _)| line),
+            col: line_col.map(|(_, col, _)| col),
+            length: err.length(),
+            filename: err.filename().map(|f| f.to_string()),
+        }
+    }
+
+    /// Renders this error as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        fn opt<T: Display>(v: &Option<T>) -> String {
+            v.as_ref().map_or("null".to_string(), |v| v.to_string())
+        }
+        format!(
+            "{{\"severity\":{},\"code\":{},\"message\":{},\"line\":{},\"col\":{},\"length\":{},\"filename\":{}}}",
+            match self.severity {
+                Severity::Error => "\"error\"",
+                Severity::Warning => "\"warning\"",
+            },
+            self.code,
+            json_string(&self.message),
+            opt(&self.line),
+            opt(&self.col),
+            opt(&self.length),
+            self.filename
+                .as_deref()
+                .map_or("null".to_string(), json_string),
+        )
+    }
+}
+
+/// Collects errors instead of printing them, for tools that want to inspect them afterwards.
+#[derive(Default)]
+pub struct BufferedErrorReporter {
+    errors: RefCell<Vec<ReportedError>>,
+}
+
+impl BufferedErrorReporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn errors(&self) -> Vec<ReportedError> {
+        self.errors.borrow().clone()
+    }
+
+    pub fn into_errors(self) -> Vec<ReportedError> {
+        self.errors.into_inner()
+    }
+}
+
+impl ErrorReporter for BufferedErrorReporter {
+    fn report<E: AnkokuError>(&self, err: E) {
+        self.errors.borrow_mut().push(ReportedError::new(&err));
+    }
+}
+
+/// Writes each error as a JSON object on its own line.
+pub struct JsonErrorReporter<W: Write> {
+    out: RefCell<W>,
+}
+
+impl<W: Write> JsonErrorReporter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out: RefCell::new(out),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out.into_inner()
+    }
+}
+
+impl<W: Write> ErrorReporter for JsonErrorReporter<W> {
+    fn report<E: AnkokuError>(&self, err: E) {
+        // there's nowhere to report a failure to report, so it's dropped like `println!` would panic
+        let _ = writeln!(
+            self.out.borrow_mut(),
+            "{}",
+            ReportedError::new(&err).to_json()
+        );
+    }
+}
+
+/// `s` as a quoted JSON string.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{stmt::Stmt, tokenizer::Tokenizer};
+
+    use super::{BufferedErrorReporter, ErrorReporter, JsonErrorReporter, ReportedError, Severity};
+
+    fn report_all<R: ErrorReporter>(reporter: &R, source: &str) {
+        let tokens = Tokenizer::new(source)
+            .map(|v| v.unwrap())
+            .collect::<Vec<_>>();
+        let (_, errors) = Stmt::parse(tokens, source.chars().collect());
+        for err in errors {
+            reporter.report(err);
+        }
+    }
+
+    #[test]
+    fn buffered() {
+        let reporter = BufferedErrorReporter::new();
+        report_all(&reporter, "print 1 + ;\nprint (2;");
+
+        assert_eq!(
+            reporter.into_errors(),
+            vec![
+                ReportedError {
+                    severity: Severity::Error,
+                    code: 2001,
+                    message: "expected expression".into(),
+                    line: Some(1),
+                    col: Some(11),
+                    length: None,
+                    filename: None,
+                },
+                ReportedError {
+                    severity: Severity::Error,
+                    code: 2003,
+                    message: "unclosed parentheses".into(),
+                    line: Some(2),
+                    col: Some(9),
+                    length: None,
+                    filename: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn json() {
+        let reporter = JsonErrorReporter::new(Vec::new());
+        report_all(&reporter, "print 1 + ;");
+
+        assert_eq!(
+            String::from_utf8(reporter.into_inner()).unwrap(),
+            "{\"severity\":\"error\",\"code\":2001,\"message\":\"expected expression\",\"line\":1,\"col\":11,\"length\":null,\"filename\":null}\n"
+        );
+
+        let escaped = ReportedError {
+            severity: Severity::Warning,
+            code: 1,
+            message: "missing \"\n".into(),
+            line: None,
+            col: None,
+            length: Some(1),
+            filename: Some("a\\b.ak".into()),
+        };
+        assert_eq!(
+            escaped.to_json(),
+            "{\"severity\":\"warning\",\"code\":1,\"message\":\"missing \\\"\\n\",\"line\":null,\"col\":null,\"length\":1,\"filename\":\"a\\\\b.ak\"}"
+        );
+    }
+}
@@ -25,11 +25,9 @@ use core::ops::BitXor;
 use std::collections::{HashMap, HashSet};
 
 /// Type alias for a hashmap using the `fx` hash algorithm.
-
 pub type FxHashMap<K, V> = HashMap<K, V, BuildHasherDefault<FxHasher>>;
 
 /// Type alias for a hashmap using the `fx` hash algorithm.
-
 pub type FxHashSet<V> = HashSet<V, BuildHasherDefault<FxHasher>>;
 
 /// A speedy hash algorithm for use within rustc. The hashmap in liballoc
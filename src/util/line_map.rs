@@ -0,0 +1,83 @@
+//! Where each line of a source starts, so positions and lines can be found without rescanning it.
+
+/// The char index of the start of every line in a source, built once and then searched.
+#[derive(Clone, Debug)]
+pub struct LineMap {
+    starts: Vec<usize>,
+}
+
+impl LineMap {
+    pub fn new(source: &[char]) -> Self {
+        let starts = std::iter::once(0)
+            .chain(
+                source
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| **c == '\n')
+                    .map(|(i, _)| i + 1),
+            )
+            .collect();
+        Self { starts }
+    }
+
+    /// The 1-based line and column of the character at `idx`. An index past the end is on the last line.
+    pub fn pos(&self, idx: usize) -> (u32, usize) {
+        let line = self.starts.partition_point(|start| *start <= idx) - 1;
+        (line as u32 + 1, idx - self.starts[line] + 1)
+    }
+
+    /// The text of line `line_num` (1-based) of `source`, which must be the source the map was built from, without
+    /// its line ending. Lines past the end are empty.
+    pub fn line(&self, source: &[char], line_num: u32) -> String {
+        assert!(line_num >= 1);
+        let Some(&start) = self.starts.get((line_num - 1) as usize) else {
+            return String::new();
+        };
+        let end = self
+            .starts
+            .get(line_num as usize)
+            .map_or(source.len(), |next| next - 1);
+        let line = &source[start..end];
+        line.strip_suffix(&['\r']).unwrap_or(line).iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LineMap;
+
+    /// The straightforward scan the map replaces.
+    fn scan_pos(source: &[char], idx: usize) -> (u32, usize) {
+        let mut line = 1;
+        let mut line_start = 0;
+        for (i, c) in source.iter().enumerate().take(idx) {
+            if *c == '\n' {
+                line += 1;
+                line_start = i + 1;
+            }
+        }
+        (line, idx - line_start + 1)
+    }
+
+    #[test]
+    fn matches_scan() {
+        let text = "var a = 1;\r\n\nfun f() {\n  print a;\r\n}\n\n// end";
+        let source: Vec<char> = text.chars().collect();
+        let map = LineMap::new(&source);
+
+        for idx in 0..=source.len() {
+            assert_eq!(map.pos(idx), scan_pos(&source, idx), "at index {}", idx);
+        }
+        for (i, expected) in text.lines().enumerate() {
+            assert_eq!(map.line(&source, i as u32 + 1), expected);
+        }
+        assert_eq!(map.line(&source, 100), "");
+    }
+
+    #[test]
+    fn empty() {
+        let map = LineMap::new(&[]);
+        assert_eq!(map.pos(0), (1, 1));
+        assert_eq!(map.line(&[], 1), "");
+    }
+}
@@ -1,2 +1,4 @@
 pub mod error;
 pub mod fxhash;
+pub mod line_map;
+pub mod rng;
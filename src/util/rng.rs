@@ -0,0 +1,48 @@
+//! A small, fast pseudorandom number generator for scripts. Not suitable for anything security sensitive.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// xorshift64*: 64 bits of state, and the same sequence every time for the same seed.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Self { state: 0 };
+        rng.seed(seed);
+        rng
+    }
+
+    /// Seeded from the current time, for when nobody asked for a particular sequence.
+    pub fn from_time() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self::new(nanos)
+    }
+
+    /// Restarts the sequence from `seed`.
+    pub fn seed(&mut self, seed: u64) {
+        // the state can't be zero, or it would stay zero forever
+        const MIX: u64 = 0x9E37_79B9_7F4A_7C15;
+        self.state = match seed ^ MIX {
+            0 => MIX,
+            state => state,
+        };
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A float in `0..1`.
+    pub fn next_f64(&mut self) -> f64 {
+        // the top 53 bits fill a double's mantissa exactly
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,348 @@
+//! The binary format chunks are saved in, so they can be loaded without compiling them again.
+//!
+//! A file is `MAGIC`, then the format's `VERSION` byte, then the chunk. A chunk is its code, its line numbers (one for
+//! every byte of code, or none if it was [stripped](Chunk::strip_debug)), and its constants. Lengths, counts and line
+//! numbers are little-endian `u32`s. Each constant is a tag byte followed by its contents: nothing for `null`, `false`
+//! and `true`, the bits of a number as a little-endian `u64`, a string's length and UTF-8 bytes, or a function's name
+//! (a `0` byte for none, or `1` and a string), arity, upvalue count and chunk. Spans and statement starts aren't saved.
+
+use std::{error::Error, fmt::Display};
+
+use super::{
+    chunk::Chunk,
+    obj::{AnkokuString, Function, Obj, ObjType},
+    value::Value,
+    VM,
+};
+
+pub const MAGIC: &[u8; 4] = b"ankc";
+pub const VERSION: u8 = 2;
+
+/// How deeply functions can be nested in a loaded chunk, so a malicious file can't overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+const NULL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const REAL: u8 = 3;
+const STRING: u8 = 4;
+const FUNCTION: u8 = 5;
+
+impl Chunk {
+    /// Saves the chunk in the format [`Chunk::from_bytes`] loads.
+    ///
+    /// # Panics
+    /// If a constant isn't a number, boolean, `null`, string or function. The compiler never makes any others.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        self.write_bytes(&mut out);
+        out
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        write_u32(out, self.code.len());
+        out.extend_from_slice(&self.code);
+        write_u32(out, self.lines.len());
+        for line in &self.lines {
+            write_u32(out, *line);
+        }
+        write_u32(out, self.constants.len());
+        for constant in &self.constants {
+            match constant {
+                Value::Null => out.push(NULL),
+                Value::Bool(false) => out.push(FALSE),
+                Value::Bool(true) => out.push(TRUE),
+                Value::Real(n) => {
+                    out.push(REAL);
+                    out.extend_from_slice(&n.to_bits().to_le_bytes());
+                }
+                Value::Obj(o) => match &o.kind {
+                    ObjType::String(s) => {
+                        out.push(STRING);
+                        write_str(out, s.as_str());
+                    }
+                    ObjType::Function(f) => {
+                        out.push(FUNCTION);
+                        match &f.name {
+                            Some(name) => {
+                                out.push(1);
+                                write_str(out, name);
+                            }
+                            None => out.push(0),
+                        }
+                        out.push(f.arity);
+                        out.push(f.upvalue_count);
+                        f.chunk.write_bytes(out);
+                    }
+                    _ => panic!("can't save constant {:?}", constant),
+                },
+            }
+        }
+    }
+
+    /// Loads a chunk saved by [`Chunk::to_bytes`], allocating its strings and functions in `vm`. The chunk isn't
+    /// checked beyond being readable, [verify](Chunk::verify) it before running anything that could be malformed.
+    pub fn from_bytes(bytes: &[u8], vm: &VM) -> Result<Chunk, DecodeError> {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let chunk = reader.chunk(vm, 0)?;
+        if reader.offset != bytes.len() {
+            return Err(DecodeError::TrailingBytes {
+                offset: reader.offset,
+            });
+        }
+        Ok(chunk)
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    let n = u32::try_from(n).expect("too large to save");
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        let taken = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, DecodeError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    /// A length or count, which can't be more than the bytes left since everything counted takes at least a byte.
+    /// Checking that up front means a corrupt count can't make loading allocate a huge amount.
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        let len = self.u32()?;
+        if len > self.bytes.len() - self.offset {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        Ok(len)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let offset = self.offset;
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| DecodeError::InvalidUtf8 { offset })
+    }
+
+    fn chunk(&mut self, vm: &VM, depth: usize) -> Result<Chunk, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(DecodeError::TooDeep);
+        }
+        let mut chunk = Chunk::new();
+        let len = self.len()?;
+        chunk.code = self.take(len)?.to_vec();
+        let offset = self.offset;
+        let lines = self.len()?;
+        if lines != len && lines != 0 {
+            return Err(DecodeError::BadLineCount { offset });
+        }
+        chunk.lines = Vec::with_capacity(lines);
+        for _ in 0..lines {
+            chunk.lines.push(self.u32()?);
+        }
+        let count = self.len()?;
+        for _ in 0..count {
+            let offset = self.offset;
+            let constant = match self.u8()? {
+                NULL => Value::Null,
+                FALSE => Value::Bool(false),
+                TRUE => Value::Bool(true),
+                REAL => Value::Real(f64::from_bits(u64::from_le_bytes(
+                    self.take(8)?.try_into().unwrap(),
+                ))),
+                STRING => {
+                    let s = self.string()?;
+                    vm.alloc_constant(AnkokuString::new(s).into())
+                }
+                FUNCTION => {
+                    let name = match self.u8()? {
+                        0 => None,
+                        1 => Some(self.string()?),
+                        _ => return Err(DecodeError::BadConstant { offset }),
+                    };
+                    let arity = self.u8()?;
+                    let upvalue_count = self.u8()?;
+                    let mut function = Function::new(name, arity, self.chunk(vm, depth + 1)?);
+                    function.upvalue_count = upvalue_count;
+                    vm.alloc_constant(Obj::new(ObjType::Function(function)))
+                }
+                _ => return Err(DecodeError::BadConstant { offset }),
+            };
+            chunk.constants.push(constant);
+        }
+        Ok(chunk)
+    }
+}
+
+/// Why [`Chunk::from_bytes`] couldn't load a chunk. Offsets are into the bytes being loaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes don't start with [`MAGIC`], so they aren't a saved chunk at all.
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The bytes end partway through the chunk.
+    UnexpectedEnd,
+    /// There's more after the end of the chunk.
+    TrailingBytes {
+        offset: usize,
+    },
+    /// A constant's tag, or a function's name, isn't one that exists.
+    BadConstant {
+        offset: usize,
+    },
+    InvalidUtf8 {
+        offset: usize,
+    },
+    /// Functions are nested more than [`MAX_DEPTH`] deep.
+    TooDeep,
+    /// A chunk has line numbers, but not one for every byte of code.
+    BadLineCount {
+        offset: usize,
+    },
+}
+impl Error for DecodeError {}
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "not a saved chunk"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported chunk format version {}", version)
+            }
+            DecodeError::UnexpectedEnd => write!(f, "chunk ends unexpectedly"),
+            DecodeError::TrailingBytes { offset } => {
+                write!(f, "{}: unexpected bytes after the chunk", offset)
+            }
+            DecodeError::BadConstant { offset } => write!(f, "{}: bad constant", offset),
+            DecodeError::InvalidUtf8 { offset } => {
+                write!(f, "{}: string isn't valid UTF-8", offset)
+            }
+            DecodeError::TooDeep => write!(f, "functions are nested too deeply"),
+            DecodeError::BadLineCount { offset } => {
+                write!(f, "{}: line numbers don't match the code", offset)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler::Compiler,
+        parser::{stmt::Stmt, tokenizer::Tokenizer},
+        vm::{chunk::Chunk, InterpretResult, VM},
+    };
+
+    use super::DecodeError;
+
+    #[test]
+    fn round_trip() {
+        let mut vm = VM::new();
+        let source = "var f = fn(x) { var y = x; return fn() { return y * 2; }; }; \
+            if (f(1.5)() == 3) { print \"ok\"; } print null; print true;";
+        let tokens = Tokenizer::new(source).map(|t| t.unwrap()).collect();
+        let chars = source.chars().collect::<Vec<_>>();
+        let (stmts, errors) = Stmt::parse(tokens, chars.clone());
+        assert!(errors.is_empty(), "{:?}", errors);
+        let (chunk, errors) = Compiler::compile(&stmts, &chars, &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let bytes = chunk.to_bytes();
+        let loaded = Chunk::from_bytes(&bytes, &vm).unwrap();
+        assert_eq!(loaded.code, chunk.code);
+        assert_eq!(loaded.lines, chunk.lines);
+        assert_eq!(loaded.to_bytes(), bytes);
+        assert_eq!(vm.interpret(loaded), InterpretResult::Ok);
+    }
+
+    #[test]
+    fn stripped() {
+        let mut vm = VM::new();
+        let source = "var f = fn(x) { return x * 2; };\nprint f(2);\nprint f(\"a\");";
+        let tokens = Tokenizer::new(source).map(|t| t.unwrap()).collect();
+        let chars = source.chars().collect::<Vec<_>>();
+        let (stmts, errors) = Stmt::parse(tokens, chars.clone());
+        assert!(errors.is_empty(), "{:?}", errors);
+        let (mut chunk, errors) = Compiler::compile(&stmts, &chars, &vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let full = chunk.to_bytes();
+        chunk.strip_debug();
+        assert!(
+            chunk.lines.is_empty() && chunk.spans.is_empty() && chunk.statement_starts.is_empty()
+        );
+        assert_eq!(chunk.line_at(0), 0);
+        let bytes = chunk.to_bytes();
+        assert!(
+            bytes.len() < full.len(),
+            "{} >= {}",
+            bytes.len(),
+            full.len()
+        );
+
+        let loaded = Chunk::from_bytes(&bytes, &vm).unwrap();
+        assert!(loaded.verify_all().is_ok());
+        // it still runs, the error just doesn't know its lines
+        assert_eq!(vm.interpret(loaded), InterpretResult::RuntimeError);
+        let traceback = &vm.last_error().unwrap().traceback;
+        assert_eq!(traceback.len(), 2);
+        assert!(traceback.iter().all(|frame| frame.line == 0));
+    }
+
+    #[test]
+    fn malformed() {
+        let vm = VM::new();
+        let bytes = Chunk::new().to_bytes();
+        assert!(Chunk::from_bytes(&bytes, &vm).is_ok());
+
+        assert_eq!(Chunk::from_bytes(b"nope", &vm), Err(DecodeError::BadMagic));
+        assert_eq!(
+            Chunk::from_bytes(&bytes[..bytes.len() - 1], &vm),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Chunk::from_bytes(&trailing, &vm),
+            Err(DecodeError::TrailingBytes {
+                offset: bytes.len()
+            })
+        );
+        // a huge count with nothing after it
+        let mut huge = bytes[..5].to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            Chunk::from_bytes(&huge, &vm),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+}
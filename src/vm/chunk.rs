@@ -1,12 +1,24 @@
+use std::{error::Error, fmt::Display, rc::Rc};
+
 use crate::vm::instruction::Instruction;
 
-use super::value::Value;
+use super::{obj::ObjType, value::Value};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: Vec<Value>,
     pub lines: Vec<usize>,
+    /// Where the code came from in the source, as `(offset, span)` pairs sorted by offset. Each span is a
+    /// `(start, end)` range of source characters like [`Expr::span`](crate::parser::expr::Expr::span), and covers the
+    /// code from its offset up to the next pair's. Code with a `None` span doesn't come from any one expression.
+    ///
+    /// Empty unless the chunk was compiled from source. Imported code has spans in the imported module's source.
+    pub spans: Vec<(usize, Option<(usize, usize)>)>,
+    /// Where each statement's code starts, as `(offset, line)` pairs sorted by offset, so a debugger can break on a
+    /// line at a statement boundary. Statements that don't compile to any code aren't here, and a statement starting
+    /// at the same offset as the one it's nested in (the first in a block) only appears once.
+    pub statement_starts: Vec<(usize, u32)>,
 }
 
 impl Chunk {
@@ -15,6 +27,8 @@ impl Chunk {
             code: Vec::with_capacity(8),
             constants: Vec::with_capacity(8),
             lines: Vec::new(),
+            spans: Vec::new(),
+            statement_starts: Vec::new(),
         }
     }
     pub fn write(&mut self, byte: u8, line: usize) {
@@ -22,6 +36,109 @@ impl Chunk {
         self.lines.push(line);
     }
 
+    /// The length of the code in bytes.
+    pub fn byte_len(&self) -> usize {
+        self.code.len()
+    }
+
+    /// The operand byte at `offset`, or `None` past the end of the code.
+    pub fn read_u8(&self, offset: usize) -> Option<u8> {
+        self.code.get(offset).copied()
+    }
+
+    /// The big-endian 32-bit operand starting at `offset`, or `None` if it runs past the end of the code.
+    pub fn read_u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.code.get(offset..offset.checked_add(4)?)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    }
+
+    /// The constant the operand byte at `offset` refers to, or `None` if there's no such operand or constant.
+    pub fn read_constant(&self, offset: usize) -> Option<&Value> {
+        self.constants.get(self.read_u8(offset)? as usize)
+    }
+
+    /// Where the jump instruction at `offset` goes. Its operand is signed and relative to the end of the instruction,
+    /// so this can be anywhere, even outside the chunk.
+    pub fn jump_target(&self, offset: usize) -> Option<i64> {
+        let jump = self.read_u32(offset + 1)? as i32;
+        Some((offset + 5) as i64 + jump as i64)
+    }
+
+    /// Marks the code written from now on as coming from `span`, see [`Chunk::spans`].
+    pub fn set_span(&mut self, span: Option<(usize, usize)>) {
+        match self.spans.last_mut() {
+            Some((_, last)) if *last == span => {}
+            // nothing was written with the last span
+            Some((offset, last)) if *offset == self.code.len() => *last = span,
+            _ => self.spans.push((self.code.len(), span)),
+        }
+    }
+
+    /// The span code is being written with, see [`Chunk::set_span`].
+    pub fn current_span(&self) -> Option<(usize, usize)> {
+        self.spans.last().and_then(|(_, span)| *span)
+    }
+
+    /// The span of source characters the byte at `offset` was compiled from, if it's known.
+    pub fn span_at(&self, offset: usize) -> Option<(usize, usize)> {
+        if offset >= self.code.len() {
+            return None;
+        }
+        let i = self.spans.partition_point(|(start, _)| *start <= offset);
+        self.spans[..i].last().and_then(|(_, span)| *span)
+    }
+
+    /// Records that a statement's code starts at `offset`, see [`Chunk::statement_starts`]. The line is the line of
+    /// the code there, so nothing is recorded if the statement didn't write any.
+    pub(crate) fn mark_statement(&mut self, offset: usize) {
+        let Some(line) = self.lines.get(offset) else {
+            return;
+        };
+        // nested statements finish compiling first, so they're recorded before the statements around them
+        let i = self
+            .statement_starts
+            .partition_point(|(start, _)| *start < offset);
+        if self.statement_starts.get(i).map(|(start, _)| *start) != Some(offset) {
+            self.statement_starts.insert(i, (offset, *line as u32));
+        }
+    }
+
+    /// The offset to break at for `line`: where the first statement on that line starts, or if none start on it, the
+    /// first statement after it. `None` if no statement starts on or after `line`.
+    pub fn offset_for_line(&self, line: u32) -> Option<usize> {
+        self.statement_starts
+            .iter()
+            .filter(|(_, start)| *start >= line)
+            .min_by_key(|(offset, start)| (*start, *offset))
+            .map(|(offset, _)| *offset)
+    }
+
+    /// The source line the byte at `offset` was compiled from, or `0` if it isn't known, like in a chunk that's had its
+    /// [debug info stripped](Chunk::strip_debug).
+    pub fn line_at(&self, offset: usize) -> usize {
+        self.lines.get(offset).copied().unwrap_or(0)
+    }
+
+    /// Removes everything that's only there for reporting errors and debugging: the line numbers, spans and statement
+    /// starts, of this chunk and the functions in its constants. The code runs the same, but errors can't say where
+    /// they happened, and the chunk [saves](Chunk::to_bytes) smaller. Functions are shared by every chunk they're a
+    /// constant in, so they're stripped for all of them.
+    ///
+    /// A stripped chunk is for running or saving, writing more code to it would give that code lines for the wrong
+    /// bytes.
+    pub fn strip_debug(&mut self) {
+        self.lines = Vec::new();
+        self.spans = Vec::new();
+        self.statement_starts = Vec::new();
+        for constant in &mut self.constants {
+            if let Value::Obj(o) = constant {
+                if let ObjType::Function(f) = &mut o.kind {
+                    Rc::make_mut(&mut f.chunk).strip_debug();
+                }
+            }
+        }
+    }
+
     pub(crate) fn last_byte_line(&self) -> usize {
         if !self.lines.is_empty() {
             self.lines[self.lines.len() - 1]
@@ -30,10 +147,290 @@ impl Chunk {
         }
     }
 
+    /// Compares code and constants, ignoring line numbers. Unlike `==`, constants are compared by contents rather
+    /// than identity, so two separately compiled chunks can be equal, and `NaN` constants are equal to each other.
+    pub fn code_eq(&self, other: &Chunk) -> bool {
+        self.code == other.code
+            && self.constants.len() == other.constants.len()
+            && self
+                .constants
+                .iter()
+                .zip(&other.constants)
+                .all(|(a, b)| match (a, b) {
+                    (Value::Real(a), Value::Real(b)) => a.to_bits() == b.to_bits(),
+                    (Value::Obj(a), Value::Obj(b)) => a.kind == b.kind,
+                    _ => a == b,
+                })
+    }
+
     pub fn add_constant(&mut self, constant: Value) -> usize {
         self.constants.push(constant);
         self.constants.len() - 1
     }
+    /// Adds a constant, reusing an existing slot if an equal constant was already added.
+    ///
+    /// `NaN` is never equal to itself and `-0.0` is equal to `0.0` but behaves differently (`1 / -0.0`), so those
+    /// always get a slot of their own.
+    pub fn intern_constant(&mut self, constant: Value) -> usize {
+        if let Value::Real(n) = constant {
+            if n.is_nan() || (n == 0.0 && n.is_sign_negative()) {
+                return self.add_constant(constant);
+            }
+        }
+        let existing = self.constants.iter().position(|c| match (c, &constant) {
+            // don't reuse a -0.0 slot for 0.0
+            (Value::Real(a), Value::Real(b)) => a.to_bits() == b.to_bits(),
+            _ => *c == constant,
+        });
+        existing.unwrap_or_else(|| self.add_constant(constant))
+    }
+    /// The index of a string constant with the contents `s`, if there is one.
+    pub fn find_string(&self, s: &str) -> Option<usize> {
+        self.constants.iter().position(|c| match c {
+            Value::Obj(o) => matches!(&o.kind, ObjType::String(string) if string.as_str() == s),
+            _ => false,
+        })
+    }
+    /// Adds `other`'s code to the end of this chunk, so successive REPL inputs can run as one growing chunk. If this
+    /// chunk ends with a `Return` it's dropped, and execution carries on into `other`'s code instead.
+    ///
+    /// `other`'s constants are added to this chunk and its constant operands renumbered to match. Jumps are relative,
+    /// so they don't need fixing up: jumps to the dropped `Return` land on the start of `other`'s code. If the merged
+    /// chunk would have more constants than an operand can refer to, neither chunk is changed.
+    pub fn append(&mut self, other: &Chunk) -> Result<(), TooManyConstants> {
+        let constants = self.constants.len();
+        let renumbered = other
+            .constants
+            .iter()
+            .map(|c| u8::try_from(self.intern_constant(c.clone())))
+            .collect::<Result<Vec<_>, _>>();
+        let Ok(renumbered) = renumbered else {
+            self.constants.truncate(constants);
+            return Err(TooManyConstants);
+        };
+
+        let last = self.instructions().last();
+        if let Some((last, _, _)) =
+            last.filter(|(_, instruction, _)| *instruction == Instruction::Return)
+        {
+            self.code.truncate(last);
+            self.lines.truncate(last);
+            self.spans.retain(|(offset, _)| *offset < last);
+            self.statement_starts.retain(|(offset, _)| *offset < last);
+        }
+
+        let start = self.code.len();
+        for (offset, instruction, operands) in other.instructions() {
+            self.code.push(instruction as u8);
+            self.code.extend_from_slice(operands);
+            match instruction {
+                Instruction::DefineGlobal
+                | Instruction::GetGlobal
+                | Instruction::GetField
+                | Instruction::SetField
+                | Instruction::SetGlobal
+                | Instruction::Constant
+                | Instruction::Closure => {
+                    self.code[start + offset + 1] = renumbered[operands[0] as usize];
+                }
+                _ => {}
+            }
+        }
+        self.lines.extend_from_slice(&other.lines);
+        if other.spans.first().map(|(offset, _)| *offset) != Some(0) && !self.spans.is_empty() {
+            // don't let the start of `other` look like it came from this chunk's last span
+            self.spans.push((start, None));
+        }
+        self.spans.extend(
+            other
+                .spans
+                .iter()
+                .map(|(offset, span)| (start + offset, *span)),
+        );
+        self.statement_starts.extend(
+            other
+                .statement_starts
+                .iter()
+                .map(|(offset, line)| (start + offset, *line)),
+        );
+        Ok(())
+    }
+
+    /// Decodes the code into instructions: the offset of each, the instruction, and its operand bytes. This stops early
+    /// at a byte that isn't an opcode or operands that run past the end, so it only covers all of the code in chunks
+    /// that [verify](Chunk::verify).
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, Instruction, &[u8])> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            let instruction = Instruction::from_byte(*self.code.get(offset)?)?;
+            let next = self.next_instruction(offset);
+            let operands = self.code.get(offset + 1..next)?;
+            let decoded = (offset, instruction, operands);
+            offset = next;
+            Some(decoded)
+        })
+    }
+
+    /// The offset of the instruction after the one at `offset`.
+    pub(crate) fn next_instruction(&self, offset: usize) -> usize {
+        match Instruction::from(self.code[offset]) {
+            Instruction::GetLocal
+            | Instruction::SetLocal
+            | Instruction::IncLocal
+            | Instruction::DecLocal
+            | Instruction::GetUpvalue
+            | Instruction::SetUpvalue
+            | Instruction::Call
+            | Instruction::NewObjectSized
+            | Instruction::SmallInt
+            | Instruction::DefineGlobal
+            | Instruction::GetGlobal
+            | Instruction::GetField
+            | Instruction::SetField
+            | Instruction::SetGlobal
+            | Instruction::Constant => offset + 2,
+            Instruction::JumpIfFalse | Instruction::Jump => offset + 5,
+            Instruction::Closure => {
+                let upvalue_count = match self.read_constant(offset + 1) {
+                    Some(Value::Obj(o)) => match &o.kind {
+                        ObjType::Function(f) => f.upvalue_count as usize,
+                        _ => 0,
+                    },
+                    _ => 0,
+                };
+                offset + 2 + upvalue_count * 2
+            }
+            _ => offset + 1,
+        }
+    }
+    /// [Verifies](Chunk::verify) the chunk and the chunks of all the functions in it, and of the functions in those.
+    pub fn verify_all(&self) -> Result<(), VerifyError> {
+        self.verify()?;
+        for constant in &self.constants {
+            if let Value::Obj(o) = constant {
+                if let ObjType::Function(f) = &o.kind {
+                    f.chunk.verify_all()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the code is well formed: every opcode exists, every operand is in the chunk, constant operands
+    /// refer to constants of the right kind, and jumps land on the start of an instruction (or the end of the
+    /// chunk). Functions' chunks aren't checked, they can be verified separately.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let mut starts = vec![false; self.code.len() + 1];
+        let mut jumps = vec![];
+        let mut offset = 0;
+        while offset < self.code.len() {
+            starts[offset] = true;
+            let byte = self.code[offset];
+            let instruction =
+                Instruction::from_byte(byte).ok_or(VerifyError::UnknownOpcode { offset, byte })?;
+            let operand = |i: usize| {
+                self.read_u8(offset + i)
+                    .ok_or(VerifyError::Truncated { offset })
+            };
+            offset = match instruction {
+                Instruction::Return
+                | Instruction::Negate
+                | Instruction::Add
+                | Instruction::Sub
+                | Instruction::Mul
+                | Instruction::Div
+                | Instruction::Less
+                | Instruction::Greater
+                | Instruction::LessEqual
+                | Instruction::GreaterEqual
+                | Instruction::Equal
+                | Instruction::Identical
+                | Instruction::Not
+                | Instruction::Pop
+                | Instruction::Print
+                | Instruction::NewObject
+                | Instruction::ObjectSet
+                | Instruction::NewList
+                | Instruction::ListPush
+                | Instruction::Index
+                | Instruction::CloseUpvalue => offset + 1,
+                Instruction::GetLocal
+                | Instruction::SetLocal
+                | Instruction::GetUpvalue
+                | Instruction::SetUpvalue
+                | Instruction::Call
+                | Instruction::NewObjectSized
+                | Instruction::SmallInt => {
+                    operand(1)?;
+                    offset + 2
+                }
+                Instruction::IncLocal | Instruction::DecLocal => {
+                    let slot = operand(1)?;
+                    let step = if instruction == Instruction::IncLocal {
+                        Instruction::Add
+                    } else {
+                        Instruction::Sub
+                    };
+                    let fallback = [step as u8, Instruction::SetLocal as u8, slot];
+                    if self.code.get(offset + 2..offset + 5) != Some(&fallback[..]) {
+                        return Err(VerifyError::BadFallback { offset });
+                    }
+                    offset + 2
+                }
+                Instruction::JumpIfFalse | Instruction::Jump => {
+                    let target = self
+                        .jump_target(offset)
+                        .ok_or(VerifyError::Truncated { offset })?;
+                    jumps.push((offset, target));
+                    offset + 5
+                }
+                Instruction::DefineGlobal
+                | Instruction::GetGlobal
+                | Instruction::GetField
+                | Instruction::SetField
+                | Instruction::SetGlobal
+                | Instruction::Constant => {
+                    operand(1)?;
+                    let constant = self.read_constant(offset + 1);
+                    let ok = match (instruction, constant) {
+                        (Instruction::Constant, Some(_)) => true,
+                        // global and field names are strings
+                        (_, Some(Value::Obj(o))) => matches!(o.kind, ObjType::String(_)),
+                        _ => false,
+                    };
+                    if !ok {
+                        return Err(VerifyError::BadConstant { offset });
+                    }
+                    offset + 2
+                }
+                Instruction::Closure => {
+                    operand(1)?;
+                    let upvalue_count = match self.read_constant(offset + 1) {
+                        Some(Value::Obj(o)) => match &o.kind {
+                            ObjType::Function(f) => f.upvalue_count as usize,
+                            _ => return Err(VerifyError::BadConstant { offset }),
+                        },
+                        _ => return Err(VerifyError::BadConstant { offset }),
+                    };
+                    operand(1 + upvalue_count * 2)?;
+                    offset + 2 + upvalue_count * 2
+                }
+            };
+        }
+        starts[self.code.len()] = true;
+
+        for (offset, target) in jumps {
+            let lands_on_instruction = usize::try_from(target)
+                .ok()
+                .and_then(|target| starts.get(target).copied())
+                .unwrap_or(false);
+            if !lands_on_instruction {
+                return Err(VerifyError::BadJumpTarget { offset, target });
+            }
+        }
+        Ok(())
+    }
+
     pub fn disassemble(&self, name: &str) {
         println!("== {} ==", name);
         let mut offset = 0;
@@ -45,7 +442,11 @@ impl Chunk {
         assert!(offset < self.code.len());
         print!("{:04} ", offset);
 
-        let instruction: Instruction = self.code[offset].try_into().expect("Invalid instruction");
+        let instruction: Instruction = self.code[offset].into();
+        let operand = |i: usize| {
+            self.read_u8(offset + i)
+                .expect("instruction runs past the end of the chunk")
+        };
         match instruction {
             Instruction::Return
             | Instruction::Negate
@@ -55,46 +456,150 @@ impl Chunk {
             | Instruction::Div
             | Instruction::Less
             | Instruction::Greater
+            | Instruction::LessEqual
+            | Instruction::GreaterEqual
+            | Instruction::Equal
+            | Instruction::Identical
             | Instruction::Not
             | Instruction::Pop
             | Instruction::Print
             | Instruction::NewObject
-            | Instruction::ObjectSet => {
+            | Instruction::ObjectSet
+            | Instruction::NewList
+            | Instruction::ListPush
+            | Instruction::Index
+            | Instruction::CloseUpvalue => {
                 println!("{:?}", instruction);
                 offset + 1
             }
-            Instruction::GetLocal | Instruction::SetLocal => {
-                println!("{:?} {}", instruction, self.code[offset + 1],);
+            Instruction::GetLocal
+            | Instruction::SetLocal
+            | Instruction::IncLocal
+            | Instruction::DecLocal
+            | Instruction::GetUpvalue
+            | Instruction::SetUpvalue
+            | Instruction::Call
+            | Instruction::NewObjectSized => {
+                println!("{:?} {}", instruction, operand(1));
                 offset + 2
             }
-            // one 32-bit operand
+            Instruction::SmallInt => {
+                println!("{:?} {}", instruction, operand(1) as i8);
+                offset + 2
+            }
+            // one signed 32-bit operand, relative to the end of the instruction
             Instruction::JumpIfFalse | Instruction::Jump => {
+                let target = self
+                    .jump_target(offset)
+                    .expect("instruction runs past the end of the chunk");
                 println!(
-                    "{:?} {}",
+                    "{:?} {} -> {}",
                     instruction,
-                    ((self.code[offset + 1] as usize) << 24)
-                        | ((self.code[offset + 2] as usize) << 16)
-                        | ((self.code[offset + 3] as usize) << 8)
-                        | (self.code[offset + 4] as usize)
+                    target - (offset + 5) as i64,
+                    target
                 );
                 offset + 5
             }
             Instruction::DefineGlobal
             | Instruction::GetGlobal
+            | Instruction::GetField
+            | Instruction::SetField
             | Instruction::SetGlobal
             | Instruction::Constant => {
                 println!(
                     "{:?} {} {:?}",
                     instruction,
-                    self.code[offset + 1],
-                    self.constants[self.code[offset + 1] as usize]
+                    operand(1),
+                    self.constants[operand(1) as usize]
                 );
                 offset + 2
             }
+            Instruction::Closure => {
+                let function = &self.constants[operand(1) as usize];
+                println!("{:?} {} {:?}", instruction, operand(1), function);
+                let upvalue_count = match function {
+                    Value::Obj(o) => match &o.kind {
+                        ObjType::Function(f) => f.upvalue_count as usize,
+                        _ => 0,
+                    },
+                    _ => 0,
+                };
+                let mut offset = offset + 2;
+                for _ in 0..upvalue_count {
+                    let kind = if self.code[offset] == 1 {
+                        "local"
+                    } else {
+                        "upvalue"
+                    };
+                    println!("{:04}    | {} {}", offset, kind, self.code[offset + 1]);
+                    offset += 2;
+                }
+                offset
+            }
+        }
+    }
+}
+
+/// Why [`Chunk::verify`] rejected a chunk. Offsets are of the offending instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    UnknownOpcode {
+        offset: usize,
+        byte: u8,
+    },
+    /// The instruction's operands run past the end of the chunk.
+    Truncated {
+        offset: usize,
+    },
+    /// The instruction's constant doesn't exist, or isn't the kind of value it needs.
+    BadConstant {
+        offset: usize,
+    },
+    /// The jump lands outside the chunk, or in the middle of an instruction.
+    BadJumpTarget {
+        offset: usize,
+        target: i64,
+    },
+    /// An `IncLocal` or `DecLocal` isn't followed by the `Add` or `Sub` and `SetLocal` it falls back on.
+    BadFallback {
+        offset: usize,
+    },
+}
+impl Error for VerifyError {}
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::UnknownOpcode { offset, byte } => {
+                write!(f, "{:04}: unknown opcode {}", offset, byte)
+            }
+            VerifyError::Truncated { offset } => {
+                write!(f, "{:04}: operands run past the end of the chunk", offset)
+            }
+            VerifyError::BadConstant { offset } => write!(f, "{:04}: bad constant operand", offset),
+            VerifyError::BadJumpTarget { offset, target } => {
+                write!(
+                    f,
+                    "{:04}: jump to {} isn't the start of an instruction",
+                    offset, target
+                )
+            }
+            VerifyError::BadFallback { offset } => {
+                write!(f, "{:04}: increment isn't followed by its fallback", offset)
+            }
         }
     }
 }
 
+/// [`Chunk::append`] would need more constants than an operand can refer to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TooManyConstants;
+impl Error for TooManyConstants {}
+impl Display for TooManyConstants {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many constants in one chunk")
+    }
+}
+
 impl Default for Chunk {
     fn default() -> Self {
         Self::new()
@@ -105,7 +610,14 @@ impl Default for Chunk {
 mod tests {
     use crate::vm::instruction::Instruction;
 
-    use super::Chunk;
+    use super::{Chunk, TooManyConstants, VerifyError};
+
+    fn write_jump(chunk: &mut Chunk, instruction: Instruction, offset: i32) {
+        chunk.write(instruction.into(), 1);
+        for byte in offset.to_be_bytes() {
+            chunk.write(byte, 1);
+        }
+    }
 
     #[test]
     fn basic() {
@@ -120,4 +632,174 @@ mod tests {
 
         assert_eq!(chunk.code, vec![1, 2, constant as u8]);
     }
+
+    #[test]
+    fn intern_constant() {
+        let mut chunk = Chunk::new();
+        let a = chunk.intern_constant(1.0.into());
+        let b = chunk.intern_constant(2.0.into());
+        assert_eq!(chunk.intern_constant(1.0.into()), a);
+        assert_ne!(a, b);
+
+        let zero = chunk.intern_constant(0.0.into());
+        assert_ne!(chunk.intern_constant((-0.0).into()), zero);
+        assert_eq!(chunk.intern_constant(0.0.into()), zero);
+        assert_eq!(chunk.constants.len(), 4);
+    }
+
+    #[test]
+    fn code_eq() {
+        let mut a = Chunk::new();
+        let mut b = Chunk::new();
+        for (chunk, line) in [(&mut a, 1), (&mut b, 2)] {
+            let constant = chunk.add_constant(1.5.into());
+            chunk.write(Instruction::Constant.into(), line);
+            chunk.write(constant as u8, line);
+        }
+
+        assert!(a.code_eq(&b));
+        assert_ne!(a, b);
+
+        b.write(Instruction::Return.into(), 2);
+        assert!(!a.code_eq(&b));
+    }
+
+    #[test]
+    fn instructions() {
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(1.5.into()) as u8;
+        chunk.write(Instruction::Constant.into(), 1);
+        chunk.write(constant, 1);
+        write_jump(&mut chunk, Instruction::JumpIfFalse, 1);
+        chunk.write(Instruction::Negate.into(), 1);
+        chunk.write(Instruction::SmallInt.into(), 2);
+        chunk.write(-3i8 as u8, 2);
+        chunk.write(Instruction::Return.into(), 2);
+
+        let decoded = chunk.instructions().collect::<Vec<_>>();
+        assert_eq!(
+            decoded,
+            [
+                (0, Instruction::Constant, &[constant][..]),
+                (2, Instruction::JumpIfFalse, &[0, 0, 0, 1][..]),
+                (7, Instruction::Negate, &[][..]),
+                (8, Instruction::SmallInt, &[-3i8 as u8][..]),
+                (10, Instruction::Return, &[][..]),
+            ]
+        );
+
+        // decoding stops at a truncated instruction
+        chunk.write(Instruction::GetLocal.into(), 2);
+        assert_eq!(chunk.instructions().count(), 5);
+    }
+
+    #[test]
+    fn verify_jump_targets() {
+        // 0: Jump, 5: Constant 0, 7: SmallInt 1, 9: Return
+        let chunk = |offset| {
+            let mut chunk = Chunk::new();
+            let constant = chunk.add_constant(1.5.into());
+            write_jump(&mut chunk, Instruction::Jump, offset);
+            chunk.write(Instruction::Constant.into(), 1);
+            chunk.write(constant as u8, 1);
+            chunk.write(Instruction::SmallInt.into(), 1);
+            chunk.write(1, 1);
+            chunk.write(Instruction::Return.into(), 1);
+            chunk
+        };
+
+        for offset in [-5, 0, 2, 4, 5] {
+            assert_eq!(chunk(offset).verify(), Ok(()), "jump by {}", offset);
+        }
+        // into Constant's and SmallInt's operands, and into the jump itself
+        for offset in [1, 3, -1] {
+            assert_eq!(
+                chunk(offset).verify(),
+                Err(VerifyError::BadJumpTarget {
+                    offset: 0,
+                    target: 5 + offset as i64
+                })
+            );
+        }
+        for offset in [6, -6] {
+            assert!(matches!(
+                chunk(offset).verify(),
+                Err(VerifyError::BadJumpTarget { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn verify_counters() {
+        let chunk = |fallback: [Instruction; 2], slot: u8| {
+            let mut chunk = Chunk::new();
+            chunk.write(Instruction::IncLocal.into(), 1);
+            chunk.write(0, 1);
+            chunk.write(fallback[0].into(), 1);
+            chunk.write(fallback[1].into(), 1);
+            chunk.write(slot, 1);
+            chunk
+        };
+        let add = [Instruction::Add, Instruction::SetLocal];
+        assert_eq!(chunk(add, 0).verify(), Ok(()));
+        for bad in [
+            chunk(add, 1),
+            chunk([Instruction::Sub, Instruction::SetLocal], 0),
+            chunk([Instruction::Add, Instruction::SetUpvalue], 0),
+        ] {
+            assert_eq!(bad.verify(), Err(VerifyError::BadFallback { offset: 0 }));
+        }
+        let mut truncated = chunk(add, 0);
+        truncated.code.pop();
+        assert_eq!(
+            truncated.verify(),
+            Err(VerifyError::BadFallback { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn append_too_many_constants() {
+        let mut chunk = Chunk::new();
+        for n in 0..250 {
+            chunk.add_constant((n as f64).into());
+        }
+        chunk.write(Instruction::Return.into(), 1);
+        let mut other = Chunk::new();
+        for n in 0..10 {
+            other.add_constant((n as f64 + 0.5).into());
+        }
+        other.write(Instruction::Return.into(), 2);
+
+        let before = chunk.clone();
+        assert_eq!(chunk.append(&other), Err(TooManyConstants));
+        assert!(chunk.code_eq(&before));
+
+        // constants it already has are reused
+        other.constants.truncate(5);
+        assert_eq!(chunk.append(&other), Ok(()));
+        assert_eq!(chunk.constants.len(), 255);
+        assert_eq!(chunk.code, [Instruction::Return.into()]);
+        assert_eq!(chunk.lines, [2]);
+    }
+
+    #[test]
+    fn verify_operands() {
+        let mut chunk = Chunk::new();
+        chunk.write(Instruction::Constant.into(), 1);
+        chunk.write(0, 1);
+        assert_eq!(chunk.verify(), Err(VerifyError::BadConstant { offset: 0 }));
+
+        let mut chunk = Chunk::new();
+        chunk.write(Instruction::Jump.into(), 1);
+        chunk.write(0, 1);
+        assert_eq!(chunk.verify(), Err(VerifyError::Truncated { offset: 0 }));
+
+        let mut chunk = Chunk::new();
+        chunk.write(Instruction::Pop.into(), 1);
+        chunk.write(0, 1);
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::UnknownOpcode { offset: 1, byte: 0 })
+        );
+    }
 }
@@ -5,7 +5,33 @@ use std::backtrace::Backtrace;
 pub struct RuntimeError {
     pub kind: RuntimeErrorType,
     pub internal_bt: Backtrace,
+    /// The calls that were running when the error happened, most recent call last. This is filled in once the error
+    /// reaches the top of the VM, so it's empty for errors that haven't.
+    pub traceback: Vec<TraceFrame>,
 }
+impl RuntimeError {
+    pub fn new(kind: RuntimeErrorType) -> Self {
+        RuntimeError {
+            kind,
+            internal_bt: Backtrace::capture(),
+            traceback: Vec::new(),
+        }
+    }
+
+    pub fn type_error(expected: RuntimeType, kind: TypeErrorType) -> Self {
+        Self::new(RuntimeErrorType::TypeError { expected, kind })
+    }
+}
+/// A call that was running when a runtime error happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// The function's name, `<fn>` for an anonymous function, or `<script>` for the top level program.
+    pub function: String,
+    /// The line the function was on, which is where it made the next call, or where the error happened for the last
+    /// frame.
+    pub line: usize,
+}
+
 #[derive(Debug)]
 pub enum RuntimeErrorType {
     TypeError {
@@ -15,6 +41,64 @@ pub enum RuntimeErrorType {
     UndefinedVariable {
         name: String,
     },
+    /// An object was read a field it doesn't have.
+    UndefinedField {
+        name: String,
+    },
+    /// The bytecode itself is invalid, e.g. a jump landing outside the chunk or a truncated operand.
+    MalformedBytecode {
+        ip: usize,
+    },
+    /// Writing to the VM's output failed.
+    Io {
+        message: String,
+    },
+    ArityMismatch {
+        name: String,
+        min: usize,
+        max: usize,
+        got: usize,
+    },
+    IndexOutOfBounds {
+        index: f64,
+        len: usize,
+    },
+    /// An index was a negative number. Indices count from the start, there's no indexing from the end.
+    NegativeIndex {
+        index: f64,
+    },
+    /// Bytes that were meant to be a string weren't valid UTF-8.
+    InvalidUtf8 {
+        valid_up_to: usize,
+    },
+    /// A number that doesn't fit in a byte, i.e. isn't an integer from 0 to 255.
+    ByteOutOfRange {
+        value: f64,
+    },
+    /// `random_int` was asked for an integer between bounds that have none between them.
+    EmptyRange {
+        lo: f64,
+        hi: f64,
+    },
+    /// A native closure was called while it was already running.
+    NativeReentered {
+        name: String,
+    },
+    /// A call would have gone deeper than the VM's frame limit, usually because of unbounded recursion.
+    StackOverflow {
+        max_frames: usize,
+    },
+    /// The value stack grew past [`MAX_STACK`](super::MAX_STACK) values, like deep recursion of functions with many
+    /// locals can before it reaches the frame limit.
+    ValueStackOverflow {
+        max_values: usize,
+    },
+    /// A number other than `0` was divided by zero, or a math native was asked for an infinite result like `log(0)`,
+    /// in [strict math](super::VM::set_strict_math) mode.
+    DivisionByZero,
+    /// A division or math native had no meaningful result (`NaN`), like `0 / 0` or `sqrt(-1)`, in
+    /// [strict math](super::VM::set_strict_math) mode.
+    NanResult,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -22,6 +106,21 @@ pub enum TypeErrorType {
     GlobalNameMustBeString,
     ObjectSetMustBeObject,
     KeyMustBeString,
+    NotCallable,
+    ListPushMustBeList,
+    ExpectedList,
+    /// Values that can't be ordered against each other, like a number and a string.
+    NotOrderable,
+    NotIndexable,
+    IndexMustBeInteger,
+    ExpectedBytes,
+    /// Something without a length was passed to `len`.
+    NoLength,
+    ExpectedFunction,
+    ExpectedString,
+    ExpectedNumber,
+    ExpectedBool,
+    ExpectedObject,
 }
 // TODO: proper type system
 #[allow(dead_code)] // for now
@@ -32,4 +131,8 @@ pub enum RuntimeType {
     String,
     Object,
     Null,
+    Bool,
+    Function,
+    List,
+    Bytes,
 }
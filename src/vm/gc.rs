@@ -1,64 +1,203 @@
-// use std::{
-//     cell::Cell,
-//     ops::Deref,
-//     ptr::{drop_in_place, NonNull},
-// };
-
-// use super::obj::Obj;
-
-// #[cfg(test)]
-// mod tests {
-//     use crate::vm::{
-//         obj::{AnkokuString, Obj, ObjType},
-//         value::Value,
-//     };
-
-//     use super::Gc;
-
-//     #[test]
-//     fn basic() {
-//         let string = Obj::new(ObjType::String(AnkokuString::new("hello,".into())));
-//         let gc = Gc::new();
-
-//         let left = Value::Obj(gc.alloc(string));
-//         let string = Obj::new(ObjType::String(AnkokuString::new(" world".into())));
-//         let right = Value::Obj(gc.alloc(string));
-
-//         let result = left.add(right, &gc);
-
-//         if let Value::Obj(o) = result {
-//             assert_eq!(
-//                 o.inner().kind,
-//                 ObjType::String(AnkokuString::new("hello, world".into()))
-//             );
-//         } else {
-//             unreachable!()
-//         }
-//     }
-
-//     #[test]
-//     fn moar_strings() {
-//         let string = Obj::new(ObjType::String(AnkokuString::new("st".into())));
-//         let gc = Gc::new();
-
-//         let left = Value::Obj(gc.alloc(string));
-//         let string = Obj::new(ObjType::String(AnkokuString::new("ri".into())));
-//         let right = Value::Obj(gc.alloc(string));
-
-//         let result = left.add(right, &gc);
-
-//         let string = Obj::new(ObjType::String(AnkokuString::new("ng".into())));
-//         let right = Value::Obj(gc.alloc(string));
-
-//         let result = result.add(right, &gc);
-
-//         if let Value::Obj(o) = result {
-//             assert_eq!(
-//                 o.inner().kind,
-//                 ObjType::String(AnkokuString::new("string".into()))
-//             );
-//         } else {
-//             unreachable!()
-//         }
-//     }
-// }
+//! The list of every object the VM has allocated, which is what the GC sweeps.
+
+use std::{
+    cell::{Cell, RefCell},
+    ptr::NonNull,
+    rc::{Rc, Weak},
+};
+
+use super::{obj::Obj, GcRef, WeakGcRef};
+
+/// An intrusive singly linked list of heap objects, linked through [`Obj::next`]. This is the only place objects are
+/// created and freed, so the rest of the VM never deals with raw pointers beyond dereferencing a [`GcRef`].
+///
+/// Safety invariants:
+/// - Every object in the list was allocated by [`ObjList::push`] and is owned by the list.
+/// - Objects are only freed when they're unlinked in [`ObjList::sweep`], or when the list is dropped, so each one is
+///   freed exactly once.
+/// - [`GcRef`]s aren't owning. One that outlives its object, because it wasn't reachable from a root when the GC
+///   swept or the VM was dropped, must never be dereferenced again. [`WeakGcRef`]s are cleared before that happens.
+pub(crate) struct ObjList {
+    // Option<NonNull<T>> is the same size as *mut T where None is a nullptr
+    head: Cell<Option<NonNull<Obj>>>,
+    /// The targets of every live [`WeakGcRef`], cleared when their object is freed.
+    weak: RefCell<Vec<Weak<Cell<Option<GcRef>>>>>,
+}
+
+impl ObjList {
+    pub(crate) fn new() -> Self {
+        Self {
+            head: Cell::new(None),
+            weak: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn downgrade(&self, obj: GcRef) -> WeakGcRef {
+        let target = Rc::new(Cell::new(Some(obj)));
+        self.weak.borrow_mut().push(Rc::downgrade(&target));
+        WeakGcRef { target }
+    }
+
+    /// Moves `obj` onto the heap and links it in.
+    pub(crate) fn push(&self, mut obj: Obj) -> GcRef {
+        obj.next = self.head.get();
+        let ptr = NonNull::from(Box::leak(Box::new(obj)));
+        self.head.set(Some(ptr));
+
+        GcRef { obj: ptr.as_ptr() }
+    }
+
+    /// Frees every object that isn't marked, and unmarks the rest for the next collection.
+    pub(crate) fn sweep(&self) {
+        // before anything is unmarked, clear weak refs to what's about to be freed, and forget dropped ones
+        self.weak.borrow_mut().retain(|target| {
+            let Some(target) = target.upgrade() else {
+                return false;
+            };
+            match target.get() {
+                Some(obj) if obj.marked => true,
+                _ => {
+                    target.set(None);
+                    false
+                }
+            }
+        });
+
+        let mut prev: Option<NonNull<Obj>> = None;
+        let mut current = self.head.get();
+        while let Some(ptr) = current {
+            // SAFETY: `ptr` is still linked, so it hasn't been freed. Fields are accessed through the raw pointer so
+            // no reference to the object is alive when it's freed below.
+            let (marked, next) = unsafe { ((*ptr.as_ptr()).marked, (*ptr.as_ptr()).next) };
+            if marked {
+                unsafe { (*ptr.as_ptr()).marked = false };
+                prev = Some(ptr);
+            } else {
+                match prev {
+                    // SAFETY: `prev` is linked and was kept, so it's still alive
+                    Some(prev) => unsafe { (*prev.as_ptr()).next = next },
+                    None => self.head.set(next),
+                }
+
+                #[cfg(feature = "gc-debug-super-slow")]
+                println!("{:?} sweeping {}", ptr, unsafe { ptr.as_ref() }.kind.name());
+
+                // SAFETY: it came from `Box::leak` in `push` and was just unlinked, so nothing can free it again
+                unsafe { drop(Box::from_raw(ptr.as_ptr())) };
+            }
+            current = next;
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        let mut len = 0;
+        let mut current = self.head.get();
+        while let Some(ptr) = current {
+            len += 1;
+            // SAFETY: linked objects are alive
+            current = unsafe { (*ptr.as_ptr()).next };
+        }
+        len
+    }
+}
+
+impl Drop for ObjList {
+    fn drop(&mut self) {
+        for target in self.weak.get_mut().drain(..) {
+            if let Some(target) = target.upgrade() {
+                target.set(None);
+            }
+        }
+        let mut current = self.head.take();
+        while let Some(ptr) = current {
+            // SAFETY: see `sweep`, every object is freed exactly once, here or there
+            let obj = unsafe { Box::from_raw(ptr.as_ptr()) };
+            current = obj.next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        obj::{AnkokuString, Obj, ObjType},
+        value::Value,
+        VM,
+    };
+
+    use super::ObjList;
+
+    fn string(s: &str) -> Obj {
+        Obj::new(ObjType::String(AnkokuString::new(s.into())))
+    }
+
+    #[test]
+    fn sweep() {
+        let list = ObjList::new();
+        let mut kept = list.push(string("kept"));
+        list.push(string("freed"));
+        let mut last = list.push(string("also kept"));
+        kept.marked = true;
+        last.marked = true;
+
+        list.sweep();
+        assert_eq!(list.len(), 2);
+        assert!(!kept.marked && !last.marked);
+        assert_eq!(kept.to_string(), "kept");
+
+        list.sweep();
+        assert_eq!(list.len(), 0);
+    }
+
+    #[test]
+    fn weak_refs() {
+        let mut vm = VM::new();
+        let obj = vm.alloc(string("cached"));
+        let weak = vm.downgrade(obj);
+        vm.stack_push(Value::Obj(obj));
+        vm.collect();
+        assert_eq!(weak.upgrade(), Some(obj));
+
+        vm.stack_pop().unwrap();
+        vm.collect();
+        assert_eq!(weak.upgrade(), None);
+
+        // dropping the VM frees everything, reachable or not
+        let obj = vm.alloc(string("still reachable"));
+        vm.stack_push(Value::Obj(obj));
+        let weak = vm.downgrade(obj);
+        drop(vm);
+        assert_eq!(weak.upgrade(), None);
+    }
+
+    // operands go on the stack as they're made, so allocating the next one can't collect them
+
+    #[test]
+    fn concat() {
+        let mut vm = VM::new();
+
+        let left = Value::Obj(vm.alloc(string("hello,")));
+        vm.stack_push(left.clone());
+        let right = Value::Obj(vm.alloc(string(" world")));
+        vm.stack_push(right.clone());
+
+        assert_eq!(left.add(right, &vm).unwrap().to_string(), "hello, world");
+    }
+
+    #[test]
+    fn moar_strings() {
+        let mut vm = VM::new();
+
+        let left = Value::Obj(vm.alloc(string("st")));
+        vm.stack_push(left.clone());
+        let right = Value::Obj(vm.alloc(string("ri")));
+        vm.stack_push(right.clone());
+        let result = left.add(right, &vm).unwrap();
+        vm.stack_push(result.clone());
+        let right = Value::Obj(vm.alloc(string("ng")));
+        vm.stack_push(right.clone());
+
+        assert_eq!(result.add(right, &vm).unwrap().to_string(), "string");
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum Instruction {
     Return = 1,
@@ -21,14 +21,45 @@ pub enum Instruction {
     Jump = 18,
     Greater = 19,
     Less = 20,
+    Call = 21,
+    NewList = 22,
+    ListPush = 23,
+    Index = 24,
+    /// Followed by the function's constant, then an `is_local` byte and an index byte for each of its upvalues.
+    Closure = 25,
+    GetUpvalue = 26,
+    SetUpvalue = 27,
+    /// Moves the local on top of the stack into the upvalues capturing it, and pops it.
+    CloseUpvalue = 28,
+    /// Pushes its operand, an `i8`, as a number. Used for small integers instead of a constant.
+    SmallInt = 29,
+    Equal = 30,
+    GreaterEqual = 31,
+    LessEqual = 32,
+    /// Replaces the object on top of the stack with its field named by the constant operand.
+    GetField = 33,
+    /// Sets the field named by the constant operand of the object under the top of the stack to the value on top,
+    /// leaving just the value.
+    SetField = 34,
+    /// Like `NewObject`, with room for as many fields as its operand.
+    NewObjectSized = 35,
+    /// `is`: whether the top two values are the same value, rather than just equal like `Equal`.
+    Identical = 36,
+    /// `x = x + 1` for the local in the operand's slot, leaving the new value on the stack. It's always followed by
+    /// `Add` and `SetLocal` with the same slot, which it skips if the local is a number. Otherwise it pushes the local
+    /// and `1` and runs them, so anything else still gets `+`'s errors and operator methods.
+    IncLocal = 37,
+    /// `x = x - 1`, like `IncLocal` but followed by `Sub`.
+    DecLocal = 38,
     Print = 100, // FIXME: TEMP, will be removed when functions work
 }
 
-impl From<u8> for Instruction {
-    fn from(v: u8) -> Self {
+impl Instruction {
+    /// The instruction with this opcode, if there is one.
+    pub fn from_byte(v: u8) -> Option<Self> {
         use Instruction::*;
 
-        match v {
+        Some(match v {
             1 => Return,
             2 => Constant,
             3 => Negate,
@@ -49,11 +80,55 @@ impl From<u8> for Instruction {
             18 => Jump,
             19 => Greater,
             20 => Less,
+            21 => Call,
+            22 => NewList,
+            23 => ListPush,
+            24 => Index,
+            25 => Closure,
+            26 => GetUpvalue,
+            27 => SetUpvalue,
+            28 => CloseUpvalue,
+            29 => SmallInt,
+            30 => Equal,
+            31 => GreaterEqual,
+            32 => LessEqual,
+            33 => GetField,
+            34 => SetField,
+            35 => NewObjectSized,
+            36 => Identical,
+            37 => IncLocal,
+            38 => DecLocal,
             100 => Print,
-            _ => panic!("not an instruction: {:?}", v),
+            _ => return None,
+        })
+    }
+
+    /// How much deeper the stack is after the instruction runs than before, given its operands. For `Call` that's
+    /// once the call has returned, and for `IncLocal` and `DecLocal` it's the same whether or not they take their
+    /// fallback. `Return` leaves the function, so it's `0` here.
+    pub fn stack_effect(self, operands: &[u8]) -> isize {
+        use Instruction::*;
+
+        match self {
+            Return | Negate | Not | SetLocal | SetGlobal | SetUpvalue | GetField | JumpIfFalse
+            | Jump => 0,
+            Constant | SmallInt | GetLocal | GetGlobal | GetUpvalue | NewObject
+            | NewObjectSized | NewList | Closure | IncLocal | DecLocal => 1,
+            Add | Sub | Mul | Div | Greater | Less | GreaterEqual | LessEqual | Equal
+            | Identical | Pop | Print | DefineGlobal | ListPush | Index | SetField
+            | CloseUpvalue => -1,
+            ObjectSet => -2,
+            // the callee and arguments are replaced by the result
+            Call => -(operands.first().copied().unwrap_or(0) as isize),
         }
     }
 }
+
+impl From<u8> for Instruction {
+    fn from(v: u8) -> Self {
+        Self::from_byte(v).unwrap_or_else(|| panic!("not an instruction: {:?}", v))
+    }
+}
 impl From<Instruction> for u8 {
     fn from(v: Instruction) -> Self {
         v as u8
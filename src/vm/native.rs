@@ -0,0 +1,1640 @@
+//! Functions implemented in Rust and callable from scripts.
+
+use std::{
+    cell::RefCell,
+    fmt::Debug,
+    rc::Rc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{parser::tokenizer, util::fxhash::FxHashMap};
+
+use super::{
+    error::{RuntimeError, RuntimeErrorType, RuntimeType, TypeErrorType},
+    obj::{AnkokuString, List, Obj, ObjType, Object},
+    value::Value,
+    GcRef, VM,
+};
+
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>;
+
+/// A native implemented by a closure, so it can carry host state. See [`VM::define_native_closure`].
+pub type NativeClosure = dyn FnMut(&mut VM, &[Value]) -> Result<Value, RuntimeError>;
+
+#[derive(Clone)]
+pub enum NativeFunction {
+    Fn(NativeFn),
+    /// Shared between clones of the native, and borrowed for the length of each call.
+    Closure(Rc<RefCell<NativeClosure>>),
+}
+
+#[derive(Clone)]
+pub struct Native {
+    pub name: String,
+    pub function: NativeFunction,
+}
+
+impl Native {
+    pub fn new<S: Into<String>>(name: S, function: NativeFn) -> Self {
+        Self {
+            name: name.into(),
+            function: NativeFunction::Fn(function),
+        }
+    }
+
+    pub fn closure<S, F>(name: S, function: F) -> Self
+    where
+        S: Into<String>,
+        F: FnMut(&mut VM, &[Value]) -> Result<Value, RuntimeError> + 'static,
+    {
+        Self {
+            name: name.into(),
+            function: NativeFunction::Closure(Rc::new(RefCell::new(function))),
+        }
+    }
+
+    /// Calls the native. A closure can't be called again while it's already running, e.g. from a callback it calls,
+    /// so that errors with [`RuntimeErrorType::NativeReentered`].
+    pub fn call(&self, vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+        match &self.function {
+            NativeFunction::Fn(function) => function(vm, args),
+            NativeFunction::Closure(function) => {
+                let Ok(mut function) = function.try_borrow_mut() else {
+                    return Err(vm.runtime_error(RuntimeErrorType::NativeReentered {
+                        name: self.name.clone(),
+                    }));
+                };
+                function(vm, args)
+            }
+        }
+    }
+}
+
+impl Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+/// Where `clock()` and `now()` get the time from, see [`VM::set_clock`]. Hosts without a system clock, like WASM in a
+/// browser, can supply their own, and tests can stub it out.
+pub trait Clock {
+    /// Seconds since some fixed point, usually when the VM was created. Never goes backwards.
+    fn monotonic(&self) -> f64;
+    /// Seconds since the Unix epoch.
+    fn wall(&self) -> f64;
+}
+
+/// The [`Clock`] VMs start with, measuring monotonic time from when it was created.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn monotonic(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    fn wall(&self) -> f64 {
+        // a clock set before 1970 is negative rather than an error
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs_f64(),
+            Err(e) => -e.duration().as_secs_f64(),
+        }
+    }
+}
+
+/// Registers the natives every VM starts with.
+pub(crate) fn define_stdlib(vm: &mut VM) {
+    vm.define_native("clone", clone);
+    vm.define_native("defined", defined);
+    vm.define_native("merge", merge);
+    vm.define_native("keys", keys);
+    vm.define_native("values", values);
+    vm.define_native("entries", entries);
+    vm.define_native("sort", sort);
+    vm.define_native("map", map);
+    vm.define_native("filter", filter);
+    vm.define_native("reduce", reduce);
+    vm.define_native("bytes", bytes);
+    vm.define_native("string", string);
+    vm.define_native("len", len);
+    vm.define_native("arity", arity);
+    vm.define_native("name", name);
+    vm.define_native("trim", trim);
+    vm.define_native("upper", upper);
+    vm.define_native("lower", lower);
+    vm.define_native("split", split);
+    vm.define_native("replace", replace);
+    vm.define_native("contains", contains);
+    vm.define_native("parse_number", parse_number);
+    vm.define_native("to_string", to_string);
+    vm.define_native("format", format);
+    vm.define_native("min", min);
+    vm.define_native("max", max);
+    vm.define_native("abs", abs);
+    vm.define_native("floor", floor);
+    vm.define_native("ceil", ceil);
+    vm.define_native("round", round);
+    vm.define_native("sqrt", sqrt);
+    vm.define_native("pow", pow);
+    vm.define_native("sin", sin);
+    vm.define_native("cos", cos);
+    vm.define_native("log", log);
+    vm.define_native("random", random);
+    vm.define_native("random_int", random_int);
+    vm.define_native("seed", seed);
+    vm.define_native("clock", clock);
+    vm.define_native("now", now);
+    vm.define_native("flush", flush);
+}
+
+/// Errors with [`RuntimeErrorType::ArityMismatch`] unless a native was passed between `min` and `max` arguments.
+pub fn check_arity(
+    vm: &VM,
+    name: &str,
+    args: &[Value],
+    min: usize,
+    max: usize,
+) -> Result<(), RuntimeError> {
+    if args.len() < min || args.len() > max {
+        Err(vm.runtime_error(RuntimeErrorType::ArityMismatch {
+            name: name.to_string(),
+            min,
+            max,
+            got: args.len(),
+        }))
+    } else {
+        Ok(())
+    }
+}
+
+/// A type a native's argument can be converted to, erroring with a type error if it's the wrong type.
+pub trait FromValue<'a>: Sized {
+    fn from_value(value: &'a Value) -> Result<Self, RuntimeError>;
+}
+
+impl<'a> FromValue<'a> for &'a Value {
+    fn from_value(value: &'a Value) -> Result<Self, RuntimeError> {
+        Ok(value)
+    }
+}
+
+impl FromValue<'_> for f64 {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        match value {
+            Value::Real(n) => Ok(*n),
+            _ => Err(RuntimeError::type_error(
+                RuntimeType::Number,
+                TypeErrorType::ExpectedNumber,
+            )),
+        }
+    }
+}
+
+impl FromValue<'_> for bool {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        match value {
+            Value::Bool(b) => Ok(*b),
+            _ => Err(RuntimeError::type_error(
+                RuntimeType::Bool,
+                TypeErrorType::ExpectedBool,
+            )),
+        }
+    }
+}
+
+impl<'a> FromValue<'a> for &'a str {
+    fn from_value(value: &'a Value) -> Result<Self, RuntimeError> {
+        if let Value::Obj(o) = value {
+            if let ObjType::String(s) = &o.kind {
+                return Ok(s.as_str());
+            }
+        }
+        Err(RuntimeError::type_error(
+            RuntimeType::String,
+            TypeErrorType::ExpectedString,
+        ))
+    }
+}
+
+impl<'a> FromValue<'a> for &'a [Value] {
+    fn from_value(value: &'a Value) -> Result<Self, RuntimeError> {
+        if let Value::Obj(o) = value {
+            if let ObjType::List(l) = &o.kind {
+                return Ok(&l.items);
+            }
+        }
+        Err(RuntimeError::type_error(
+            RuntimeType::List,
+            TypeErrorType::ExpectedList,
+        ))
+    }
+}
+
+/// Typed access to a native's arguments. Arguments must be there, so check the arity first.
+pub trait NativeArgs {
+    fn arg<'a, T: FromValue<'a>>(&'a self, i: usize) -> Result<T, RuntimeError>;
+
+    fn get_real(&self, i: usize) -> Result<f64, RuntimeError> {
+        self.arg(i)
+    }
+    fn get_bool(&self, i: usize) -> Result<bool, RuntimeError> {
+        self.arg(i)
+    }
+    fn get_str(&self, i: usize) -> Result<&str, RuntimeError> {
+        self.arg(i)
+    }
+    fn get_list(&self, i: usize) -> Result<&[Value], RuntimeError> {
+        self.arg(i)
+    }
+}
+
+impl NativeArgs for [Value] {
+    fn arg<'a, T: FromValue<'a>>(&'a self, i: usize) -> Result<T, RuntimeError> {
+        T::from_value(&self[i])
+    }
+}
+
+/// Defines a [`NativeFn`] that checks it was passed exactly as many arguments as it declares, and converts each one
+/// with [`FromValue`]. The first parameter names the VM, and the body returns a `Result<Value, RuntimeError>`.
+///
+/// ```
+/// use ankoku::{native, vm::value::Value};
+///
+/// native! {
+///     /// `pow(base, exp)`: `base` raised to the power of `exp`.
+///     fn pow(_vm, base: f64, exp: f64) {
+///         Ok(Value::Real(base.powf(exp)))
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! native {
+    ($(#[$attr:meta])* $vis:vis fn $name:ident($vm:ident $(, $arg:ident: $ty:ty)* $(,)?) $body:block) => {
+        $(#[$attr])*
+        $vis fn $name(
+            $vm: &mut $crate::vm::VM,
+            args: &[$crate::vm::value::Value],
+        ) -> ::std::result::Result<$crate::vm::value::Value, $crate::vm::error::RuntimeError> {
+            #[allow(unused_imports)]
+            use $crate::vm::native::NativeArgs as _;
+            let arity = 0 $(+ { let _ = stringify!($arg); 1 })*;
+            $crate::vm::native::check_arity($vm, stringify!($name), args, arity, arity)?;
+            let mut _i = 0;
+            $(
+                let $arg: $ty = args.arg(_i)?;
+                _i += 1;
+            )*
+            $body
+        }
+    };
+}
+
+/// `clone(value, deep)`: copies an object's fields into a new object. Nested objects are shared unless `deep` is
+/// `true`, in which case they're copied as well. Anything other than an object is immutable and returned as is.
+fn clone(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "clone", args, 1, 2)?;
+    let deep = matches!(args.get(1), Some(Value::Bool(true)));
+
+    clone_value(vm, &args[0], deep, &mut FxHashMap::default())
+}
+
+/// `defined(name)`: whether a global called `name` exists, so scripts can check for one without erroring.
+fn defined(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "defined", args, 1, 1)?;
+    if let Value::Obj(o) = &args[0] {
+        if let ObjType::String(name) = &o.kind {
+            return Ok(Value::Bool(vm.globals.get(name).is_some()));
+        }
+    }
+    Err(vm.type_error(RuntimeType::String, TypeErrorType::GlobalNameMustBeString))
+}
+
+/// `merge(a, ...)`: a new object with the fields of every object passed, later objects' fields replacing earlier
+/// ones with the same key. The objects passed aren't changed.
+fn merge(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "merge", args, 1, usize::MAX)?;
+    let mut merged = Object::new();
+    for arg in args {
+        let Value::Obj(o) = arg else {
+            return Err(vm.type_error(RuntimeType::Object, TypeErrorType::ExpectedObject));
+        };
+        let ObjType::Object(object) = &o.kind else {
+            return Err(vm.type_error(RuntimeType::Object, TypeErrorType::ExpectedObject));
+        };
+        merged.table.add_all(&object.table);
+    }
+    Ok(Value::Obj(vm.alloc(Obj::new(ObjType::Object(merged)))))
+}
+
+/// `keys(obj)`: a list of an object's field names. `keys`, `values` and `entries` list fields in the same order,
+/// which is the same every run for objects built the same way, see
+/// [`HashTable::entries`](super::table::HashTable::entries).
+fn keys(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "keys", args, 1, 1)?;
+    let fields = object_fields(vm, &args[0])?;
+    // the list is rooted on the stack while its items are allocated
+    let list = Value::Obj(vm.alloc(Obj::new(ObjType::List(List::default()))));
+    vm.stack_push(list.clone());
+    for (key, _) in fields {
+        let key = Value::Obj(vm.alloc(key.into()));
+        list_push(&list, key);
+    }
+    vm.stack_pop()
+}
+
+/// `values(obj)`: a list of an object's field values, see `keys`.
+fn values(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "values", args, 1, 1)?;
+    let values = object_fields(vm, &args[0])?
+        .into_iter()
+        .map(|(_, value)| value)
+        .collect();
+    Ok(Value::Obj(
+        vm.alloc(Obj::new(ObjType::List(List::new(values)))),
+    ))
+}
+
+/// `entries(obj)`: a list of `[key, value]` lists, one for each of an object's fields, see `keys`.
+fn entries(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "entries", args, 1, 1)?;
+    let fields = object_fields(vm, &args[0])?;
+    let list = Value::Obj(vm.alloc(Obj::new(ObjType::List(List::default()))));
+    vm.stack_push(list.clone());
+    for (key, value) in fields {
+        // each pair is in the rooted list before its key is allocated
+        let pair = Value::Obj(vm.alloc(Obj::new(ObjType::List(List::new(vec![value])))));
+        list_push(&list, pair.clone());
+        let key = Value::Obj(vm.alloc(key.into()));
+        if let Value::Obj(mut o) = pair {
+            if let ObjType::List(l) = &mut o.kind {
+                l.items.insert(0, key);
+            }
+        }
+    }
+    vm.stack_pop()
+}
+
+/// `sort(list)`: returns a sorted copy of a list of numbers or a list of strings. Errors if any two elements can't
+/// be compared, see the `PartialOrd` impl for [`Value`].
+///
+/// `sort(list, cmp)` orders any list with `cmp(a, b)`, which returns a negative number if `a` goes before `b`, a
+/// positive one if it goes after, and 0 if either order will do. Elements `cmp` calls equal keep their order.
+fn sort(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "sort", args, 1, 2)?;
+    let mut items = list_items(vm, &args[0])?;
+
+    let Some(cmp) = args.get(1) else {
+        // check up front so the comparator below never sees an unorderable pair
+        for pair in items.windows(2) {
+            if pair[0].partial_cmp(&pair[1]).is_none() {
+                return Err(vm.type_error(RuntimeType::Number, TypeErrorType::NotOrderable));
+            }
+        }
+        items.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        return Ok(Value::Obj(
+            vm.alloc(Obj::new(ObjType::List(List::new(items)))),
+        ));
+    };
+
+    // the copy is rooted on the stack, so `cmp` dropping the original list can't free elements still being sorted
+    let sorted = Value::Obj(vm.alloc(Obj::new(ObjType::List(List::new(items.clone())))));
+    vm.stack_push(sorted.clone());
+    let items = merge_sort(items, &mut |a, b| {
+        let order = vm.call(cmp.clone(), &[a.clone(), b.clone()])?;
+        Ok(f64::from_value(&order)? < 0.0)
+    })?;
+    if let Value::Obj(mut o) = sorted {
+        if let ObjType::List(l) = &mut o.kind {
+            l.items = items;
+        }
+    }
+    vm.stack_pop()
+}
+
+/// A stable merge sort that stops at the first error from `less`. The standard library's sorts can panic when the
+/// comparison isn't a total order, which a script's comparator doesn't have to be.
+fn merge_sort(
+    mut items: Vec<Value>,
+    less: &mut impl FnMut(&Value, &Value) -> Result<bool, RuntimeError>,
+) -> Result<Vec<Value>, RuntimeError> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let mut left = merge_sort(items, less)?.into_iter().peekable();
+    let mut right = merge_sort(right, less)?.into_iter().peekable();
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        // only take from the right when it's strictly less, so equal elements stay in order
+        if less(r, l)? {
+            merged.extend(right.next());
+        } else {
+            merged.extend(left.next());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+/// `map(list, fn)`: a new list with `fn` applied to each element.
+fn map(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "map", args, 2, 2)?;
+    let items = list_items(vm, &args[0])?;
+    // the list is rooted on the stack, so a collection in a later call can't free the results so far
+    let mapped = Value::Obj(vm.alloc(Obj::new(ObjType::List(List::default()))));
+    vm.stack_push(mapped.clone());
+    for item in items {
+        let result = vm.call(args[1].clone(), &[item])?;
+        list_push(&mapped, result);
+    }
+    vm.stack_pop()
+}
+
+/// `filter(list, fn)`: a new list with only the elements `fn` returns something truthy for.
+fn filter(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "filter", args, 2, 2)?;
+    let items = list_items(vm, &args[0])?;
+    // rooted like in `map`, since `fn` can drop the original list
+    let kept = Value::Obj(vm.alloc(Obj::new(ObjType::List(List::default()))));
+    vm.stack_push(kept.clone());
+    for item in items {
+        if !vm
+            .call(args[1].clone(), std::slice::from_ref(&item))?
+            .falsey()
+        {
+            list_push(&kept, item);
+        }
+    }
+    vm.stack_pop()
+}
+
+/// `reduce(list, fn, init)`: folds the list into one value, calling `fn(accumulator, element)` for each element.
+fn reduce(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "reduce", args, 3, 3)?;
+    let mut accumulator = args[2].clone();
+    for item in list_items(vm, &args[0])? {
+        accumulator = vm.call(args[1].clone(), &[accumulator, item])?;
+    }
+    Ok(accumulator)
+}
+
+/// `bytes(value)`: a string's UTF-8 encoding, or a list of numbers from 0 to 255 as bytes.
+fn bytes(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "bytes", args, 1, 1)?;
+    if let Value::Obj(o) = &args[0] {
+        if let ObjType::String(s) = &o.kind {
+            let bytes = s.as_str().as_bytes().to_vec();
+            return Ok(Value::Obj(vm.alloc(Obj::new(ObjType::Bytes(bytes)))));
+        }
+    }
+    let mut bytes = Vec::new();
+    for item in list_items(vm, &args[0])? {
+        match item {
+            Value::Real(n) if n.fract() == 0.0 && (0.0..=255.0).contains(&n) => bytes.push(n as u8),
+            Value::Real(n) => {
+                return Err(vm.runtime_error(RuntimeErrorType::ByteOutOfRange { value: n }))
+            }
+            _ => return Err(vm.type_error(RuntimeType::Number, TypeErrorType::ExpectedBytes)),
+        }
+    }
+    Ok(Value::Obj(vm.alloc(Obj::new(ObjType::Bytes(bytes)))))
+}
+
+/// `string(bytes)`: decodes UTF-8 bytes into a string, erroring if they aren't valid UTF-8.
+fn string(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "string", args, 1, 1)?;
+    if let Value::Obj(o) = &args[0] {
+        if let ObjType::Bytes(b) = &o.kind {
+            return match std::str::from_utf8(b) {
+                Ok(s) => Ok(Value::Obj(
+                    vm.alloc(AnkokuString::new(s.to_string()).into()),
+                )),
+                Err(e) => Err(vm.runtime_error(RuntimeErrorType::InvalidUtf8 {
+                    valid_up_to: e.valid_up_to(),
+                })),
+            };
+        }
+    }
+    Err(vm.type_error(RuntimeType::Bytes, TypeErrorType::ExpectedBytes))
+}
+
+/// `len(value)`: the number of elements in a list, bytes in bytes, or characters in a string.
+fn len(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "len", args, 1, 1)?;
+    if let Value::Obj(o) = &args[0] {
+        match &o.kind {
+            ObjType::List(l) => return Ok(Value::Real(l.items.len() as f64)),
+            ObjType::Bytes(b) => return Ok(Value::Real(b.len() as f64)),
+            ObjType::String(s) => return Ok(Value::Real(s.as_str().chars().count() as f64)),
+            _ => {}
+        }
+    }
+    Err(vm.type_error(RuntimeType::List, TypeErrorType::NoLength))
+}
+
+/// `arity(f)`: the number of parameters a function declares. Natives don't declare theirs, so they give `null`.
+fn arity(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "arity", args, 1, 1)?;
+    if let Value::Obj(o) = &args[0] {
+        match &o.kind {
+            ObjType::Function(f) => return Ok(Value::Real(f.arity as f64)),
+            ObjType::Closure(c) => return Ok(Value::Real(c.function().arity as f64)),
+            ObjType::Native(_) => return Ok(Value::Null),
+            _ => {}
+        }
+    }
+    Err(vm.type_error(RuntimeType::Function, TypeErrorType::ExpectedFunction))
+}
+
+/// `name(f)`: the name a function was declared with, or `null` for an anonymous function.
+fn name(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "name", args, 1, 1)?;
+    if let Value::Obj(o) = &args[0] {
+        let name = match &o.kind {
+            ObjType::Function(f) => f.name.clone(),
+            ObjType::Closure(c) => c.function().name.clone(),
+            ObjType::Native(n) => Some(n.name.clone()),
+            _ => return Err(vm.type_error(RuntimeType::Function, TypeErrorType::ExpectedFunction)),
+        };
+        return Ok(match name {
+            Some(name) => Value::Obj(vm.alloc(AnkokuString::new(name).into())),
+            None => Value::Null,
+        });
+    }
+    Err(vm.type_error(RuntimeType::Function, TypeErrorType::ExpectedFunction))
+}
+
+native! {
+    /// `trim(s)`: `s` without leading or trailing whitespace.
+    fn trim(vm, s: &str) {
+        Ok(Value::string(vm, s.trim()))
+    }
+}
+
+native! {
+    /// `upper(s)`: `s` in uppercase.
+    fn upper(vm, s: &str) {
+        Ok(Value::string(vm, s.to_uppercase()))
+    }
+}
+
+native! {
+    /// `lower(s)`: `s` in lowercase.
+    fn lower(vm, s: &str) {
+        Ok(Value::string(vm, s.to_lowercase()))
+    }
+}
+
+/// `split(s, sep)`: a list of the parts of `s` between each `sep`. An empty `sep` splits `s` into its characters.
+fn split(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "split", args, 2, 2)?;
+    let s = args.get_str(0)?;
+    let sep = args.get_str(1)?;
+    let parts = if sep.is_empty() {
+        s.chars().map(String::from).collect::<Vec<_>>()
+    } else {
+        s.split(sep).map(String::from).collect()
+    };
+    // the list is rooted on the stack while its items are allocated
+    let list = Value::Obj(vm.alloc(Obj::new(ObjType::List(List::default()))));
+    vm.stack_push(list.clone());
+    for part in parts {
+        let part = Value::string(vm, part);
+        if let Value::Obj(mut o) = list {
+            if let ObjType::List(l) = &mut o.kind {
+                l.items.push(part);
+            }
+        }
+    }
+    vm.stack_pop()
+}
+
+native! {
+    /// `replace(s, from, to)`: `s` with every `from` replaced by `to`.
+    fn replace(vm, s: &str, from: &str, to: &str) {
+        Ok(Value::string(vm, s.replace(from, to)))
+    }
+}
+
+native! {
+    /// `contains(s, sub)`: whether `sub` appears in `s`.
+    fn contains(_vm, s: &str, sub: &str) {
+        Ok(Value::Bool(s.contains(sub)))
+    }
+}
+
+/// `parse_number(s)`: the number `s` is written as, in the same syntax as number literals, or `null` if it isn't one.
+fn parse_number(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "parse_number", args, 1, 1)?;
+    let s = args.get_str(0)?;
+    Ok(match tokenizer::parse_number(s.trim()) {
+        Some(n) => Value::Real(n),
+        None => Value::Null,
+    })
+}
+
+/// `to_string(value)`: `value` as `print` would show it.
+fn to_string(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "to_string", args, 1, 1)?;
+    let s = args[0].to_string();
+    Ok(Value::string(vm, s))
+}
+
+/// `format(fmt, ...)`: `fmt` with each `{}` replaced by the next argument, shown as `print` would. There has to be
+/// exactly one argument per `{}`.
+fn format(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "format", args, 1, usize::MAX)?;
+    let fmt = args.get_str(0)?;
+    let pieces = fmt.split("{}").collect::<Vec<_>>();
+    check_arity(vm, "format", args, pieces.len(), pieces.len())?;
+    let mut out = pieces[0].to_string();
+    for (piece, arg) in pieces[1..].iter().zip(&args[1..]) {
+        out.push_str(&arg.to_string());
+        out.push_str(piece);
+    }
+    Ok(Value::string(vm, out))
+}
+
+// The math natives follow IEEE 754 like the arithmetic operators, so they don't error outside their domain:
+// `sqrt(-1)` and `log(-1)` are `NaN`, and `log(0)` is `-inf`. In strict math mode those are errors, like they are for
+// division: a `NaN` result is `NanResult`, and `log(0)` and `pow(0, n)` for negative `n` are `DivisionByZero`.
+
+/// A math native's result, which in [strict math](VM::set_strict_math) mode can't be `NaN`.
+fn math_result(vm: &VM, n: f64) -> Result<Value, RuntimeError> {
+    if vm.strict_math() && n.is_nan() {
+        return Err(vm.runtime_error(RuntimeErrorType::NanResult));
+    }
+    Ok(Value::Real(n))
+}
+
+/// `min(a, ...)`: the smallest of its arguments. `NaN`s are ignored unless every argument is `NaN`.
+fn min(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "min", args, 1, usize::MAX)?;
+    let mut min = args.get_real(0)?;
+    for i in 1..args.len() {
+        min = min.min(args.get_real(i)?);
+    }
+    Ok(Value::Real(min))
+}
+
+/// `max(a, ...)`: the largest of its arguments. `NaN`s are ignored unless every argument is `NaN`.
+fn max(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    check_arity(vm, "max", args, 1, usize::MAX)?;
+    let mut max = args.get_real(0)?;
+    for i in 1..args.len() {
+        max = max.max(args.get_real(i)?);
+    }
+    Ok(Value::Real(max))
+}
+
+native! {
+    /// `abs(n)`: `n` without its sign.
+    fn abs(_vm, n: f64) {
+        Ok(Value::Real(n.abs()))
+    }
+}
+
+native! {
+    /// `floor(n)`: the largest integer less than or equal to `n`.
+    fn floor(_vm, n: f64) {
+        Ok(Value::Real(n.floor()))
+    }
+}
+
+native! {
+    /// `ceil(n)`: the smallest integer greater than or equal to `n`.
+    fn ceil(_vm, n: f64) {
+        Ok(Value::Real(n.ceil()))
+    }
+}
+
+native! {
+    /// `round(n)`: the nearest integer to `n`, rounding halves away from zero.
+    fn round(_vm, n: f64) {
+        Ok(Value::Real(n.round()))
+    }
+}
+
+native! {
+    /// `sqrt(n)`: the square root of `n`.
+    fn sqrt(vm, n: f64) {
+        math_result(vm, n.sqrt())
+    }
+}
+
+native! {
+    /// `pow(base, exp)`: `base` raised to the power of `exp`.
+    fn pow(vm, base: f64, exp: f64) {
+        if vm.strict_math() && base == 0.0 && exp < 0.0 {
+            return Err(vm.runtime_error(RuntimeErrorType::DivisionByZero));
+        }
+        math_result(vm, base.powf(exp))
+    }
+}
+
+native! {
+    /// `sin(n)`: the sine of `n` radians.
+    fn sin(vm, n: f64) {
+        math_result(vm, n.sin())
+    }
+}
+
+native! {
+    /// `cos(n)`: the cosine of `n` radians.
+    fn cos(vm, n: f64) {
+        math_result(vm, n.cos())
+    }
+}
+
+native! {
+    /// `log(n)`: the natural logarithm of `n`.
+    fn log(vm, n: f64) {
+        if vm.strict_math() && n == 0.0 {
+            return Err(vm.runtime_error(RuntimeErrorType::DivisionByZero));
+        }
+        math_result(vm, n.ln())
+    }
+}
+
+native! {
+    /// `random()`: a random number from 0 up to but not including 1.
+    fn random(vm) {
+        Ok(Value::Real(vm.rng.next_f64()))
+    }
+}
+
+native! {
+    /// `random_int(lo, hi)`: a random integer from `lo` to `hi`, including both. Errors if there aren't any integers
+    /// between them.
+    fn random_int(vm, lo: f64, hi: f64) {
+        let (lo, hi) = (lo.ceil(), hi.floor());
+        if lo.is_nan() || hi.is_nan() || lo > hi {
+            return Err(vm.runtime_error(RuntimeErrorType::EmptyRange { lo, hi }));
+        }
+        let n = lo + (vm.rng.next_f64() * (hi - lo + 1.0)).floor();
+        // rounding can only push it up to the next integer, never past the range otherwise
+        Ok(Value::Real(n.min(hi)))
+    }
+}
+
+native! {
+    /// `seed(n)`: restarts `random` and `random_int`'s sequence, so a program seeded with the same number gets the
+    /// same random numbers every run. Unseeded VMs are seeded from the time they're created.
+    fn seed(vm, n: f64) {
+        vm.rng.seed(n.to_bits());
+        Ok(Value::Null)
+    }
+}
+
+native! {
+    /// `clock()`: seconds since the VM was created, for timing things. Never goes backwards.
+    fn clock(vm) {
+        Ok(Value::Real(vm.clock.monotonic()))
+    }
+}
+
+native! {
+    /// `now()`: seconds since the Unix epoch, by the wall clock.
+    fn now(vm) {
+        Ok(Value::Real(vm.clock.wall()))
+    }
+}
+
+native! {
+    /// `flush()`: writes out anything `print` has buffered, for when output has to appear before the program goes on.
+    fn flush(vm) {
+        vm.flush_output()?;
+        Ok(Value::Null)
+    }
+}
+
+/// Copies out a list's elements, so callbacks are free to modify the list while it's iterated.
+fn list_items(vm: &VM, value: &Value) -> Result<Vec<Value>, RuntimeError> {
+    if let Value::Obj(o) = value {
+        if let ObjType::List(l) = &o.kind {
+            return Ok(l.items.clone());
+        }
+    }
+    Err(vm.type_error(RuntimeType::List, TypeErrorType::ExpectedList))
+}
+
+/// Copies out an object's fields, like `list_items`.
+fn object_fields(vm: &VM, value: &Value) -> Result<Vec<(AnkokuString, Value)>, RuntimeError> {
+    if let Value::Obj(o) = value {
+        if let ObjType::Object(object) = &o.kind {
+            return Ok(object
+                .table
+                .entries()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect());
+        }
+    }
+    Err(vm.type_error(RuntimeType::Object, TypeErrorType::ExpectedObject))
+}
+
+fn list_push(list: &Value, item: Value) {
+    if let Value::Obj(mut o) = list {
+        if let ObjType::List(l) = &mut o.kind {
+            l.items.push(item);
+        }
+    }
+}
+
+fn clone_value(
+    vm: &mut VM,
+    value: &Value,
+    deep: bool,
+    copies: &mut FxHashMap<GcRef, GcRef>,
+) -> Result<Value, RuntimeError> {
+    let Value::Obj(o) = value else {
+        return Ok(value.clone());
+    };
+    let ObjType::Object(object) = &o.kind else {
+        return Ok(value.clone());
+    };
+    if let Some(copy) = copies.get(o) {
+        return Ok(Value::Obj(*copy));
+    }
+
+    // the copy is rooted on the stack while the copies of its fields are allocated
+    let mut copy = vm.alloc(Obj::new(ObjType::Object(Object::new())));
+    vm.stack_push(Value::Obj(copy));
+    copies.insert(*o, copy);
+    for (key, field) in object.table.entries() {
+        let field = if deep {
+            clone_value(vm, field, deep, copies)?
+        } else {
+            field.clone()
+        };
+        if let ObjType::Object(c) = &mut copy.kind {
+            c.table.set(key.clone(), field);
+        }
+    }
+    vm.stack_pop()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::Write, rc::Rc};
+
+    use super::Clock;
+    use crate::{
+        compiler::Compiler,
+        parser::{stmt::Stmt, tokenizer::Tokenizer},
+        vm::{
+            error::{RuntimeErrorType, TypeErrorType},
+            obj::{AnkokuString, ObjType},
+            value::Value,
+            InterpretResult, VM,
+        },
+    };
+
+    fn run(vm: &mut VM, source: &str) -> InterpretResult {
+        let tokens = Tokenizer::new(source)
+            .map(|v| v.unwrap())
+            .collect::<Vec<_>>();
+        let source = source.chars().collect::<Vec<_>>();
+        let (stmts, errors) = Stmt::parse(tokens, source.clone());
+        assert!(errors.is_empty(), "{:?}", errors);
+        let (chunk, errors) = Compiler::compile(&stmts, &source, vm);
+        assert!(errors.is_empty(), "{:?}", errors);
+        vm.interpret(chunk)
+    }
+
+    fn global(vm: &VM, name: &str) -> Value {
+        vm.globals
+            .get(&AnkokuString::new(name.into()))
+            .cloned()
+            .unwrap_or_else(|| panic!("no global named {}", name))
+    }
+
+    fn field(value: &Value, name: &str) -> Value {
+        if let Value::Obj(o) = value {
+            if let ObjType::Object(o) = &o.kind {
+                return o
+                    .table
+                    .get(&AnkokuString::new(name.into()))
+                    .unwrap()
+                    .clone();
+            }
+        }
+        panic!("not an object: {:?}", value)
+    }
+
+    #[test]
+    fn clone_is_independent() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(&mut vm, "var a = { x = 1 }; var b = clone(a);"),
+            InterpretResult::Ok
+        );
+        let a = global(&vm, "a");
+        let b = global(&vm, "b");
+        assert_ne!(a, b);
+
+        if let Value::Obj(mut o) = b {
+            if let ObjType::Object(o) = &mut o.kind {
+                o.table.set(AnkokuString::new("x".into()), Value::Real(2.0));
+            }
+        }
+        assert_eq!(field(&a, "x"), Value::Real(1.0));
+        assert_eq!(field(&global(&vm, "b"), "x"), Value::Real(2.0));
+    }
+
+    #[test]
+    fn clone_shallow_and_deep() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(
+                &mut vm,
+                "var a = { inner = { x = 1 } }; var s = clone(a); var d = clone(a, true);"
+            ),
+            InterpretResult::Ok
+        );
+        let inner = field(&global(&vm, "a"), "inner");
+        assert_eq!(field(&global(&vm, "s"), "inner"), inner);
+
+        let deep_inner = field(&global(&vm, "d"), "inner");
+        assert_ne!(deep_inner, inner);
+        assert_eq!(field(&deep_inner, "x"), Value::Real(1.0));
+    }
+
+    #[test]
+    fn deep_clone_survives_collection() {
+        let mut vm = VM::new();
+        vm.define_native_closure("gc", |vm, _| {
+            vm.collect();
+            Ok(Value::Null)
+        });
+        // under `gc-debug-super-slow` every copy the clone allocates collects first
+        assert_eq!(
+            run(
+                &mut vm,
+                "var d = clone({ p = { x = 1 }, q = { y = 2 }, r = { z = 3 } }, true); gc();"
+            ),
+            InterpretResult::Ok
+        );
+        let d = global(&vm, "d");
+        assert_eq!(field(&field(&d, "p"), "x"), Value::Real(1.0));
+        assert_eq!(field(&field(&d, "q"), "y"), Value::Real(2.0));
+        assert_eq!(field(&field(&d, "r"), "z"), Value::Real(3.0));
+    }
+
+    #[test]
+    fn clone_arity() {
+        let mut vm = VM::new();
+        assert_eq!(run(&mut vm, "clone();"), InterpretResult::RuntimeError);
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::ArityMismatch { got: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn merge() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(
+                &mut vm,
+                "var a = { x = 1, y = 2 }; var b = { y = 3, z = 4 }; var m = merge(a, b); var c = merge(a);"
+            ),
+            InterpretResult::Ok
+        );
+        let m = global(&vm, "m");
+        assert_eq!(field(&m, "x"), Value::Real(1.0));
+        assert_eq!(field(&m, "y"), Value::Real(3.0));
+        assert_eq!(field(&m, "z"), Value::Real(4.0));
+        assert_eq!(field(&global(&vm, "a"), "y"), Value::Real(2.0));
+        assert_eq!(field(&global(&vm, "b"), "y"), Value::Real(3.0));
+        assert_ne!(global(&vm, "c"), global(&vm, "a"));
+
+        assert_eq!(run(&mut vm, "merge(a, 1);"), InterpretResult::RuntimeError);
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::TypeError {
+                kind: TypeErrorType::ExpectedObject,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn keys_values_entries() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(
+                &mut vm,
+                "var o = { a = 1, b = \"two\", c = true };
+                var k = keys(o); var v = values(o); var e = entries(o); var none = keys({});"
+            ),
+            InterpretResult::Ok
+        );
+        let list = |name| match global(&vm, name) {
+            Value::Obj(o) => match &o.kind {
+                ObjType::List(l) => l.items.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                _ => panic!("{} isn't a list", name),
+            },
+            _ => panic!("{} isn't a list", name),
+        };
+        let (k, v, e) = (list("k"), list("v"), list("e"));
+        let mut sorted = k.clone();
+        sorted.sort();
+        assert_eq!(sorted, ["a", "b", "c"]);
+        // all in the same order, with strings quoted inside the pairs
+        for i in 0..3 {
+            let (value, in_pair) = match k[i].as_str() {
+                "a" => ("1", "1"),
+                "b" => ("two", "\"two\""),
+                _ => ("true", "true"),
+            };
+            assert_eq!(v[i], value);
+            assert_eq!(e[i], format!("[\"{}\", {}]", k[i], in_pair));
+        }
+        assert!(list("none").is_empty());
+
+        assert_eq!(run(&mut vm, "keys([1]);"), InterpretResult::RuntimeError);
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::TypeError {
+                kind: TypeErrorType::ExpectedObject,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn defined() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(
+                &mut vm,
+                "var a = 1; var b = defined(\"clone\"); var c = defined(\"nope\"); var d = defined(\"a\");"
+            ),
+            InterpretResult::Ok
+        );
+        assert_eq!(global(&vm, "b"), Value::Bool(true));
+        assert_eq!(global(&vm, "c"), Value::Bool(false));
+        assert_eq!(global(&vm, "d"), Value::Bool(true));
+
+        assert_eq!(run(&mut vm, "defined(1);"), InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn sort() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(
+                &mut vm,
+                "var a = sort([3, 1, 2]); var b = sort([\"b\", \"a\"]); var c = sort([]);"
+            ),
+            InterpretResult::Ok
+        );
+        assert_eq!(global(&vm, "a").to_string(), "[1, 2, 3]");
+        assert_eq!(global(&vm, "b").to_string(), "[\"a\", \"b\"]");
+        assert_eq!(global(&vm, "c").to_string(), "[]");
+
+        assert_eq!(
+            run(&mut vm, "sort([1, \"a\"]);"),
+            InterpretResult::RuntimeError
+        );
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::TypeError {
+                kind: TypeErrorType::NotOrderable,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn sort_with_comparator() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(
+                &mut vm,
+                "var down = sort([3, 1, 2], fn(a, b) { return b - a; });
+                var pairs = sort([[2, \"a\"], [1, \"b\"], [2, \"c\"], [1, \"d\"]], fn(a, b) { return a[0] - b[0]; });
+                var list = [2, 1]; var dropped = sort(list, fn(a, b) { list = []; return a - b; });"
+            ),
+            InterpretResult::Ok
+        );
+        assert_eq!(global(&vm, "down").to_string(), "[3, 2, 1]");
+        assert_eq!(
+            global(&vm, "pairs").to_string(),
+            "[[1, \"b\"], [1, \"d\"], [2, \"a\"], [2, \"c\"]]"
+        );
+        assert_eq!(global(&vm, "dropped").to_string(), "[1, 2]");
+
+        assert_eq!(
+            run(&mut vm, "sort([1, 2], fn(a, b) { return \"no\"; });"),
+            InterpretResult::RuntimeError
+        );
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::TypeError {
+                kind: TypeErrorType::ExpectedNumber,
+                ..
+            }
+        ));
+        assert_eq!(
+            run(&mut vm, "sort([1, 2], fn(a, b) { return nope; });"),
+            InterpretResult::RuntimeError
+        );
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::UndefinedVariable { .. }
+        ));
+    }
+
+    #[test]
+    fn map_filter_reduce() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(
+                &mut vm,
+                "var doubled = map([1, 2, 3], fn(x) { return x * 2; });
+                var big = filter([1, 2, 3], fn(x) { return x > 1; });
+                var sum = reduce([1, 2, 3, 4], fn(acc, x) { return acc + x; }, 0);"
+            ),
+            InterpretResult::Ok
+        );
+        assert_eq!(global(&vm, "doubled").to_string(), "[2, 4, 6]");
+        assert_eq!(global(&vm, "big").to_string(), "[2, 3]");
+        assert_eq!(global(&vm, "sum"), Value::Real(10.0));
+    }
+
+    #[test]
+    fn callback_results_survive_collection() {
+        let mut vm = VM::new();
+        vm.define_native_closure("gc", |vm, _| {
+            vm.collect();
+            Ok(Value::Null)
+        });
+        assert_eq!(
+            run(
+                &mut vm,
+                "var mapped = map([1, 2, 3, 4], fn(x) { var l = [x, \"s\"]; gc(); return l; });
+                var kept = filter(map([1, 2, 3], fn(x) { return [x]; }), fn(l) { gc(); return l[0] != 2; });"
+            ),
+            InterpretResult::Ok
+        );
+        assert_eq!(
+            global(&vm, "mapped").to_string(),
+            r#"[[1, "s"], [2, "s"], [3, "s"], [4, "s"]]"#
+        );
+        assert_eq!(global(&vm, "kept").to_string(), "[[1], [3]]");
+    }
+
+    #[test]
+    fn callback_errors() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(&mut vm, "map([1], fn(x) { return nope; });"),
+            InterpretResult::RuntimeError
+        );
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::UndefinedVariable { .. }
+        ));
+
+        assert_eq!(
+            run(&mut vm, "map([1], fn(a, b) { return a; });"),
+            InterpretResult::RuntimeError
+        );
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::ArityMismatch { got: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn bytes() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(
+                &mut vm,
+                "var b = bytes(\"hi\"); var h = b[0]; var n = len(b); var s = string(bytes([104, 105]));"
+            ),
+            InterpretResult::Ok
+        );
+        assert_eq!(global(&vm, "b").to_string(), "b\"hi\"");
+        assert_eq!(global(&vm, "h"), Value::Real(104.0));
+        assert_eq!(global(&vm, "n"), Value::Real(2.0));
+        assert_eq!(global(&vm, "s").to_string(), "hi");
+
+        assert_eq!(
+            run(&mut vm, "string(bytes([104, 255]));"),
+            InterpretResult::RuntimeError
+        );
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::InvalidUtf8 { valid_up_to: 1 }
+        ));
+
+        assert_eq!(run(&mut vm, "bytes([256]);"), InterpretResult::RuntimeError);
+        assert_eq!(
+            run(&mut vm, "bytes(\"a\")[1];"),
+            InterpretResult::RuntimeError
+        );
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::IndexOutOfBounds { len: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn function_introspection() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(
+                &mut vm,
+                "var add = fn(a, b) { return a + b; };
+                var n = arity(add); var s = name(add);
+                var anon = name(fn() {}); var native = name(len); var native_arity = arity(len);"
+            ),
+            InterpretResult::Ok
+        );
+        assert_eq!(global(&vm, "n"), Value::Real(2.0));
+        assert_eq!(global(&vm, "s").to_string(), "add");
+        assert_eq!(global(&vm, "add").to_string(), "<fn add>");
+        assert_eq!(global(&vm, "anon"), Value::Null);
+        assert_eq!(global(&vm, "native").to_string(), "len");
+        assert_eq!(global(&vm, "native_arity"), Value::Null);
+
+        assert_eq!(run(&mut vm, "arity(1);"), InterpretResult::RuntimeError);
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::TypeError {
+                kind: TypeErrorType::ExpectedFunction,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn strings() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(
+                &mut vm,
+                "var t = trim(\"  hi \\n\"); var u = upper(\"aBc\"); var l = lower(\"aBc\");
+                var parts = split(\"a,b,c\", \",\"); var chars = split(\"ab\", \"\");
+                var r = replace(\"a-b-c\", \"-\", \"+\");
+                var yes = contains(\"hello\", \"ell\"); var no = contains(\"hello\", \"z\");"
+            ),
+            InterpretResult::Ok
+        );
+        assert_eq!(global(&vm, "t").to_string(), "hi");
+        assert_eq!(global(&vm, "u").to_string(), "ABC");
+        assert_eq!(global(&vm, "l").to_string(), "abc");
+        assert_eq!(global(&vm, "parts").to_string(), "[\"a\", \"b\", \"c\"]");
+        assert_eq!(global(&vm, "chars").to_string(), "[\"a\", \"b\"]");
+        assert_eq!(global(&vm, "r").to_string(), "a+b+c");
+        assert_eq!(global(&vm, "yes"), Value::Bool(true));
+        assert_eq!(global(&vm, "no"), Value::Bool(false));
+
+        for source in [
+            "trim(1);",
+            "upper([]);",
+            "lower(true);",
+            "split(\"a\", 1);",
+            "replace(\"a\", \"a\", 2);",
+            "contains(1, \"a\");",
+        ] {
+            assert_eq!(
+                run(&mut vm, source),
+                InterpretResult::RuntimeError,
+                "{}",
+                source
+            );
+            assert!(matches!(
+                vm.last_error().unwrap().kind,
+                RuntimeErrorType::TypeError {
+                    kind: TypeErrorType::ExpectedString,
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn numbers_and_formatting() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(
+                &mut vm,
+                "var a = parse_number(\"1.5e3\"); var b = parse_number(\" -12 \");
+                var c = parse_number(\"nope\"); var d = parse_number(\"1.\");
+                var s = to_string([1, true]); var f = format(\"{} + {}\", 1, 2);"
+            ),
+            InterpretResult::Ok
+        );
+        assert_eq!(global(&vm, "a"), Value::Real(1500.0));
+        assert_eq!(global(&vm, "b"), Value::Real(-12.0));
+        assert_eq!(global(&vm, "c"), Value::Null);
+        assert_eq!(global(&vm, "d"), Value::Null);
+        assert_eq!(global(&vm, "s").to_string(), "[1, true]");
+        assert_eq!(global(&vm, "f").to_string(), "1 + 2");
+
+        assert_eq!(
+            run(&mut vm, "format(\"{} {}\", 1);"),
+            InterpretResult::RuntimeError
+        );
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::ArityMismatch {
+                min: 3,
+                max: 3,
+                got: 2,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn math() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(
+                &mut vm,
+                "var lo = min(3, -1, 2); var hi = max(3, -1, 2); var one = min(5);
+                var a = abs(-2.5); var f = floor(-1.5); var c = ceil(1.2); var r = round(2.5);
+                var s = sqrt(9); var p = pow(2, 0.5); var z = sin(0) + cos(0); var l = log(1);
+                var bad = sqrt(-1); var inf = log(0);"
+            ),
+            InterpretResult::Ok
+        );
+        for (name, expected) in [
+            ("lo", -1.0),
+            ("hi", 3.0),
+            ("one", 5.0),
+            ("a", 2.5),
+            ("f", -2.0),
+            ("c", 2.0),
+            ("r", 3.0),
+            ("s", 3.0),
+            ("p", 2f64.sqrt()),
+            ("z", 1.0),
+            ("l", 0.0),
+            ("inf", f64::NEG_INFINITY),
+        ] {
+            assert_eq!(global(&vm, name), Value::Real(expected), "{}", name);
+        }
+        assert!(matches!(global(&vm, "bad"), Value::Real(n) if n.is_nan()));
+
+        for source in [
+            "min(1, \"2\");",
+            "max(null);",
+            "abs(true);",
+            "sqrt(\"4\");",
+            "pow(2, [1]);",
+        ] {
+            assert_eq!(
+                run(&mut vm, source),
+                InterpretResult::RuntimeError,
+                "{}",
+                source
+            );
+            assert!(matches!(
+                vm.last_error().unwrap().kind,
+                RuntimeErrorType::TypeError {
+                    kind: TypeErrorType::ExpectedNumber,
+                    ..
+                }
+            ));
+        }
+        assert_eq!(run(&mut vm, "max();"), InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn strict_math() {
+        let mut vm = VM::new();
+        vm.set_strict_math(true);
+        for (source, expected) in [
+            ("sqrt(-1);", RuntimeErrorType::NanResult),
+            ("log(-1);", RuntimeErrorType::NanResult),
+            ("pow(-8, 1 / 3);", RuntimeErrorType::NanResult),
+            ("log(0);", RuntimeErrorType::DivisionByZero),
+            ("pow(0, -1);", RuntimeErrorType::DivisionByZero),
+        ] {
+            assert_eq!(
+                run(&mut vm, source),
+                InterpretResult::RuntimeError,
+                "{}",
+                source
+            );
+            assert_eq!(
+                std::mem::discriminant(&vm.last_error().unwrap().kind),
+                std::mem::discriminant(&expected),
+                "{}",
+                source
+            );
+        }
+        assert_eq!(
+            run(
+                &mut vm,
+                "var s = sqrt(4); var l = log(1); var p = pow(0, 2);"
+            ),
+            InterpretResult::Ok
+        );
+        assert_eq!(global(&vm, "s"), Value::Real(2.0));
+        assert_eq!(global(&vm, "l"), Value::Real(0.0));
+        assert_eq!(global(&vm, "p"), Value::Real(0.0));
+    }
+
+    #[test]
+    fn random() {
+        let sequence = |vm: &mut VM| {
+            assert_eq!(
+                run(
+                    vm,
+                    "seed(42); var a = random(); var b = random(); var c = random_int(1, 100);"
+                ),
+                InterpretResult::Ok
+            );
+            ["a", "b", "c"].map(|name| global(vm, name))
+        };
+        let mut vm = VM::new();
+        let first = sequence(&mut vm);
+        assert_eq!(sequence(&mut vm), first);
+        assert_eq!(sequence(&mut VM::new()), first);
+        assert_ne!(first[0], first[1]);
+        for n in &first[..2] {
+            assert!(
+                matches!(n, Value::Real(n) if (0.0..1.0).contains(n)),
+                "{:?}",
+                n
+            );
+        }
+
+        assert_eq!(
+            run(
+                &mut vm,
+                "var seen = {}; var ok = true; var i = 0;
+                while (i < 1000) {
+                    var n = random_int(-2, 2);
+                    if (n < -2 || n > 2 || n != floor(n)) ok = false;
+                    i = i + 1;
+                }
+                var one = random_int(0.5, 1.5);"
+            ),
+            InterpretResult::Ok
+        );
+        assert_eq!(global(&vm, "ok"), Value::Bool(true));
+        assert_eq!(global(&vm, "one"), Value::Real(1.0));
+
+        assert_eq!(
+            run(&mut vm, "random_int(1.2, 1.8);"),
+            InterpretResult::RuntimeError
+        );
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::EmptyRange { .. }
+        ));
+    }
+
+    #[test]
+    fn clock() {
+        let mut vm = VM::new();
+        assert_eq!(
+            run(&mut vm, "var a = clock(); var b = clock(); var t = now();"),
+            InterpretResult::Ok
+        );
+        let (Value::Real(a), Value::Real(b)) = (global(&vm, "a"), global(&vm, "b")) else {
+            panic!("clock() isn't a number");
+        };
+        assert!(0.0 <= a && a <= b, "{} then {}", a, b);
+        // some time after this test was written
+        assert!(matches!(global(&vm, "t"), Value::Real(t) if t > 1.6e9));
+
+        struct Stopped;
+        impl Clock for Stopped {
+            fn monotonic(&self) -> f64 {
+                1.5
+            }
+            fn wall(&self) -> f64 {
+                1e9
+            }
+        }
+        vm.set_clock(Box::new(Stopped));
+        assert_eq!(
+            run(&mut vm, "var a = clock(); var t = now();"),
+            InterpretResult::Ok
+        );
+        assert_eq!(global(&vm, "a"), Value::Real(1.5));
+        assert_eq!(global(&vm, "t"), Value::Real(1e9));
+    }
+
+    #[test]
+    fn native_closures() {
+        let mut vm = VM::new();
+        let mut count = 0;
+        vm.define_native_closure("counter", move |vm, _| {
+            count += 1;
+            Ok(Value::string(vm, format!("called {} times", count)))
+        });
+        assert_eq!(
+            run(&mut vm, "var a = counter(); var b = counter();"),
+            InterpretResult::Ok
+        );
+        assert_eq!(global(&vm, "a").to_string(), "called 1 times");
+        assert_eq!(global(&vm, "b").to_string(), "called 2 times");
+
+        vm.define_native_closure("apply", |vm, args| vm.call(args[0].clone(), &[]));
+        assert_eq!(
+            run(&mut vm, "apply(fn() { apply(fn() {}); });"),
+            InterpretResult::RuntimeError
+        );
+        assert!(matches!(
+            &vm.last_error().unwrap().kind,
+            RuntimeErrorType::NativeReentered { name } if name == "apply"
+        ));
+    }
+
+    native! {
+        fn pow(_vm, base: f64, exp: f64) {
+            Ok(Value::Real(base.powf(exp)))
+        }
+    }
+
+    #[test]
+    fn native_macro() {
+        let mut vm = VM::new();
+        vm.define_native("pow", pow);
+        assert_eq!(run(&mut vm, "var p = pow(2, 10);"), InterpretResult::Ok);
+        assert_eq!(global(&vm, "p"), Value::Real(1024.0));
+
+        assert_eq!(run(&mut vm, "pow(2);"), InterpretResult::RuntimeError);
+        assert!(matches!(
+            &vm.last_error().unwrap().kind,
+            RuntimeErrorType::ArityMismatch { name, min: 2, max: 2, got: 1 } if name == "pow"
+        ));
+
+        assert_eq!(
+            run(&mut vm, "pow(2, \"x\");"),
+            InterpretResult::RuntimeError
+        );
+        assert!(matches!(
+            vm.last_error().unwrap().kind,
+            RuntimeErrorType::TypeError {
+                kind: TypeErrorType::ExpectedNumber,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn flush() {
+        /// Keeps a copy of everything written so far each time it's flushed.
+        #[derive(Clone, Default)]
+        struct Snapshots(Rc<RefCell<(Vec<u8>, Vec<String>)>>);
+        impl Write for Snapshots {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().0.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                let mut inner = self.0.borrow_mut();
+                let contents = String::from_utf8(inner.0.clone()).unwrap();
+                inner.1.push(contents);
+                Ok(())
+            }
+        }
+
+        let mut vm = VM::new();
+        let output = Snapshots::default();
+        vm.set_output(Box::new(output.clone()));
+        assert_eq!(
+            run(&mut vm, "print 1; var a = flush(); print 2;"),
+            InterpretResult::Ok
+        );
+        // once for flush(), and once when the program finished
+        assert_eq!(output.0.borrow().1, vec!["1\n", "1\n2\n"]);
+        assert_eq!(global(&vm, "a"), Value::Null);
+    }
+}
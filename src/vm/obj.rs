@@ -1,8 +1,14 @@
-use std::{fmt::Debug, hash::Hasher, ptr::NonNull};
+use std::{
+    cell::RefCell,
+    fmt::{Debug, Display},
+    hash::Hasher,
+    ptr::NonNull,
+    rc::Rc,
+};
 
 use crate::util::fxhash::FxHasher;
 
-use super::table::HashTable;
+use super::{chunk::Chunk, native::Native, table::HashTable, value::Value, GcRef};
 
 #[derive(Clone, PartialEq)]
 pub struct Obj {
@@ -20,15 +26,85 @@ impl Obj {
         }
     }
 }
+/// How deeply objects and lists nest inside each other before formatting them stops and shows `...` for the rest.
+/// Formatting recurses, so without a limit a deep enough value would overflow the stack.
+pub(crate) const MAX_FORMAT_DEPTH: usize = 128;
+
+thread_local! {
+    /// The objects and lists being formatted on this thread, outermost first.
+    static FORMATTING: RefCell<Vec<*const Obj>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Marks an object or list as being formatted until dropped.
+struct Formatting;
+
+impl Formatting {
+    /// Otherwise what to show instead: `<cycle>` if `obj` is already being formatted, i.e. it contains itself, or `...`
+    /// if it's nested too deep, see [`MAX_FORMAT_DEPTH`].
+    fn enter(obj: &Obj) -> Result<Self, &'static str> {
+        let ptr = obj as *const Obj;
+        FORMATTING.with(|formatting| {
+            let mut formatting = formatting.borrow_mut();
+            if formatting.contains(&ptr) {
+                return Err("<cycle>");
+            }
+            if formatting.len() >= MAX_FORMAT_DEPTH {
+                return Err("...");
+            }
+            formatting.push(ptr);
+            Ok(Formatting)
+        })
+    }
+}
+impl Drop for Formatting {
+    fn drop(&mut self) {
+        FORMATTING.with(|formatting| formatting.borrow_mut().pop());
+    }
+}
+
+/// Objects and lists that contain themselves show up as `<cycle>` inside themselves, and ones nested more than 128
+/// levels deep as `...`, in both `Debug` and `Display`.
 impl Debug for Obj {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self.kind)
+        match &self.kind {
+            ObjType::Object(_) | ObjType::List(_) => match Formatting::enter(self) {
+                Ok(_formatting) => write!(f, "{:?}", self.kind),
+                Err(instead) => f.write_str(instead),
+            },
+            _ => write!(f, "{:?}", self.kind),
+        }
+    }
+}
+impl Display for Obj {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            ObjType::String(s) => write!(f, "{}", s.as_str()),
+            ObjType::Object(o) => match Formatting::enter(self) {
+                Ok(_formatting) => write!(f, "{}", o),
+                Err(instead) => f.write_str(instead),
+            },
+            ObjType::List(l) => match Formatting::enter(self) {
+                Ok(_formatting) => write!(f, "{}", l),
+                Err(instead) => f.write_str(instead),
+            },
+            ObjType::Native(n) => write!(f, "{:?}", n),
+            ObjType::Function(func) => write!(f, "{:?}", func),
+            ObjType::Closure(c) => write!(f, "{}", *c.function),
+            ObjType::Upvalue(_) => write!(f, "<upvalue>"),
+            ObjType::Bytes(b) => {
+                write!(f, "b\"")?;
+                for byte in b {
+                    write!(f, "{}", std::ascii::escape_default(*byte))?;
+                }
+                write!(f, "\"")
+            }
+        }
     }
 }
 impl Drop for Obj {
     fn drop(&mut self) {
         #[cfg(feature = "gc-debug-super-slow")]
-        println!("{:?} dropped", self);
+        println!("{:p} dropped {}", self, self.kind.name());
     }
 }
 
@@ -42,6 +118,33 @@ impl From<AnkokuString> for Obj {
 pub enum ObjType {
     String(AnkokuString),
     Object(Object),
+    List(List),
+    Native(Native),
+    Function(Function),
+    /// A function along with the variables it captured from enclosing functions.
+    Closure(Closure),
+    /// A variable captured by a closure. Only closures refer to these, scripts never see them.
+    Upvalue(Upvalue),
+    /// Binary data. Unlike strings, this doesn't have to be valid UTF-8.
+    Bytes(Vec<u8>),
+}
+
+impl ObjType {
+    /// Just the kind of object, for the GC's debug output. Objects are freed while others may still point at them, so
+    /// that output can't format their contents.
+    #[cfg(feature = "gc-debug-super-slow")]
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            ObjType::String(_) => "String",
+            ObjType::Object(_) => "Object",
+            ObjType::List(_) => "List",
+            ObjType::Native(_) => "Native",
+            ObjType::Function(_) => "Function",
+            ObjType::Closure(_) => "Closure",
+            ObjType::Upvalue(_) => "Upvalue",
+            ObjType::Bytes(_) => "Bytes",
+        }
+    }
 }
 
 /// Not an [Obj], an [Object]. Objects are a language feature, basically a hashtable, but [Obj]s are a VM implementation of heap-allocated objects.
@@ -54,12 +157,43 @@ impl Debug for Object {
         self.table.fmt(f)
     }
 }
+/// Renders as `{ a = 1, b = "two" }`. Strings nested inside objects are quoted so they can't be confused with
+/// other values.
+impl Display for Object {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.table.is_empty() {
+            return write!(f, "{{}}");
+        }
+        write!(f, "{{ ")?;
+        for (i, (key, value)) in self.table.entries().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} = ", key.as_str())?;
+            match value {
+                Value::Obj(o) => match &o.kind {
+                    ObjType::String(s) => write!(f, "{:?}", s)?,
+                    _ => write!(f, "{}", value)?,
+                },
+                _ => write!(f, "{}", value)?,
+            }
+        }
+        write!(f, " }}")
+    }
+}
 impl Object {
     pub fn new() -> Self {
         Self {
             table: HashTable::new(),
         }
     }
+
+    /// An object with room for `fields` fields before its table has to grow.
+    pub fn with_capacity(fields: usize) -> Self {
+        Self {
+            table: HashTable::with_capacity(fields),
+        }
+    }
 }
 
 impl Default for Object {
@@ -68,25 +202,138 @@ impl Default for Object {
     }
 }
 
+/// An ordered list of values. Iterates in insertion order, unlike [Object].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct List {
+    pub items: Vec<Value>,
+}
+impl List {
+    pub fn new(items: Vec<Value>) -> Self {
+        Self { items }
+    }
+}
+/// Renders as `[1, "two"]`, quoting strings like [Object] does.
+impl Display for List {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[")?;
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match item {
+                Value::Obj(o) => match &o.kind {
+                    ObjType::String(s) => write!(f, "{:?}", s)?,
+                    _ => write!(f, "{}", item)?,
+                },
+                _ => write!(f, "{}", item)?,
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+/// A function defined in a script. The chunk is shared between every copy of the function.
+#[derive(Clone, PartialEq)]
+pub struct Function {
+    pub name: Option<String>,
+    pub arity: u8,
+    /// How many variables closures of this function capture. Functions that don't capture anything are called
+    /// directly, without a closure.
+    pub upvalue_count: u8,
+    pub chunk: Rc<Chunk>,
+}
+impl Function {
+    pub fn new(name: Option<String>, arity: u8, chunk: Chunk) -> Self {
+        Self {
+            name,
+            arity,
+            upvalue_count: 0,
+            chunk: Rc::new(chunk),
+        }
+    }
+}
+impl Debug for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<fn>"),
+        }
+    }
+}
+
+/// A function that captures variables, created each time the function expression is evaluated.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Closure {
+    /// Always an [`ObjType::Function`].
+    pub function: GcRef,
+    /// Always [`ObjType::Upvalue`]s, in the order the function's chunk refers to them.
+    pub upvalues: Vec<GcRef>,
+}
+impl Closure {
+    pub fn function(&self) -> &Function {
+        match &self.function.kind {
+            ObjType::Function(f) => f,
+            _ => unreachable!("closures always wrap functions"),
+        }
+    }
+}
+
+/// A captured variable. While the variable is still on the stack the upvalue points at its slot, and once it goes
+/// out of scope the value is moved into the upvalue, so closures can keep using it.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+/// Strings up to this many bytes are stored inline, without a heap allocation of their own.
+const INLINE_CAPACITY: usize = 22;
+
 #[derive(Clone)]
 pub struct AnkokuString {
-    inner: String,
+    repr: Repr,
     hash: usize,
 }
 
+/// Most strings are short identifiers and literals, so they're kept inline and only longer ones get a `String`.
+#[derive(Clone)]
+enum Repr {
+    Inline {
+        len: u8,
+        bytes: [u8; INLINE_CAPACITY],
+    },
+    Heap(String),
+}
+
+impl Repr {
+    fn inline(s: &str) -> Option<Repr> {
+        if s.len() > INLINE_CAPACITY {
+            return None;
+        }
+        let mut bytes = [0; INLINE_CAPACITY];
+        bytes[..s.len()].copy_from_slice(s.as_bytes());
+        Some(Repr::Inline {
+            len: s.len() as u8,
+            bytes,
+        })
+    }
+}
+
 impl Debug for AnkokuString {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.inner.fmt(f)
+        Debug::fmt(self.as_str(), f)
     }
 }
 impl AnkokuString {
     pub fn new(str: String) -> Self {
         AnkokuString {
             hash: AnkokuString::hash_bytes(str.as_bytes()),
-            inner: str,
+            repr: Repr::inline(&str).unwrap_or(Repr::Heap(str)),
         }
     }
 
+    /// The string's FxHash. FxHash is unseeded, so a string has the same hash in every run, and tables with the same
+    /// keys set in the same order iterate in the same order every time.
     #[inline(always)]
     pub fn hash(&self) -> usize {
         self.hash
@@ -101,17 +348,40 @@ impl AnkokuString {
     }
 
     pub fn concat(&self, other: &str) -> AnkokuString {
-        let mut s = self.inner.clone();
+        let len = self.as_str().len() + other.len();
+        if len <= INLINE_CAPACITY {
+            let mut bytes = [0; INLINE_CAPACITY];
+            bytes[..self.as_str().len()].copy_from_slice(self.as_str().as_bytes());
+            bytes[self.as_str().len()..len].copy_from_slice(other.as_bytes());
+            return AnkokuString {
+                hash: AnkokuString::hash_bytes(&bytes[..len]),
+                repr: Repr::Inline {
+                    len: len as u8,
+                    bytes,
+                },
+            };
+        }
+        let mut s = String::with_capacity(len);
+        s.push_str(self.as_str());
         s.push_str(other);
         AnkokuString::new(s)
     }
 
     pub fn as_str(&self) -> &str {
-        &self.inner
+        match &self.repr {
+            // SAFETY: inline bytes are only ever copied from a `str`, and always up to `len`
+            Repr::Inline { len, bytes } => unsafe {
+                std::str::from_utf8_unchecked(&bytes[..*len as usize])
+            },
+            Repr::Heap(s) => s,
+        }
     }
 
     pub fn into_inner(self) -> String {
-        self.inner
+        match self.repr {
+            Repr::Inline { .. } => self.as_str().to_string(),
+            Repr::Heap(s) => s,
+        }
     }
 }
 impl PartialEq for AnkokuString {
@@ -120,3 +390,46 @@ impl PartialEq for AnkokuString {
     }
 }
 impl Eq for AnkokuString {}
+
+#[cfg(test)]
+mod tests {
+    use super::{AnkokuString, Repr};
+
+    #[test]
+    fn short_and_long_strings() {
+        let short = "identifier";
+        let long = "a string that's too long to be stored inline";
+        for s in [short, long, "", "日本語"] {
+            let string = AnkokuString::new(s.to_string());
+            assert_eq!(string.as_str(), s);
+            assert_eq!(format!("{:?}", string), format!("{:?}", s));
+            assert_eq!(string.hash(), AnkokuString::new(s.to_string()).hash());
+            assert_eq!(string.clone().into_inner(), s);
+        }
+        assert!(matches!(
+            AnkokuString::new(short.to_string()).repr,
+            Repr::Inline { .. }
+        ));
+        assert!(matches!(
+            AnkokuString::new(long.to_string()).repr,
+            Repr::Heap(_)
+        ));
+    }
+
+    #[test]
+    fn concat_across_inline_capacity() {
+        let a = AnkokuString::new("hello".to_string());
+        let short = a.concat(" world");
+        assert!(matches!(short.repr, Repr::Inline { .. }));
+        assert_eq!(short.as_str(), "hello world");
+        assert_eq!(short, AnkokuString::new("hello world".to_string()));
+
+        let long = short.concat(", and everyone else in it");
+        assert!(matches!(long.repr, Repr::Heap(_)));
+        assert_eq!(long.as_str(), "hello world, and everyone else in it");
+        assert_eq!(
+            long,
+            AnkokuString::new("hello world, and everyone else in it".to_string())
+        );
+    }
+}
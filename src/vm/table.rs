@@ -5,7 +5,10 @@ use super::{obj::AnkokuString, value::Value};
 #[derive(Clone, PartialEq)]
 pub struct HashTable {
     entries: Vec<Entry>,
+    /// Number of live entries.
     count: usize,
+    /// Number of deleted entries still occupying a slot. These count towards the load factor.
+    tombstones: usize,
 }
 const TABLE_MAX_LOAD: f32 = 0.75;
 impl Debug for HashTable {
@@ -22,9 +25,32 @@ impl HashTable {
         Self {
             entries: Vec::new(),
             count: 0,
+            tombstones: 0,
         }
     }
 
+    /// A table that can hold `capacity` entries before it has to grow.
+    pub fn with_capacity(capacity: usize) -> Self {
+        if capacity == 0 {
+            return Self::new();
+        }
+        // the same sizes it would grow through
+        let mut slots = 8;
+        while capacity as f32 > slots as f32 * TABLE_MAX_LOAD {
+            slots *= 2;
+        }
+        Self {
+            entries: vec![Entry::empty(); slots],
+            count: 0,
+            tombstones: 0,
+        }
+    }
+
+    /// How many slots the table has, including empty ones.
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
     pub fn values(&self) -> impl Iterator<Item = &Value> {
         self.entries.iter().filter_map(|v| {
             if v.key.is_some() {
@@ -35,6 +61,8 @@ impl HashTable {
         })
     }
 
+    /// The table's entries, in bucket order. That's the same for the same keys set (and removed) in the same order,
+    /// since keys' hashes don't change between runs, see [`AnkokuString::hash`].
     pub fn entries(&self) -> impl Iterator<Item = (&AnkokuString, &Value)> {
         self.entries
             .iter()
@@ -56,7 +84,7 @@ impl HashTable {
         loop {
             entry = &entries[index];
             if entry.key.is_none() {
-                if entry.value == Value::Null {
+                if !entry.tombstone {
                     // Empty entry.
                     return if let Some(t) = tombstone { t } else { index };
                 } else {
@@ -83,7 +111,7 @@ impl HashTable {
         }
     }
     pub fn set(&mut self, key: AnkokuString, value: Value) -> bool {
-        if (self.count + 1) as f32 > self.entries.len() as f32 * TABLE_MAX_LOAD {
+        if (self.count + self.tombstones + 1) as f32 > self.entries.len() as f32 * TABLE_MAX_LOAD {
             let capacity = if self.entries.len() < 8 {
                 8
             } else {
@@ -93,10 +121,7 @@ impl HashTable {
             let mut entries = Vec::with_capacity(capacity);
 
             for _ in 0..capacity {
-                entries.push(Entry {
-                    key: None,
-                    value: Value::Null,
-                });
+                entries.push(Entry::empty());
             }
 
             for i in 0..self.entries.len() {
@@ -111,6 +136,7 @@ impl HashTable {
             }
 
             self.entries = entries;
+            self.tombstones = 0;
         }
         let entry = HashTable::find_entry(&self.entries, key.hash());
         let is_new_key = self.entries[entry].key.is_none();
@@ -118,11 +144,24 @@ impl HashTable {
             self.count += 1;
         }
         let entry = &mut self.entries[entry];
+        if entry.tombstone {
+            entry.tombstone = false;
+            self.tombstones -= 1;
+        }
         entry.key = Some(key);
         entry.value = value;
         is_new_key
     }
 
+    /// Removes every entry while keeping the backing allocation around for reuse.
+    pub fn clear(&mut self) {
+        for entry in &mut self.entries {
+            *entry = Entry::empty();
+        }
+        self.count = 0;
+        self.tombstones = 0;
+    }
+
     pub fn add_all(&mut self, from: &HashTable) {
         for i in 0..from.entries.len() {
             let entry = &from.entries[i];
@@ -133,16 +172,26 @@ impl HashTable {
     }
 
     pub fn delete(&mut self, key: usize) -> bool {
+        self.remove_hash(key).is_some()
+    }
+
+    /// Removes `key` from the table, returning the value it mapped to if it was present.
+    pub fn remove(&mut self, key: &AnkokuString) -> Option<Value> {
+        self.remove_hash(key.hash())
+    }
+
+    fn remove_hash(&mut self, key: usize) -> Option<Value> {
         if self.count == 0 {
-            false
+            None
         } else {
             let entry = HashTable::find_entry(&self.entries, key);
-            if self.entries[entry].key.is_none() {
-                return false;
-            }
-            self.entries[entry].key = None;
-            self.entries[entry].value = Value::Bool(true);
-            true
+            let entry = &mut self.entries[entry];
+            entry.key.as_ref()?;
+            let removed = std::mem::replace(entry, Entry::empty());
+            entry.tombstone = true;
+            self.count -= 1;
+            self.tombstones += 1;
+            Some(removed.value)
         }
     }
 }
@@ -157,6 +206,18 @@ impl Default for HashTable {
 struct Entry {
     key: Option<AnkokuString>,
     value: Value,
+    /// Set on deleted entries so probing continues past them; `key` is `None` for both empty and deleted entries.
+    tombstone: bool,
+}
+
+impl Entry {
+    fn empty() -> Self {
+        Self {
+            key: None,
+            value: Value::Null,
+            tombstone: false,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -178,6 +239,76 @@ mod tests {
         assert_eq!(table.get(&key), Some(&thingy));
     }
 
+    #[test]
+    fn remove() {
+        let key = AnkokuString::new("hello_world".into());
+        let other = AnkokuString::new("other".into());
+
+        let mut table = HashTable::new();
+        assert_eq!(table.remove(&key), None);
+
+        table.set(key.clone(), Value::Real(1.0));
+        table.set(other.clone(), Value::Bool(true));
+        assert_eq!(table.len(), 2);
+
+        assert_eq!(table.remove(&key), Some(Value::Real(1.0)));
+        assert_eq!(table.remove(&key), None);
+        assert_eq!(table.get(&key), None);
+        assert_eq!(table.len(), 1);
+
+        // the removed slot is a tombstone, so entries after it are still found
+        assert_eq!(table.get(&other), Some(&Value::Bool(true)));
+        assert_eq!(table.remove(&other), Some(Value::Bool(true)));
+        assert!(table.is_empty());
+
+        assert!(table.set(key.clone(), Value::Real(2.0)));
+        assert_eq!(table.get(&key), Some(&Value::Real(2.0)));
+    }
+
+    #[test]
+    fn deterministic_order() {
+        let build = || {
+            let mut table = HashTable::new();
+            for i in 0..100 {
+                table.set(
+                    AnkokuString::new(format!("key{}", i)),
+                    Value::Real(i as f64),
+                );
+            }
+            for i in (0..100).step_by(3) {
+                table.remove(&AnkokuString::new(format!("key{}", i)));
+            }
+            table
+                .entries()
+                .map(|(k, v)| (k.as_str().to_string(), v.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(build(), build());
+
+        // a seeded hash would give this a different value in each run
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(
+            AnkokuString::new("hello".into()).hash(),
+            18099358241699475913
+        );
+    }
+
+    #[test]
+    fn with_capacity() {
+        let mut table = HashTable::with_capacity(20);
+        let capacity = table.capacity();
+        assert!(capacity >= 20);
+        for i in 0..20 {
+            table.set(
+                AnkokuString::new(format!("key{}", i)),
+                Value::Real(i as f64),
+            );
+        }
+        assert_eq!(table.capacity(), capacity);
+
+        assert_eq!(HashTable::with_capacity(0).capacity(), 0);
+    }
+
     #[test]
     fn stress_test() {
         let start = Instant::now();
@@ -1,9 +1,16 @@
 use std::{
-    fmt::Debug,
+    cmp::Ordering,
+    fmt::{Debug, Display},
     hash::{Hash, Hasher},
 };
 
-use super::{obj::ObjType, GcRef, VM};
+use crate::util::{error::json_string, fxhash::FxHashSet};
+
+use super::{
+    error::{RuntimeError, RuntimeErrorType, RuntimeType, TypeErrorType},
+    obj::{AnkokuString, ObjType, MAX_FORMAT_DEPTH},
+    GcRef, VM,
+};
 
 #[derive(Clone)]
 pub enum Value {
@@ -23,12 +30,31 @@ impl PartialEq for Value {
         }
     }
 }
+/// Orders numbers numerically and strings lexicographically by contents. Anything else, including `NaN` and values of
+/// different types, can't be ordered and gives `None`. This is what `<`, `>`, `<=`, `>=` and `sort` use.
+///
+/// Strings are ordered by contents but `==` on values compares objects by identity, so two different string objects
+/// with the same contents are `Some(Equal)` without being `==`. Use [`Value::deep_eq`] for the language's `==`.
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Real(l), Value::Real(r)) => l.partial_cmp(r),
+            (Value::Obj(l), Value::Obj(r)) => match (&l.kind, &r.kind) {
+                (ObjType::String(l), ObjType::String(r)) => Some(l.as_str().cmp(r.as_str())),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
 impl Hash for Value {
     fn hash<H: Hasher>(&self, state: &mut H) {
         let tag = core::mem::discriminant(self);
         Hash::hash(&tag, state);
         match self {
             Value::Bool(b) => Hash::hash(b, state),
+            // -0.0 == 0.0, so they have to hash the same
+            Value::Real(f) if *f == 0.0 => Hash::hash(&0.0f64.to_bits(), state),
             Value::Real(f) => Hash::hash(&f.to_bits(), state),
             Value::Obj(r) => Hash::hash(r, state),
             _ => {}
@@ -37,109 +63,355 @@ impl Hash for Value {
 }
 
 impl Value {
-    /// Try to convert this into a real (f64).
-    pub fn coerce_real(self) -> f64 {
+    /// Try to convert this into a real (f64). Booleans are `1` and `0`, and anything else isn't a number.
+    pub fn coerce_real(self) -> Option<f64> {
         match self {
-            Value::Bool(v) => {
-                if v {
-                    1.0
-                } else {
-                    0.0
-                }
-            }
-            Value::Real(v) => v,
-            _ => todo!("implement proper type errors here instead of panics"),
+            Value::Bool(v) => Some(if v { 1.0 } else { 0.0 }),
+            Value::Real(v) => Some(v),
+            _ => None,
         }
     }
 
-    pub fn coerce_bool(self) -> bool {
+    pub fn coerce_bool(self) -> Option<bool> {
         match self {
-            Value::Bool(v) => v,
-            Value::Real(v) => v != 0.0,
-            _ => todo!("implement proper type errors here instead of panics"),
+            Value::Bool(v) => Some(v),
+            Value::Real(v) => Some(v != 0.0),
+            _ => None,
         }
     }
 
-    pub fn coerce_str(self) -> String {
+    /// The text of a boolean, number or string. Other values aren't joined onto strings with `+`.
+    pub fn coerce_str(self) -> Option<String> {
         match self {
-            Value::Bool(v) => {
-                if v {
-                    "true".to_string()
-                } else {
-                    "false".to_string()
-                }
-            }
-            Value::Real(v) => v.to_string(),
+            Value::Bool(v) => Some(v.to_string()),
+            Value::Real(v) => Some(format_number(v)),
             Value::Obj(o) => match &o.inner().kind {
-                ObjType::String(v) => v.clone().into_inner(),
-                ObjType::Object(_) => todo!("typeerrors"),
+                ObjType::String(v) => Some(v.clone().into_inner()),
+                _ => None,
             },
-            _ => todo!("implement proper type errors here instead of panics"),
+            _ => None,
         }
     }
 
-    pub fn add(self, rhs: Value, gc: &VM) -> Value {
-        match self {
-            Value::Real(l) => (l + rhs.coerce_real()).into(),
+    pub fn add(self, rhs: Value, gc: &VM) -> Result<Value, RuntimeError> {
+        match &self {
+            Value::Real(l) => Ok((l + rhs.real_operand()?).into()),
             Value::Obj(gcref) => match &gcref.kind {
-                ObjType::String(self_string) => {
-                    Value::Obj(gc.alloc(self_string.concat(&rhs.coerce_str()).into()))
-                }
-                ObjType::Object(_) => todo!(),
+                ObjType::String(self_string) => match rhs.coerce_str() {
+                    Some(rhs) => Ok(Value::Obj(gc.alloc(self_string.concat(&rhs).into()))),
+                    None => Err(RuntimeError::type_error(
+                        RuntimeType::String,
+                        TypeErrorType::ExpectedString,
+                    )),
+                },
+                _ => Err(RuntimeError::type_error(
+                    RuntimeType::Number,
+                    TypeErrorType::ExpectedNumber,
+                )),
             },
-            _ => todo!("implement proper type errors here instead of panics"),
+            _ => Err(RuntimeError::type_error(
+                RuntimeType::Number,
+                TypeErrorType::ExpectedNumber,
+            )),
         }
     }
 
-    pub fn sub(self, rhs: Value, _gc: &VM) -> Value {
-        match self {
-            Value::Real(l) => (l - rhs.coerce_real()).into(),
-            _ => todo!("implement proper type errors here instead of panics"),
+    pub fn sub(self, rhs: Value, _gc: &VM) -> Result<Value, RuntimeError> {
+        Ok((self.number()? - rhs.real_operand()?).into())
+    }
+
+    pub fn mul(self, rhs: Value, _gc: &VM) -> Result<Value, RuntimeError> {
+        Ok((self.number()? * rhs.real_operand()?).into())
+    }
+    /// Divides numbers. In [strict math](VM::set_strict_math) mode dividing by zero is an error, rather than giving
+    /// an infinity or `NaN`.
+    pub fn div(self, rhs: Value, vm: &VM) -> Result<Value, RuntimeError> {
+        let (l, r) = (self.number()?, rhs.real_operand()?);
+        let n = l / r;
+        if vm.strict_math {
+            if n.is_nan() {
+                return Err(RuntimeError::new(RuntimeErrorType::NanResult));
+            }
+            if r == 0.0 {
+                return Err(RuntimeError::new(RuntimeErrorType::DivisionByZero));
+            }
         }
+        Ok(n.into())
     }
 
-    pub fn mul(self, rhs: Value, _gc: &VM) -> Value {
+    /// The value as an index into a list or bytes. It has to be a whole number, not `1.5` or `NaN`, which is a type
+    /// error, and can't be negative. Whether it's in bounds is up to whatever it indexes.
+    pub fn as_index(&self) -> Result<usize, RuntimeError> {
         match self {
-            Value::Real(l) => (l * rhs.coerce_real()).into(),
-            _ => todo!("implement proper type errors here instead of panics"),
+            Value::Real(n) if n.fract() == 0.0 && *n < 0.0 => {
+                Err(RuntimeError::new(RuntimeErrorType::NegativeIndex {
+                    index: *n,
+                }))
+            }
+            Value::Real(n) if n.fract() == 0.0 => Ok(*n as usize),
+            _ => Err(RuntimeError::type_error(
+                RuntimeType::Number,
+                TypeErrorType::IndexMustBeInteger,
+            )),
         }
     }
-    pub fn div(self, rhs: Value, _gc: &VM) -> Value {
+
+    /// The left operand of arithmetic, which has to be a number.
+    fn number(&self) -> Result<f64, RuntimeError> {
         match self {
-            Value::Real(l) => (l / rhs.coerce_real()).into(),
-            _ => todo!("implement proper type errors here instead of panics"),
+            Value::Real(n) => Ok(*n),
+            _ => Err(RuntimeError::type_error(
+                RuntimeType::Number,
+                TypeErrorType::ExpectedNumber,
+            )),
         }
     }
-    pub fn greater(self, rhs: Value, _gc: &VM) -> Value {
+
+    /// The right operand of arithmetic, which is coerced like `1 + true`.
+    fn real_operand(self) -> Result<f64, RuntimeError> {
+        self.coerce_real().ok_or_else(|| {
+            RuntimeError::type_error(RuntimeType::Number, TypeErrorType::ExpectedNumber)
+        })
+    }
+
+    // Comparisons follow IEEE 754: any comparison with `NaN` is false, including `NaN == NaN`, and only `!=` is true.
+    pub fn greater(self, rhs: Value, _gc: &VM) -> Result<Value, RuntimeError> {
+        self.ordered(&rhs, |o| o == Ordering::Greater)
+    }
+    pub fn less(self, rhs: Value, _gc: &VM) -> Result<Value, RuntimeError> {
+        self.ordered(&rhs, |o| o == Ordering::Less)
+    }
+    pub fn greater_equal(self, rhs: Value, _gc: &VM) -> Result<Value, RuntimeError> {
+        self.ordered(&rhs, |o| o != Ordering::Less)
+    }
+    pub fn less_equal(self, rhs: Value, _gc: &VM) -> Result<Value, RuntimeError> {
+        self.ordered(&rhs, |o| o != Ordering::Greater)
+    }
+
+    fn ordered(&self, rhs: &Value, test: fn(Ordering) -> bool) -> Result<Value, RuntimeError> {
         match (self, rhs) {
-            (Value::Real(l), Value::Real(r)) => (l > r).into(),
-            _ => todo!("implement proper type errors here instead of panics"),
+            // NaN has no ordering, so it fails every test
+            (Value::Real(l), Value::Real(r)) => Ok(l.partial_cmp(r).is_some_and(test).into()),
+            _ => match self.partial_cmp(rhs) {
+                Some(ordering) => Ok(test(ordering).into()),
+                None => Err(RuntimeError::type_error(
+                    RuntimeType::Number,
+                    TypeErrorType::NotOrderable,
+                )),
+            },
         }
     }
-    pub fn less(self, rhs: Value, _gc: &VM) -> Value {
-        match (self, rhs) {
-            (Value::Real(l), Value::Real(r)) => (l < r).into(),
-            _ => todo!("implement proper type errors here instead of panics"),
+
+    /// `==`. Numbers are equal if they're numerically equal, so `0 == -0` and `NaN` isn't equal to anything. Strings
+    /// and bytes are equal if they have the same contents, lists if their items are equal, and objects if they have the
+    /// same fields with equal values. Other objects, like functions, are only equal to themselves. Values of different
+    /// types are never equal.
+    ///
+    /// Nested objects are compared with a loop rather than recursion, so however deep they go this can't overflow the
+    /// stack. A pair of objects that comes round again in a cycle is taken to be equal, so the rest of their contents
+    /// decide.
+    pub fn deep_eq(&self, other: &Value) -> bool {
+        let mut pending = Vec::new();
+        let mut queued = FxHashSet::default();
+        if !self.queue_eq(other, &mut pending, &mut queued) {
+            return false;
+        }
+        while let Some((l, r)) = pending.pop() {
+            let equal = match (&l.kind, &r.kind) {
+                (ObjType::String(a), ObjType::String(b)) => a.as_str() == b.as_str(),
+                (ObjType::Bytes(a), ObjType::Bytes(b)) => a == b,
+                (ObjType::List(a), ObjType::List(b)) => {
+                    a.items.len() == b.items.len()
+                        && a.items
+                            .iter()
+                            .zip(&b.items)
+                            .all(|(a, b)| a.queue_eq(b, &mut pending, &mut queued))
+                }
+                (ObjType::Object(a), ObjType::Object(b)) => {
+                    a.table.len() == b.table.len()
+                        && a.table.entries().all(|(key, a)| {
+                            b.table
+                                .get(key)
+                                .is_some_and(|b| a.queue_eq(b, &mut pending, &mut queued))
+                        })
+                }
+                _ => false,
+            };
+            if !equal {
+                return false;
+            }
         }
+        true
     }
-    pub fn neg(self, _gc: &VM) -> Value {
-        match self {
-            Value::Real(l) => (-l).into(),
-            _ => todo!("implement proper type errors here instead of panics"),
+
+    /// `is`: whether two values are the same value. Objects are only identical to themselves, however alike their
+    /// contents are, and anything else is compared like `==`.
+    pub fn ref_eq(&self, other: &Value) -> bool {
+        self == other
+    }
+
+    /// Part of [`Value::deep_eq`]: values that aren't both objects are compared straight away. Two objects are equal
+    /// for now, and unless they're the same object or have been queued already, they're queued in `pending` to have
+    /// their contents compared.
+    fn queue_eq(
+        &self,
+        other: &Value,
+        pending: &mut Vec<(GcRef, GcRef)>,
+        queued: &mut FxHashSet<(GcRef, GcRef)>,
+    ) -> bool {
+        let (Value::Obj(l), Value::Obj(r)) = (self, other) else {
+            return self == other;
+        };
+        if l != r && queued.insert((*l, *r)) {
+            pending.push((*l, *r));
         }
+        true
+    }
+
+    pub fn neg(self, _gc: &VM) -> Result<Value, RuntimeError> {
+        Ok((-self.number()?).into())
     }
+    /// `!`, which works on any value: it's `true` if the value is [falsey](Value::falsey) and `false` otherwise.
     pub fn not(self, _gc: &VM) -> Value {
+        self.falsey().into()
+    }
+
+    /// Like `Display`, but with each field of an object and element of a list on its own line, indented by `indent`
+    /// spaces for each level of nesting. Empty objects and lists stay on one line, and an object or list that contains
+    /// itself shows up as `<cycle>` inside itself instead of going on forever. Like `Display`, anything nested more
+    /// than 128 levels deep shows up as `...`.
+    pub fn pretty(&self, indent: usize) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, indent, 0, &mut Vec::new());
+        out
+    }
+
+    /// `parents` are the objects and lists being written that this value is nested in.
+    fn write_pretty(
+        &self,
+        out: &mut String,
+        indent: usize,
+        depth: usize,
+        parents: &mut Vec<GcRef>,
+    ) {
+        let Value::Obj(o) = self else {
+            out.push_str(&self.to_string());
+            return;
+        };
+        // nested strings are quoted, like `Display` does
+        let nested = |value: &Value, out: &mut String, parents: &mut Vec<GcRef>| {
+            if let Value::Obj(o) = value {
+                if let ObjType::String(s) = &o.kind {
+                    out.push_str(&format!("{:?}", s));
+                    return;
+                }
+            }
+            value.write_pretty(out, indent, depth + 1, parents)
+        };
+        let pad = |out: &mut String, depth: usize| out.push_str(&" ".repeat(indent * depth));
+        match &o.kind {
+            ObjType::Object(_) | ObjType::List(_) if parents.contains(o) => out.push_str("<cycle>"),
+            ObjType::Object(_) | ObjType::List(_) if parents.len() >= MAX_FORMAT_DEPTH => {
+                out.push_str("...")
+            }
+            ObjType::Object(object) if !object.table.is_empty() => {
+                parents.push(*o);
+                out.push_str("{\n");
+                for (i, (key, value)) in object.table.entries().enumerate() {
+                    if i > 0 {
+                        out.push_str(",\n");
+                    }
+                    pad(out, depth + 1);
+                    out.push_str(key.as_str());
+                    out.push_str(" = ");
+                    nested(value, out, parents);
+                }
+                out.push('\n');
+                pad(out, depth);
+                out.push('}');
+                parents.pop();
+            }
+            ObjType::List(list) if !list.items.is_empty() => {
+                parents.push(*o);
+                out.push_str("[\n");
+                for (i, item) in list.items.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(",\n");
+                    }
+                    pad(out, depth + 1);
+                    nested(item, out, parents);
+                }
+                out.push('\n');
+                pad(out, depth);
+                out.push(']');
+                parents.pop();
+            }
+            _ => out.push_str(&self.to_string()),
+        }
+    }
+
+    /// The value as JSON, with objects as JSON objects and lists as arrays. `None` if it can't be represented in JSON:
+    /// if it is or contains a function, bytes, `NaN` or an infinity, or an object or list that contains itself or is
+    /// nested more than 128 levels deep.
+    pub fn to_json(&self) -> Option<String> {
+        let mut out = String::new();
+        self.write_json(&mut out, &mut Vec::new())?;
+        Some(out)
+    }
+
+    /// `parents` are the objects and lists being written that this value is nested in.
+    fn write_json(&self, out: &mut String, parents: &mut Vec<GcRef>) -> Option<()> {
         match self {
-            Value::Bool(l) => (!l).into(),
-            _ => todo!("implement proper type errors here instead of panics"),
+            Value::Null => out.push_str("null"),
+            Value::Bool(b) => out.push_str(&b.to_string()),
+            Value::Real(n) if n.is_finite() => out.push_str(&format_number(*n)),
+            Value::Real(_) => return None,
+            Value::Obj(o) => match &o.kind {
+                ObjType::String(s) => out.push_str(&json_string(s.as_str())),
+                ObjType::Object(_) | ObjType::List(_)
+                    if parents.contains(o) || parents.len() >= MAX_FORMAT_DEPTH =>
+                {
+                    return None
+                }
+                ObjType::Object(object) => {
+                    parents.push(*o);
+                    out.push('{');
+                    for (i, (key, value)) in object.table.entries().enumerate() {
+                        if i > 0 {
+                            out.push(',');
+                        }
+                        out.push_str(&json_string(key.as_str()));
+                        out.push(':');
+                        value.write_json(out, parents)?;
+                    }
+                    out.push('}');
+                    parents.pop();
+                }
+                ObjType::List(list) => {
+                    parents.push(*o);
+                    out.push('[');
+                    for (i, item) in list.items.iter().enumerate() {
+                        if i > 0 {
+                            out.push(',');
+                        }
+                        item.write_json(out, parents)?;
+                    }
+                    out.push(']');
+                    parents.pop();
+                }
+                _ => return None,
+            },
         }
+        Some(())
     }
 
+    /// Whether the value counts as false in conditions and for `!`. `false`, `null`, `0` and `""` are falsey, and
+    /// everything else (including `NaN`, empty objects and empty lists) is truthy.
     pub fn falsey(&self) -> bool {
         match self {
             Value::Bool(b) => !*b,
             Value::Null => true,
-            Value::Real(b) => *b != 0.0,
+            Value::Real(b) => *b == 0.0,
             Value::Obj(o) => {
                 if let ObjType::String(s) = &o.kind {
                     s.as_str().is_empty()
@@ -151,6 +423,15 @@ impl Value {
     }
 }
 
+/// How numbers are shown to users, by `print`, `to_string` and string concatenation. Anything else that turns numbers
+/// into text for users should go through this too, so they all agree.
+///
+/// Integers are shown without a fractional part (`4`, not `4.0`), and other numbers with as many digits as it takes
+/// to read them back exactly. There's no exponent notation, and the special values are `NaN`, `inf` and `-inf`.
+pub fn format_number(n: f64) -> String {
+    n.to_string()
+}
+
 impl Debug for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Value(")?;
@@ -163,6 +444,17 @@ impl Debug for Value {
         write!(f, ")")
     }
 }
+/// The user-facing format, used by `print`.
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Null => write!(f, "null"),
+            Self::Real(n) => write!(f, "{}", format_number(*n)),
+            Self::Obj(a) => write!(f, "{}", a.inner()),
+        }
+    }
+}
 impl From<f64> for Value {
     fn from(v: f64) -> Self {
         Value::Real(v)
@@ -174,3 +466,240 @@ impl From<bool> for Value {
         Value::Bool(v)
     }
 }
+
+impl From<i32> for Value {
+    fn from(v: i32) -> Self {
+        Value::Real(v as f64)
+    }
+}
+
+/// Integers beyond 2^53 lose precision, since numbers are all `f64`s.
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Real(v as f64)
+    }
+}
+
+/// Integers beyond 2^53 lose precision, since numbers are all `f64`s.
+impl From<usize> for Value {
+    fn from(v: usize) -> Self {
+        Value::Real(v as f64)
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Value::Null
+    }
+}
+
+impl Value {
+    pub fn null() -> Self {
+        Value::Null
+    }
+
+    /// Allocates a string. There's no `From<&str>` or `From<String>` for `Value`, because strings live on the VM's
+    /// heap and creating one needs the VM.
+    pub fn string<S: Into<String>>(vm: &VM, s: S) -> Self {
+        Value::Obj(vm.alloc(AnkokuString::new(s.into()).into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cmp::Ordering,
+        hash::{Hash, Hasher},
+    };
+
+    use crate::{
+        util::fxhash::FxHasher,
+        vm::{
+            obj::{AnkokuString, List, Obj, ObjType, Object},
+            VM,
+        },
+    };
+
+    use super::{format_number, Value};
+
+    fn hash(value: &Value) -> u64 {
+        let mut hasher = FxHasher::default();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Allocates `obj` so it stays alive for the rest of the test, even when every allocation collects.
+    fn alloc(vm: &VM, obj: Obj) -> Value {
+        vm.alloc_constant(obj)
+    }
+
+    fn string(vm: &VM, s: &str) -> Value {
+        alloc(vm, AnkokuString::new(s.into()).into())
+    }
+
+    #[test]
+    fn zero_hash() {
+        assert_eq!(Value::Real(0.0), Value::Real(-0.0));
+        assert_eq!(hash(&Value::Real(0.0)), hash(&Value::Real(-0.0)));
+        assert_ne!(hash(&Value::Real(0.0)), hash(&Value::Real(1.0)));
+    }
+
+    #[test]
+    fn ordering() {
+        assert_eq!(
+            Value::Real(1.0).partial_cmp(&Value::Real(2.0)),
+            Some(Ordering::Less)
+        );
+        assert!(Value::Real(-0.0) >= Value::Real(0.0));
+        assert_eq!(Value::Real(f64::NAN).partial_cmp(&Value::Real(1.0)), None);
+
+        let vm = VM::new();
+        let (a, b) = (string(&vm, "a"), string(&vm, "b"));
+        assert!(a < b);
+        assert_eq!(a.partial_cmp(&string(&vm, "a")), Some(Ordering::Equal));
+        assert_eq!(Value::Real(1.0).partial_cmp(&a), None);
+        assert_eq!(a.partial_cmp(&Value::Real(1.0)), None);
+        assert_eq!(Value::Null.partial_cmp(&Value::Null), None);
+        assert_eq!(Value::Bool(false).partial_cmp(&Value::Bool(true)), None);
+    }
+
+    #[test]
+    fn pretty() {
+        let vm = VM::new();
+        let obj = |key: &str, value: Value| {
+            let mut object = Object::new();
+            object.table.set(AnkokuString::new(key.into()), value);
+            alloc(&vm, Obj::new(ObjType::Object(object)))
+        };
+        let list = |items: Vec<Value>| alloc(&vm, Obj::new(ObjType::List(List::new(items))));
+
+        let inner = obj("x", Value::Real(1.0));
+        let empty = alloc(&vm, Obj::new(ObjType::Object(Object::new())));
+        let items = list(vec![
+            Value::Null,
+            string(&vm, "two"),
+            list(vec![]),
+            empty,
+            inner,
+        ]);
+        // the list contains itself
+        if let Value::Obj(mut o) = items.clone() {
+            if let ObjType::List(l) = &mut o.kind {
+                l.items.push(items.clone());
+            }
+        }
+        let outer = obj("list", items);
+
+        assert_eq!(
+            outer.pretty(2),
+            "{
+  list = [
+    null,
+    \"two\",
+    [],
+    {},
+    {
+      x = 1
+    },
+    <cycle>
+  ]
+}"
+        );
+        assert_eq!(list(vec![Value::Bool(true)]).pretty(0), "[\ntrue\n]");
+        assert_eq!(Value::Real(1.5).pretty(4), "1.5");
+        assert_eq!(string(&vm, "top").pretty(4), "top");
+    }
+
+    #[test]
+    fn deeply_nested() {
+        let vm = VM::new();
+        let mut value = Value::Real(1.0);
+        for _ in 0..200 {
+            value = alloc(&vm, Obj::new(ObjType::List(List::new(vec![value]))));
+        }
+        assert_eq!(
+            value.to_string(),
+            format!("{}...{}", "[".repeat(128), "]".repeat(128))
+        );
+        assert!(value.pretty(0).contains("[\n...\n]"));
+        assert_eq!(value.to_json(), None);
+    }
+
+    #[test]
+    fn json() {
+        let vm = VM::new();
+        let list = |items: Vec<Value>| alloc(&vm, Obj::new(ObjType::List(List::new(items))));
+        let mut object = Object::new();
+        object
+            .table
+            .set(AnkokuString::new("a b".into()), Value::Bool(true));
+        let object = alloc(&vm, Obj::new(ObjType::Object(object)));
+
+        let nested = list(vec![
+            Value::Real(1.0),
+            list(vec![Value::Real(2.0), Value::Real(3.0)]),
+            string(&vm, "x"),
+        ]);
+        assert_eq!(nested.to_string(), "[1, [2, 3], \"x\"]");
+        assert_eq!(nested.to_json().as_deref(), Some("[1,[2,3],\"x\"]"));
+        assert_eq!(
+            list(vec![object, Value::Null, Value::Real(-0.5), list(vec![])])
+                .to_json()
+                .as_deref(),
+            Some("[{\"a b\":true},null,-0.5,[]]")
+        );
+        assert_eq!(
+            string(&vm, "say \"hi\"\n").to_json().as_deref(),
+            Some("\"say \\\"hi\\\"\\n\"")
+        );
+
+        assert_eq!(Value::Real(f64::NAN).to_json(), None);
+        assert_eq!(list(vec![Value::Real(f64::INFINITY)]).to_json(), None);
+        let cyclic = list(vec![]);
+        if let Value::Obj(mut o) = cyclic.clone() {
+            if let ObjType::List(l) = &mut o.kind {
+                l.items.push(cyclic.clone());
+            }
+        }
+        assert_eq!(cyclic.to_json(), None);
+        // the same list twice isn't a cycle
+        let shared = list(vec![Value::Real(1.0)]);
+        assert_eq!(
+            list(vec![shared.clone(), shared]).to_json().as_deref(),
+            Some("[[1],[1]]")
+        );
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Value::from(3), Value::Real(3.0));
+        assert_eq!(Value::from(-4i64), Value::Real(-4.0));
+        assert_eq!(Value::from(5usize), Value::Real(5.0));
+        assert_eq!(Value::from(()), Value::Null);
+        assert_eq!(Value::null(), Value::Null);
+        assert_eq!(Value::from(1.5), Value::Real(1.5));
+        assert_eq!(Value::from(true), Value::Bool(true));
+
+        let vm = VM::new();
+        let s = Value::string(&vm, "hi");
+        assert_eq!(s.to_string(), "hi");
+        assert_eq!(Value::string(&vm, String::from("hi")).to_string(), "hi");
+    }
+
+    #[test]
+    fn number_formatting() {
+        for (n, expected) in [
+            (4.0, "4"),
+            (4.5, "4.5"),
+            (-0.0, "-0"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (1e21, "1000000000000000000000"),
+            (f64::NAN, "NaN"),
+            (f64::NEG_INFINITY, "-inf"),
+        ] {
+            assert_eq!(format_number(n), expected);
+            assert_eq!(Value::Real(n).to_string(), expected);
+            assert_eq!(Value::Real(n).coerce_str().unwrap(), expected);
+        }
+    }
+}